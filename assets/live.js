@@ -0,0 +1,214 @@
+(() => {
+  // Live reload client for the mdr preview server. Speaks the versioned JSON
+  // protocol and still understands the legacy bare "reload" text frames.
+  const PROTOCOL = 1;
+  const HEARTBEAT_TIMEOUT_MS = 90000;
+  const POLL_INTERVAL_MS = 2000;
+  const RECONNECT_INTERVAL_MS = 500;
+
+  // Endpoints sit next to this script, so --base-path and proxy prefixes
+  // carry over without configuration.
+  const script = document.currentScript;
+  const BASE = script ? new URL(".", script.src).pathname : "/";
+
+  let ws = null;
+  let lastSeen = Date.now();
+
+  // Only the WebSocket transport can talk back; SSE and polling are one-way.
+  function send(msg) {
+    if (ws && ws.readyState === WebSocket.OPEN) {
+      ws.send(JSON.stringify(Object.assign({ v: PROTOCOL }, msg)));
+    }
+  }
+
+  function overlay() {
+    let el = document.getElementById("mdr-live-status");
+    if (!el) {
+      el = document.createElement("div");
+      el.id = "mdr-live-status";
+      el.setAttribute("role", "status");
+      el.style.cssText =
+        "position:fixed;left:0;right:0;bottom:0;z-index:2147483647;margin:0;" +
+        "padding:8px 12px;font:13px/1.4 Menlo,monospace;white-space:pre-wrap;" +
+        "max-height:40vh;overflow:auto;color:#fff;background:rgba(27,39,51,.92);";
+      document.body.appendChild(el);
+    }
+    return el;
+  }
+
+  function clearOverlay() {
+    const el = document.getElementById("mdr-live-status");
+    if (el) el.remove();
+  }
+
+  // With --check-links the last build's broken links ride along in the status;
+  // listed until clicked away.
+  function showLinkProblems() {
+    fetch(BASE + "api/status", { cache: "no-store" })
+      .then((res) => res.json())
+      .then((status) => {
+        const problems = status.link_problems || [];
+        if (!problems.length) return;
+        const el = overlay();
+        if (el.dataset.error) return;
+        el.style.background = "rgba(125,85,0,.95)";
+        el.style.cursor = "pointer";
+        el.title = "Click to dismiss";
+        el.textContent =
+          "mdr: " + problems.length + " broken link(s)\n\n" + problems.join("\n");
+        el.onclick = clearOverlay;
+      })
+      .catch(() => {});
+  }
+
+  function handle(msg) {
+    switch (msg.type) {
+      case "reload":
+        location.reload();
+        break;
+      case "build-started": {
+        const el = overlay();
+        if (!el.dataset.error) el.textContent = "mdr: rebuilding…";
+        break;
+      }
+      case "build-error": {
+        const el = overlay();
+        el.dataset.error = "1";
+        el.style.background = "rgba(160,30,30,.95)";
+        el.textContent =
+          "mdr: build failed (exit " + msg.exit_code + ")" +
+          (msg.stderr ? "\n\n" + msg.stderr : "");
+        break;
+      }
+      case "title-changed":
+        document.title = msg.title;
+        break;
+      case "scroll-to": {
+        const target = document.getElementById(msg.id);
+        if (target) target.scrollIntoView({ behavior: "smooth", block: "start" });
+        break;
+      }
+      case "navigate":
+        // Tabs already on the new route share the channel; only move the rest.
+        if (!BASE.endsWith("/" + msg.path)) {
+          location.replace(BASE + msg.path + location.hash);
+        }
+        break;
+      case "ping":
+        send({ type: "pong" });
+        break;
+      case "hello":
+        clearOverlay();
+        showLinkProblems();
+        break;
+    }
+  }
+
+  function onText(data) {
+    lastSeen = Date.now();
+    if (data === "reload") {
+      // Server predates the JSON protocol.
+      location.reload();
+      return;
+    }
+    let msg;
+    try {
+      msg = JSON.parse(data);
+    } catch (_) {
+      return;
+    }
+    if (msg && typeof msg.type === "string") handle(msg);
+  }
+
+  // WebSocket first; if it never connects (e.g. a proxy strips the upgrade),
+  // fall back to Server-Sent Events, then to polling /api/version.
+  function connectWebSocket() {
+    const proto = location.protocol === "https:" ? "wss://" : "ws://";
+    let opened = false;
+    try {
+      ws = new WebSocket(proto + location.host + BASE + "ws?protocol=" + PROTOCOL);
+    } catch (_) {
+      connectEventSource();
+      return;
+    }
+    ws.onopen = () => {
+      opened = true;
+      send({ type: "hello" });
+    };
+    ws.onmessage = (ev) => onText(ev.data);
+    ws.onclose = () => {
+      ws = null;
+      if (opened) {
+        reloadWhenBack();
+      } else {
+        connectEventSource();
+      }
+    };
+
+    // The server pings every 30s; a silent socket means a dead connection
+    // that the browser has not noticed yet.
+    const heartbeat = setInterval(() => {
+      if (!ws) {
+        clearInterval(heartbeat);
+      } else if (opened && Date.now() - lastSeen > HEARTBEAT_TIMEOUT_MS) {
+        ws.close();
+      }
+    }, 10000);
+  }
+
+  function connectEventSource() {
+    if (typeof EventSource === "undefined") {
+      poll(null);
+      return;
+    }
+    let opened = false;
+    const es = new EventSource(BASE + "events");
+    es.onopen = () => {
+      opened = true;
+    };
+    es.onmessage = (ev) => onText(ev.data);
+    es.onerror = () => {
+      es.close();
+      if (opened) {
+        reloadWhenBack();
+      } else {
+        poll(null);
+      }
+    };
+  }
+
+  // The server went away (rebuilt binary, restart with --open): keep this tab
+  // and reload once it answers again, so mdr can reuse it instead of opening
+  // another one.
+  function reloadWhenBack() {
+    fetch(BASE + "api/version", { cache: "no-store" })
+      .then((res) => {
+        if (!res.ok) throw new Error(res.status);
+        location.reload();
+      })
+      .catch(() => setTimeout(reloadWhenBack, RECONNECT_INTERVAL_MS));
+  }
+
+  function poll(etag) {
+    const headers = etag ? { "If-None-Match": etag } : {};
+    fetch(BASE + "api/version", { headers, cache: "no-store" })
+      .then((res) => {
+        if (res.status === 304) return etag;
+        const next = res.headers.get("ETag");
+        return res.json().then((info) => {
+          if (etag && next !== etag) {
+            if (info.success) {
+              location.reload();
+            } else {
+              handle({ type: "build-error", exit_code: info.exit_code });
+            }
+          }
+          return next;
+        });
+      })
+      .catch(() => etag)
+      .then((next) => setTimeout(() => poll(next), POLL_INTERVAL_MS));
+  }
+
+  connectWebSocket();
+})();
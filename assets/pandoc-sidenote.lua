@@ -1,5 +1,23 @@
 -- Minimal Tufte-style sidenotes for pandoc-markdown-css-theme.
 -- Inspired by https://github.com/jez/pandoc-sidenote
+--
+-- Also turns GitHub alerts (`> [!NOTE]`) and fenced divs (`::: warning`)
+-- into callouts, and numbers labelled figures and tables for `@fig:label`
+-- references.
+--
+-- `pandoc server` cannot run this filter, so mdr only uses it for documents
+-- containing none of the markers below. Keep them in step with what the
+-- filter rewrites; mdr reads the `-- trigger:` lines from this file.
+--
+-- trigger: [^        footnotes, rendered as sidenotes
+-- trigger: ^[        inline footnotes
+-- trigger: [!        GitHub alerts
+-- trigger: :::       fenced-div callouts
+-- trigger: #fig:     figure labels
+-- trigger: #tbl:     table labels
+-- trigger: @fig:     figure references
+-- trigger: @tbl:     table references
+-- trigger: .margin   margin figures
 
 local counter = 0
 
@@ -72,3 +90,310 @@ function Note(note)
   local is_margin = drop_margin_marker(blocks)
   return render_note(blocks, is_margin)
 end
+
+local callout_titles = {
+  note = "Note",
+  tip = "Tip",
+  important = "Important",
+  warning = "Warning",
+  caution = "Caution",
+}
+
+-- Other names the same callouts go by in docs written for other tools.
+local callout_aliases = {
+  info = "note",
+  hint = "tip",
+  danger = "caution",
+}
+
+local function callout_kind(name)
+  name = name:lower()
+  name = callout_aliases[name] or name
+  if callout_titles[name] then
+    return name
+  end
+  return nil
+end
+
+-- `attr` is the source div's, if any: its identifier, other classes and
+-- attributes carry over so existing `#id` links keep working.
+local function callout(kind, title, blocks, attr)
+  if #title == 0 then
+    title = { pandoc.Str(callout_titles[kind]) }
+  end
+  local heading = {
+    pandoc.RawInline("html", '<span class="callout-icon" aria-hidden="true"></span>'),
+  }
+  for _, inline in ipairs(title) do
+    table.insert(heading, inline)
+  end
+  table.insert(
+      blocks,
+      1,
+      pandoc.Div({ pandoc.Plain(heading) }, pandoc.Attr("", { "callout-title" }))
+    )
+  local identifier = ""
+  local classes = { "callout", "callout-" .. kind }
+  local attributes = { { "role", "note" } }
+  if attr then
+    identifier = attr.identifier
+    for _, class in ipairs(attr.classes) do
+      if callout_kind(class) ~= kind then
+        table.insert(classes, class)
+      end
+    end
+    for key, value in pairs(attr.attributes) do
+      if key == "role" then
+        attributes[1] = { key, value }
+      elseif key ~= "title" then
+        table.insert(attributes, { key, value })
+      end
+    end
+  end
+  return pandoc.Div(blocks, pandoc.Attr(identifier, classes, attributes))
+end
+
+-- `> [!WARNING]` on its own line, or followed by a custom title.
+function BlockQuote(quote)
+  local first = quote.content[1]
+  if not first or first.t ~= "Para" then
+    return nil
+  end
+
+  local inlines = first.content
+  local marker = inlines[1]
+  if not marker or marker.t ~= "Str" then
+    return nil
+  end
+  local name = marker.text:match("^%[!(%a+)%]$")
+  local kind = name and callout_kind(name)
+  if not kind then
+    return nil
+  end
+
+  table.remove(inlines, 1)
+  local title = {}
+  while #inlines > 0 and inlines[1].t ~= "SoftBreak" and inlines[1].t ~= "LineBreak" do
+    local inline = table.remove(inlines, 1)
+    if #title > 0 or inline.t ~= "Space" then
+      table.insert(title, inline)
+    end
+  end
+  if #inlines > 0 then
+    table.remove(inlines, 1)
+  end
+
+  local blocks = quote.content
+  if #inlines == 0 then
+    table.remove(blocks, 1)
+  else
+    first.content = inlines
+    blocks[1] = first
+  end
+  return callout(kind, title, blocks)
+end
+
+-- `::: warning` or `::: {.tip title="Faster builds"}`.
+function Div(div)
+  for _, class in ipairs(div.classes) do
+    local kind = callout_kind(class)
+    if kind then
+      local title = {}
+      if div.attributes.title then
+        title = { pandoc.Str(div.attributes.title) }
+      end
+      return callout(kind, title, div.content, div.attr)
+    end
+  end
+  return nil
+end
+
+-- pandoc-crossref style labels: `![Caption](a.png){#fig:label}` and
+-- `Table: Caption {#tbl:label}`, referenced as `@fig:label` (or
+-- `-@fig:label` for the bare number). Figures need pandoc 3.
+local crossref_names = { fig = "Figure", tbl = "Table" }
+local crossref_counts = { fig = 0, tbl = 0 }
+local crossref_numbers = {}
+
+local function crossref_number(kind, id)
+  if id:sub(1, #kind + 1) ~= kind .. ":" then
+    return nil
+  end
+  crossref_counts[kind] = crossref_counts[kind] + 1
+  crossref_numbers[id] = crossref_counts[kind]
+  return crossref_counts[kind]
+end
+
+local function number_caption(long, kind, number)
+  local label = pandoc.Span(
+      { pandoc.Str(string.format("%s\u{a0}%d:", crossref_names[kind], number)) },
+      pandoc.Attr("", { "caption-label" })
+    )
+  local first = long[1]
+  if first and (first.t == "Plain" or first.t == "Para") then
+    local inlines = first.content
+    table.insert(inlines, 1, pandoc.Space())
+    table.insert(inlines, 1, label)
+    first.content = inlines
+    long[1] = first
+  else
+    table.insert(long, 1, pandoc.Plain({ label }))
+  end
+  return long
+end
+
+-- `{.margin}` on the image (or figure) puts the figure in the margin,
+-- next to the text like a margin note.
+local function number_figure(fig)
+  local margin = fig.classes:includes("margin")
+  fig.content:walk({
+    Image = function(image)
+      if image.classes:includes("margin") then
+        margin = true
+      end
+      return nil
+    end,
+  })
+  if margin then
+    local classes = fig.classes
+    classes:insert("marginfigure")
+    fig.classes = classes
+  end
+
+  local number = crossref_number("fig", fig.identifier)
+  if number then
+    local caption = fig.caption
+    caption.long = number_caption(caption.long, "fig", number)
+    fig.caption = caption
+  end
+  return fig
+end
+
+local function number_table(tbl)
+  local caption = tbl.caption
+  local long = caption.long
+
+  -- Older readers leave `{#tbl:label}` at the end of the caption text.
+  local last = long[#long]
+  if tbl.identifier == "" and last and (last.t == "Plain" or last.t == "Para") then
+    local inlines = last.content
+    local final = inlines[#inlines]
+    local id = final and final.t == "Str" and final.text:match("^{#(tbl:[^}%s]+)}$")
+    if id then
+      table.remove(inlines)
+      if #inlines > 0 and inlines[#inlines].t == "Space" then
+        table.remove(inlines)
+      end
+      last.content = inlines
+      long[#long] = last
+      tbl.identifier = id
+    end
+  end
+
+  local number = crossref_number("tbl", tbl.identifier)
+  if not number then
+    return nil
+  end
+  caption.long = number_caption(long, "tbl", number)
+  tbl.caption = caption
+  return tbl
+end
+
+-- Citations that are all `fig:` / `tbl:` labels become links; anything else
+-- is a real citation and left alone.
+local function resolve_references(cite)
+  local inlines = {}
+  for i, citation in ipairs(cite.citations) do
+    local id = citation.id
+    local kind = id:match("^(%a+):")
+    if not crossref_names[kind] then
+      return nil
+    end
+    if i > 1 then
+      table.insert(inlines, pandoc.Str(","))
+      table.insert(inlines, pandoc.Space())
+    end
+
+    local number = crossref_numbers[id]
+    if number then
+      local text = tostring(number)
+      if citation.mode ~= "SuppressAuthor" then
+        text = crossref_names[kind] .. "\u{a0}" .. text
+      end
+      table.insert(
+          inlines,
+          pandoc.Link({ pandoc.Str(text) }, "#" .. id, "", pandoc.Attr("", { "cross-ref" }))
+        )
+    else
+      -- A link to the missing label, so mdr reports it with the other
+      -- broken links (`mdr check`, `--check-links`, and the build log).
+      table.insert(
+          inlines,
+          pandoc.Link(
+            { pandoc.Strong({ pandoc.Str("??") }) },
+            "#" .. id,
+            "",
+            pandoc.Attr("", { "cross-ref", "cross-ref-missing" })
+          )
+        )
+    end
+  end
+  return inlines
+end
+
+-- Diagram blocks (```mermaid, ```dot, ```plantuml, ...) become inline SVG.
+-- mdr names itself in MDR_DIAGRAM_COMMAND and renders each block from stdin,
+-- answering "ok" and the HTML, or "error" and a message to show above the
+-- code. mdr sends documents with diagram blocks to the CLI on its own, so
+-- they need no trigger here.
+local diagram_command = os.getenv("MDR_DIAGRAM_COMMAND")
+local diagram_classes = {
+  mermaid = true,
+  dot = true,
+  graphviz = true,
+  plantuml = true,
+  puml = true,
+}
+
+function CodeBlock(block)
+  if not diagram_command then
+    return nil
+  end
+  local class
+  for _, name in ipairs(block.classes) do
+    if diagram_classes[name] then
+      class = name
+      break
+    end
+  end
+  if not class then
+    return nil
+  end
+
+  local ok, reply = pcall(pandoc.pipe, diagram_command, { "render-diagram", class }, block.text)
+  local status, body
+  if ok then
+    status, body = reply:match("^(%a+)\n(.*)$")
+  end
+  if status == "ok" then
+    return pandoc.RawBlock("html", body)
+  end
+  local message = body or string.format("could not render the %s diagram: %s", class, tostring(reply))
+  return pandoc.Div(
+      { pandoc.Para({ pandoc.Str(message) }), block },
+      pandoc.Attr("", { "diagram-error" })
+    )
+end
+
+-- Numbering has to finish before references are resolved, so the filters
+-- run as two passes.
+return {
+  { Figure = number_figure, Table = number_table },
+  {
+    Cite = resolve_references,
+    Note = Note,
+    BlockQuote = BlockQuote,
+    Div = Div,
+    CodeBlock = CodeBlock,
+  },
+}
@@ -0,0 +1,137 @@
+/// A heading in rendered HTML that can be linked to.
+#[derive(Debug, PartialEq)]
+pub struct Heading {
+    pub id: String,
+    /// Text content, tags stripped.
+    pub text: String,
+}
+
+/// Headings with an `id`, in document order.
+pub fn headings(html: &str) -> Vec<Heading> {
+    let mut found = Vec::new();
+    let mut rest = html;
+    while let Some(start) = rest.find("<h") {
+        rest = &rest[start + 2..];
+        let Some(level @ b'1'..=b'6') = rest.bytes().next() else {
+            continue;
+        };
+        let Some(tag_end) = rest.find('>') else {
+            break;
+        };
+        let attrs = &rest[1..tag_end];
+        if !attrs.is_empty() && !attrs.starts_with(char::is_whitespace) {
+            continue;
+        }
+        let close = format!("</h{}>", level as char);
+        let Some(end) = rest.find(&close) else {
+            break;
+        };
+        if let Some(id) = attribute(attrs, "id") {
+            found.push(Heading {
+                id: decode(id),
+                text: heading_text(&rest[tag_end + 1..end]),
+            });
+        }
+        rest = &rest[end + close.len()..];
+    }
+    found
+}
+
+/// Headings that share their text with an earlier one, so pandoc told them
+/// apart with a `-1`, `-2`, ... suffix. Links to those break as soon as the
+/// sections are reordered.
+pub fn duplicate_warnings(headings: &[Heading]) -> Vec<String> {
+    headings
+        .iter()
+        .enumerate()
+        .filter_map(|(i, heading)| {
+            let (base, n) = heading.id.rsplit_once('-')?;
+            n.parse::<u32>().ok()?;
+            let first = headings[..i]
+                .iter()
+                .find(|earlier| earlier.id == base && earlier.text == heading.text)?;
+            Some(format!(
+                "duplicate heading \"{}\": #{} is only told apart from #{} by its position; \
+                 give it an explicit {{#id}}",
+                heading.text, heading.id, first.id
+            ))
+        })
+        .collect()
+}
+
+fn attribute<'a>(attrs: &'a str, name: &str) -> Option<&'a str> {
+    let needle = format!("{name}=\"");
+    let mut search = attrs;
+    loop {
+        let at = search.find(&needle)?;
+        let preceded_by_space = search[..at].ends_with(char::is_whitespace) || at == 0;
+        let value = &search[at + needle.len()..];
+        if preceded_by_space {
+            return value.find('"').map(|end| &value[..end]);
+        }
+        search = value;
+    }
+}
+
+// Without the section number `--number-sections` adds, which differs between
+// otherwise identical headings.
+fn heading_text(inner: &str) -> String {
+    const NUMBER: &str = "<span class=\"header-section-number\">";
+    let inner = match inner.find(NUMBER) {
+        Some(start) => match inner[start..].find("</span>") {
+            Some(end) => format!(
+                "{}{}",
+                &inner[..start],
+                &inner[start + end + "</span>".len()..]
+            ),
+            None => inner.to_string(),
+        },
+        None => inner.to_string(),
+    };
+    decode(strip_tags(&inner).trim())
+}
+
+fn strip_tags(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            _ if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text
+}
+
+fn decode(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_heading_ids_and_positional_duplicates() {
+        let html = r##"<h1 class="title">Doc</h1>
+<nav><a href="#setup" id="toc-setup">Setup</a></nav>
+<h2 data-number="1" id="setup"><span class="header-section-number">1</span> Setup</h2>
+<header><hr /><h3 id="step-1">Step 1</h3>
+<h2 id="setup-1">Setup</h2>
+<h2 id="q-a">Q &amp; <em>A</em></h2>"##;
+        let found = headings(html);
+        let ids: Vec<&str> = found.iter().map(|h| h.id.as_str()).collect();
+        assert_eq!(ids, ["setup", "step-1", "setup-1", "q-a"]);
+        assert_eq!(found[3].text, "Q & A");
+
+        let warnings = duplicate_warnings(&found);
+        assert_eq!(warnings.len(), 1, "{warnings:?}");
+        assert!(warnings[0].contains("#setup-1"), "{}", warnings[0]);
+    }
+}
@@ -0,0 +1,248 @@
+use std::io;
+use std::sync::Arc;
+
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode, Uri};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use crate::forwarded;
+
+pub const COOKIE_NAME: &str = "mdr_token";
+
+/// Access control for the preview server. A request is let through when it
+/// carries any configured credential: the access token (as `?token=` once,
+/// then as a cookie) or HTTP basic auth.
+#[derive(Default)]
+pub struct Auth {
+    pub token: Option<String>,
+    pub basic: Option<(String, String)>,
+    /// Mark the cookie `Secure` when serving over TLS.
+    pub secure: bool,
+    /// `--base-path`, which scopes the cookie.
+    pub base_path: String,
+}
+
+#[derive(Debug, PartialEq)]
+enum Decision {
+    Allow,
+    // Valid `?token=`: store it in a cookie and send the browser to `location`
+    // so the token does not linger in the address bar or history.
+    Exchange { location: String },
+    Deny,
+}
+
+impl Auth {
+    pub fn enabled(&self) -> bool {
+        self.token.is_some() || self.basic.is_some()
+    }
+
+    fn decide(&self, headers: &HeaderMap, uri: &Uri) -> Decision {
+        if !self.enabled() {
+            return Decision::Allow;
+        }
+
+        if let Some(token) = &self.token {
+            if cookie_value(headers, COOKIE_NAME).is_some_and(|v| constant_time_eq(v, token)) {
+                return Decision::Allow;
+            }
+            let (query_token, rest) = split_token_param(uri.query().unwrap_or_default());
+            if query_token.is_some_and(|v| constant_time_eq(&v, token)) {
+                // WebSocket handshakes cannot follow redirects.
+                if headers.contains_key(header::UPGRADE) {
+                    return Decision::Allow;
+                }
+                // `uri` already includes the base path; a proxy may have
+                // stripped a prefix of its own.
+                let path = format!("{}{}", forwarded::public_prefix(headers, ""), uri.path());
+                let location = if rest.is_empty() {
+                    path
+                } else {
+                    format!("{path}?{rest}")
+                };
+                return Decision::Exchange { location };
+            }
+        }
+
+        if let Some((user, pass)) = &self.basic {
+            if let Some((u, p)) = basic_credentials(headers) {
+                // Evaluate both so timing does not reveal which one matched.
+                let user_ok = constant_time_eq(&u, user);
+                let pass_ok = constant_time_eq(&p, pass);
+                if user_ok && pass_ok {
+                    return Decision::Allow;
+                }
+            }
+        }
+
+        Decision::Deny
+    }
+
+    fn cookie(&self, headers: &HeaderMap) -> String {
+        let token = self.token.as_deref().unwrap_or_default();
+        let path = forwarded::public_prefix(headers, &self.base_path);
+        let secure = if forwarded::is_https(headers, self.secure) {
+            "; Secure"
+        } else {
+            ""
+        };
+        format!("{COOKIE_NAME}={token}; Path={path}/; HttpOnly; SameSite=Lax{secure}")
+    }
+}
+
+/// Middleware enforcing `Auth` on every route, `/ws` and `/events` included.
+pub async fn require(State(auth): State<Arc<Auth>>, request: Request, next: Next) -> Response {
+    match auth.decide(request.headers(), request.uri()) {
+        Decision::Allow => next.run(request).await,
+        Decision::Exchange { location } => {
+            let cookie = HeaderValue::from_str(&auth.cookie(request.headers()))
+                .expect("token and prefix are ASCII");
+            let location = HeaderValue::from_str(&location).expect("path came from a valid URI");
+            (
+                StatusCode::SEE_OTHER,
+                [(header::SET_COOKIE, cookie), (header::LOCATION, location)],
+            )
+                .into_response()
+        }
+        Decision::Deny => {
+            debug!(
+                "rejected unauthenticated request for {}",
+                request.uri().path()
+            );
+            let mut response = (StatusCode::UNAUTHORIZED, "unauthorized\n").into_response();
+            if auth.basic.is_some() {
+                response.headers_mut().insert(
+                    header::WWW_AUTHENTICATE,
+                    HeaderValue::from_static("Basic realm=\"mdr\", charset=\"UTF-8\""),
+                );
+            }
+            response
+        }
+    }
+}
+
+/// Random 128-bit token, hex encoded.
+pub fn generate_token() -> io::Result<String> {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).map_err(io::Error::other)?;
+    Ok(bytes.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// Parse `user:password` from `--basic-auth` or `MDR_BASIC_AUTH`.
+pub fn parse_basic(value: &str) -> Option<(String, String)> {
+    let (user, pass) = value.split_once(':')?;
+    (!user.is_empty() && !pass.is_empty()).then(|| (user.to_string(), pass.to_string()))
+}
+
+fn cookie_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|pair| {
+            let (key, value) = pair.trim().split_once('=')?;
+            (key == name).then_some(value)
+        })
+}
+
+// Splits `token=...` out of a query string, returning it and the remaining params.
+fn split_token_param(query: &str) -> (Option<String>, String) {
+    let mut token = None;
+    let mut rest = Vec::new();
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        match pair.strip_prefix("token=") {
+            Some(value) => token = Some(value.to_string()),
+            None => rest.push(pair),
+        }
+    }
+    (token, rest.join("&"))
+}
+
+fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = STANDARD.decode(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, pass) = decoded.split_once(':')?;
+    Some((user.to_string(), pass.to_string()))
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(header::HeaderName, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(name.clone(), HeaderValue::from_str(value).unwrap());
+        }
+        map
+    }
+
+    #[test]
+    fn token_is_exchanged_for_cookie_then_accepted() {
+        let auth = Auth {
+            token: Some("abc123".into()),
+            ..Auth::default()
+        };
+        let none = HeaderMap::new();
+
+        assert_eq!(
+            auth.decide(&none, &"/?token=abc123&x=1".parse().unwrap()),
+            Decision::Exchange {
+                location: "/?x=1".into()
+            }
+        );
+        assert_eq!(
+            auth.decide(&none, &"/?token=wrong".parse().unwrap()),
+            Decision::Deny
+        );
+        assert_eq!(
+            auth.decide(
+                &headers(&[(header::COOKIE, "a=b; mdr_token=abc123")]),
+                &"/ws".parse().unwrap()
+            ),
+            Decision::Allow
+        );
+        assert!(auth.cookie(&none).contains("; Path=/; HttpOnly"));
+        // Lax so a shared link opened from another site keeps the cookie
+        // across the exchange redirect.
+        assert!(auth.cookie(&none).contains("; SameSite=Lax"));
+    }
+
+    #[test]
+    fn basic_auth_checks_user_and_password() {
+        let auth = Auth {
+            basic: parse_basic("ana:s3cret:x"),
+            ..Auth::default()
+        };
+        let uri: Uri = "/".parse().unwrap();
+        let good = format!("Basic {}", STANDARD.encode("ana:s3cret:x"));
+        let bad = format!("Basic {}", STANDARD.encode("ana:nope"));
+
+        assert_eq!(
+            auth.decide(&headers(&[(header::AUTHORIZATION, &good)]), &uri),
+            Decision::Allow
+        );
+        assert_eq!(
+            auth.decide(&headers(&[(header::AUTHORIZATION, &bad)]), &uri),
+            Decision::Deny
+        );
+        assert_eq!(auth.decide(&HeaderMap::new(), &uri), Decision::Deny);
+        assert_eq!(parse_basic("nopassword"), None);
+    }
+}
@@ -0,0 +1,114 @@
+use std::env;
+use std::io;
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// Launch a browser on `url` without waiting for it.
+///
+/// `command` comes from the config file; otherwise each entry of the
+/// colon-separated `$BROWSER` is tried in turn, then the platform opener
+/// (`xdg-open`, `open`, or `start`).
+pub fn open(url: &str, command: Option<&str>) -> io::Result<()> {
+    let browser = env::var("BROWSER").ok();
+    let mut last_err = None;
+    for argv in candidates(command, browser.as_deref(), url) {
+        match spawn(&argv) {
+            Ok(()) => return Ok(()),
+            Err(err) => {
+                debug!("could not run {}: {err}", argv[0]);
+                last_err = Some(err);
+            }
+        }
+    }
+    Err(last_err.expect("the platform opener is always a candidate"))
+}
+
+// The command lines to try, in order.
+fn candidates(command: Option<&str>, browser: Option<&str>, url: &str) -> Vec<Vec<String>> {
+    if let Some(command) = command {
+        return vec![command_line(command, url)];
+    }
+    browser
+        .unwrap_or_default()
+        .split(':')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| command_line(entry, url))
+        .chain([default_command(url)])
+        .collect()
+}
+
+fn spawn(argv: &[String]) -> io::Result<()> {
+    let Some((program, args)) = argv.split_first() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "empty browser command",
+        ));
+    };
+
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    // Reap the opener (xdg-open usually exits right away) without blocking.
+    thread::spawn(move || child.wait());
+    Ok(())
+}
+
+// `%s` marks where the URL goes, as with `$BROWSER`; otherwise it is appended.
+pub fn command_line(command: &str, url: &str) -> Vec<String> {
+    let mut argv: Vec<String> = command.split_whitespace().map(str::to_string).collect();
+    if argv.iter().any(|arg| arg.contains("%s")) {
+        for arg in &mut argv {
+            *arg = arg.replace("%s", url);
+        }
+    } else {
+        argv.push(url.to_string());
+    }
+    argv
+}
+
+fn default_command(url: &str) -> Vec<String> {
+    let opener: &[&str] = if cfg!(target_os = "macos") {
+        &["open"]
+    } else if cfg!(windows) {
+        &["cmd", "/C", "start", ""]
+    } else {
+        &["xdg-open"]
+    };
+    opener
+        .iter()
+        .map(|s| s.to_string())
+        .chain([url.to_string()])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line_substitutes_or_appends_url() {
+        assert_eq!(
+            command_line("firefox --new-tab", "http://x/"),
+            ["firefox", "--new-tab", "http://x/"]
+        );
+        assert_eq!(
+            command_line("chromium --app=%s", "http://x/"),
+            ["chromium", "--app=http://x/"]
+        );
+    }
+
+    #[test]
+    fn browser_variable_is_a_colon_separated_list() {
+        let tried = candidates(None, Some("nope %s:firefox --new-tab"), "http://x/");
+        assert_eq!(tried[0], ["nope", "http://x/"]);
+        assert_eq!(tried[1], ["firefox", "--new-tab", "http://x/"]);
+        assert_eq!(tried[2], default_command("http://x/"));
+        assert_eq!(tried.len(), 3);
+
+        let configured = candidates(Some("chromium"), Some("firefox"), "http://x/");
+        assert_eq!(configured, [["chromium", "http://x/"]]);
+    }
+}
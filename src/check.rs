@@ -0,0 +1,299 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::browser;
+
+/// A link or image in a rendered document that leads nowhere.
+#[derive(Debug, PartialEq)]
+pub struct Problem {
+    /// The reference as written in the document.
+    pub target: String,
+    pub message: String,
+}
+
+/// Checks external URLs. `mdr check` only consults one when asked to, so it
+/// works offline by default.
+pub trait UrlChecker {
+    fn check(&self, url: &str) -> Result<(), String>;
+}
+
+/// Runs a command per URL and trusts its exit status. `%s` marks where the
+/// URL goes; otherwise it is appended.
+pub struct CommandChecker {
+    command: String,
+}
+
+impl CommandChecker {
+    pub const DEFAULT: &'static str = "curl -sSfL --max-time 10 -r 0-0 -o /dev/null";
+
+    pub fn new(command: &str) -> Self {
+        CommandChecker {
+            command: command.to_string(),
+        }
+    }
+}
+
+impl UrlChecker for CommandChecker {
+    fn check(&self, url: &str) -> Result<(), String> {
+        let argv = browser::command_line(&self.command, url);
+        let (program, args) = argv.split_first().ok_or("empty URL check command")?;
+        let output = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .output()
+            .map_err(|err| format!("could not run {program}: {err}"))?;
+        if output.status.success() {
+            return Ok(());
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        match stderr.lines().map(str::trim).find(|l| !l.is_empty()) {
+            Some(line) => Err(line.to_string()),
+            None => Err(format!("{program} exited with {}", output.status)),
+        }
+    }
+}
+
+/// Check every link and image in `html`: fragments against the IDs in the
+/// page, relative paths against the filesystem under `base_dir`, and
+/// absolute URLs with `external`, if given.
+pub fn check_html(html: &str, base_dir: &Path, external: Option<&dyn UrlChecker>) -> Vec<Problem> {
+    let ids = page_ids(html);
+
+    let mut problems = Vec::new();
+    let mut checked_urls = HashSet::new();
+    let links = tag_attributes(html, "a", "href")
+        .into_iter()
+        .map(|href| (href, "link"));
+    let images = tag_attributes(html, "img", "src")
+        .into_iter()
+        .map(|src| (src, "image"));
+
+    for (raw, kind) in links.chain(images) {
+        let target = decode_entities(raw);
+        let problem = |message: String| Problem {
+            target: target.clone(),
+            message,
+        };
+
+        if let Some(fragment) = target.strip_prefix('#') {
+            let id = percent_decode(fragment);
+            if !fragment.is_empty() && !ids.contains(&id) {
+                problems.push(
+                    unknown_crossref(&id)
+                        .unwrap_or_else(|| problem(format!("no heading or anchor #{id}"))),
+                );
+            }
+            continue;
+        }
+
+        let scheme = target
+            .split_once(':')
+            .map(|(scheme, _)| scheme)
+            .filter(|s| {
+                s.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+            });
+        match scheme.map(str::to_ascii_lowercase).as_deref() {
+            Some("http" | "https") => {
+                if let Some(checker) = external {
+                    if checked_urls.insert(target.clone()) {
+                        if let Err(err) = checker.check(&target) {
+                            problems.push(problem(format!("{kind} failed: {err}")));
+                        }
+                    }
+                }
+            }
+            // Other schemes (mailto:, data:, ...) and protocol-relative or
+            // site-absolute paths are not something a file can be checked for.
+            Some(_) => {}
+            None if target.starts_with('/') => {}
+            None => {
+                let path = target
+                    .split(['#', '?'])
+                    .next()
+                    .expect("split yields one item");
+                let path = percent_decode(path);
+                if !path.is_empty() && !base_dir.join(&path).exists() {
+                    problems.push(problem(format!("{kind} target {path} does not exist")));
+                }
+            }
+        }
+    }
+    problems
+}
+
+/// `@fig:label` and `@tbl:label` references to labels the document does not
+/// define. The Lua filter links them to the missing label.
+pub fn unknown_crossrefs(html: &str) -> Vec<Problem> {
+    let ids = page_ids(html);
+    tag_attributes(html, "a", "href")
+        .into_iter()
+        .filter_map(|href| href.strip_prefix('#'))
+        .map(|fragment| percent_decode(&decode_entities(fragment)))
+        .filter(|id| !ids.contains(id))
+        .filter_map(|id| unknown_crossref(&id))
+        .collect()
+}
+
+// Reported against `@label`, which is what the Markdown source contains.
+fn unknown_crossref(id: &str) -> Option<Problem> {
+    if !id.starts_with("fig:") && !id.starts_with("tbl:") {
+        return None;
+    }
+    Some(Problem {
+        target: format!("@{id}"),
+        message: format!("unknown cross-reference @{id}"),
+    })
+}
+
+fn page_ids(html: &str) -> HashSet<String> {
+    attribute_values(html, "id")
+        .into_iter()
+        .chain(attribute_values(html, "name"))
+        .map(decode_entities)
+        .collect()
+}
+
+/// The 1-based line of `source` that mentions `target`, for pointing at a
+/// problem in the Markdown rather than the HTML.
+pub fn line_of(source: &str, target: &str) -> Option<usize> {
+    source
+        .lines()
+        .position(|line| line.contains(target))
+        .map(|i| i + 1)
+}
+
+// Values of `attr` on every `<tag ...>` element.
+fn tag_attributes<'a>(html: &'a str, tag: &str, attr: &str) -> Vec<&'a str> {
+    let open = format!("<{tag}");
+    html.match_indices(open.as_str())
+        .filter_map(|(at, _)| {
+            let rest = &html[at + open.len()..];
+            if !rest.starts_with(char::is_whitespace) {
+                return None;
+            }
+            let attrs = &rest[..rest.find('>')?];
+            attribute_values(attrs, attr).first().copied()
+        })
+        .collect()
+}
+
+// Values of `attr="..."` anywhere in `html`.
+fn attribute_values<'a>(html: &'a str, attr: &str) -> Vec<&'a str> {
+    let needle = format!(" {attr}=\"");
+    html.match_indices(needle.as_str())
+        .filter_map(|(at, matched)| {
+            let value = &html[at + matched.len()..];
+            value.find('"').map(|end| &value[..end])
+        })
+        .collect()
+}
+
+fn decode_entities(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::fs;
+
+    struct Recorder(RefCell<Vec<String>>);
+
+    impl UrlChecker for Recorder {
+        fn check(&self, url: &str) -> Result<(), String> {
+            self.0.borrow_mut().push(url.to_string());
+            if url.contains("gone") {
+                Err("404".into())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn reports_missing_anchors_files_and_images() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("other.md"), "").unwrap();
+        fs::create_dir(dir.path().join("img")).unwrap();
+        fs::write(dir.path().join("img/a b.png"), "").unwrap();
+
+        let html = r##"<h2 id="setup">Setup</h2>
+<p><a href="#setup">ok</a> <a href="#set%20up">bad</a> <a href="#">top</a>
+<a href="other.md#intro">ok</a> <a href="missing.md">bad</a>
+<a href="mailto:me@example.com">mail</a> <a href="/site/root">root</a>
+<a href="https://example.com/gone">bad</a> <a href="https://example.com/gone">dup</a>
+<img src="img/a%20b.png" /> <img src="img/missing.png" alt="x" />
+<img src="data:image/png;base64,AAAA" /></p>"##;
+
+        let offline = check_html(html, dir.path(), None);
+        let targets: Vec<&str> = offline.iter().map(|p| p.target.as_str()).collect();
+        assert_eq!(targets, ["#set%20up", "missing.md", "img/missing.png"]);
+        assert!(offline[2].message.starts_with("image"), "{:?}", offline[2]);
+
+        let recorder = Recorder(RefCell::new(Vec::new()));
+        let online = check_html(html, dir.path(), Some(&recorder));
+        assert_eq!(online.len(), 4);
+        assert_eq!(*recorder.0.borrow(), ["https://example.com/gone"]);
+    }
+
+    #[test]
+    fn reports_unknown_cross_references_by_label() {
+        let html = r##"<figure id="fig:plot"><img src="data:," /></figure>
+<p><a href="#fig:plot" class="cross-ref">Figure 1</a>
+<a href="#tbl:missing" class="cross-ref cross-ref-missing"><strong>??</strong></a></p>"##;
+
+        let problems = check_html(html, Path::new("."), None);
+        assert_eq!(
+            problems,
+            [Problem {
+                target: "@tbl:missing".into(),
+                message: "unknown cross-reference @tbl:missing".into(),
+            }]
+        );
+        assert_eq!(unknown_crossrefs(html), problems);
+        assert_eq!(
+            line_of("Intro.\n\nSee @tbl:missing.\n", "@tbl:missing"),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn finds_the_source_line() {
+        let source = "# Doc\n\nSee [it](missing.md).\n";
+        assert_eq!(line_of(source, "missing.md"), Some(3));
+        assert_eq!(line_of(source, "#nowhere"), None);
+        assert_eq!(percent_decode("a%20b%2"), "a b%2");
+    }
+}
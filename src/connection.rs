@@ -0,0 +1,77 @@
+use std::fmt::Display;
+use std::future::Future;
+
+use axum::Router;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use hyper_util::service::TowerToHyperService;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::select;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+/// Serve one already-accepted connection for listeners `axum::serve` cannot
+/// drive (TLS, Unix sockets). Upgrades stay enabled for `/ws`. Once `closing`
+/// fires, in-flight requests finish and the connection closes.
+pub async fn serve<I>(io: I, app: Router, peer: impl Display, mut closing: watch::Receiver<bool>)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = TowerToHyperService::new(app);
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let conn = builder.serve_connection_with_upgrades(TokioIo::new(io), service);
+    tokio::pin!(conn);
+
+    let mut shutting_down = false;
+    let result = loop {
+        select! {
+            result = conn.as_mut() => break result,
+            _ = closing.wait_for(|closing| *closing), if !shutting_down => {
+                shutting_down = true;
+                conn.as_mut().graceful_shutdown();
+            }
+        }
+    };
+    if let Err(err) = result {
+        debug!("connection from {peer} ended with error: {err}");
+    }
+}
+
+/// The connections a listener loop has spawned, so shutdown can wait for them
+/// the way `axum::serve`'s graceful shutdown does.
+pub struct Connections {
+    tasks: JoinSet<()>,
+    closing: watch::Sender<bool>,
+}
+
+impl Connections {
+    pub fn new() -> Self {
+        Connections {
+            tasks: JoinSet::new(),
+            closing: watch::channel(false).0,
+        }
+    }
+
+    /// Run `task`, which is handed the signal to pass on to `serve`.
+    pub fn spawn<F, T>(&mut self, task: F)
+    where
+        F: FnOnce(watch::Receiver<bool>) -> T,
+        T: Future<Output = ()> + Send + 'static,
+    {
+        self.tasks.spawn(task(self.closing.subscribe()));
+    }
+
+    /// Forget connections that have ended; pends while none are open.
+    pub async fn reap(&mut self) {
+        if self.tasks.join_next().await.is_none() {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    /// Ask every connection to close and wait until they have. Callers bound
+    /// the wait; dropping this aborts whatever is left.
+    pub async fn drain(mut self) {
+        let _ = self.closing.send(true);
+        while self.tasks.join_next().await.is_some() {}
+    }
+}
@@ -0,0 +1,308 @@
+use std::env;
+use std::fs;
+use std::future::Future;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader as AsyncBufReader};
+
+use crate::unix_socket::SocketListener;
+
+/// A command for a running preview server. The control socket speaks one JSON
+/// object per line in each direction.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "cmd", rename_all = "kebab-case")]
+pub enum Request {
+    /// Serve another document (or find the one already serving `path`).
+    Open { path: PathBuf },
+    /// Describe every document the server serves.
+    List,
+    /// Rebuild the document serving `path`, or all of them.
+    Rebuild {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<PathBuf>,
+    },
+    /// Stop serving `path`, or shut the server down as if it got `SIGTERM`
+    /// when no path is given or `path` is its last document.
+    Stop {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<PathBuf>,
+    },
+}
+
+impl Request {
+    // How long `send` waits for the answer. A server that stops answering
+    // must not hang `mdr ctl` or `mdr open`, but opening and rebuilding run
+    // pandoc before they reply.
+    fn timeout(&self) -> Duration {
+        match self {
+            Request::Open { .. } | Request::Rebuild { .. } => Duration::from_secs(120),
+            Request::List | Request::Stop { .. } => Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub documents: Vec<DocumentInfo>,
+    /// The server is shutting down in response to this request.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stopping: bool,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct DocumentInfo {
+    /// Canonical path of the served file.
+    pub path: PathBuf,
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+}
+
+impl Response {
+    pub fn url(url: String) -> Self {
+        Response {
+            ok: true,
+            url: Some(url),
+            ..Response::default()
+        }
+    }
+
+    pub fn documents(documents: Vec<DocumentInfo>) -> Self {
+        Response {
+            ok: true,
+            documents,
+            ..Response::default()
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Response {
+            error: Some(message.into()),
+            ..Response::default()
+        }
+    }
+}
+
+/// Directory holding one `<pid>.sock` per running server:
+/// `$XDG_RUNTIME_DIR/mdr`, or a per-user directory under the temp dir.
+pub fn runtime_dir() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR").filter(|v| !v.is_empty()) {
+        Some(dir) => PathBuf::from(dir).join("mdr"),
+        // Not `mdr-<n>`: the temp dir sweep would take it for a dead pid.
+        None => env::temp_dir().join(format!("mdr-run-{}", unsafe { libc::getuid() })),
+    }
+}
+
+/// Bind this process's control socket.
+pub fn bind() -> io::Result<SocketListener> {
+    let dir = runtime_dir();
+    fs::create_dir_all(&dir)?;
+    // Under a shared temp dir, someone else may have created it first.
+    let meta = fs::metadata(&dir)?;
+    if meta.uid() != unsafe { libc::getuid() } {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} belongs to another user", dir.display()),
+        ));
+    }
+    fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+    remove_stale_sockets(&dir);
+    SocketListener::bind(&dir.join(format!("{}.sock", process::id())))
+}
+
+// Sockets of processes that were killed before they could clean up.
+fn remove_stale_sockets(dir: &Path) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for path in entries.flatten().map(|entry| entry.path()) {
+        let is_socket = path.extension().is_some_and(|ext| ext == "sock");
+        if is_socket && StdUnixStream::connect(&path).is_err() {
+            let _ = fs::remove_file(&path);
+        }
+    }
+}
+
+/// Answer requests on `listener` until the task is dropped.
+pub async fn serve<H, F>(listener: SocketListener, handler: H)
+where
+    H: Fn(Request) -> F + Clone + Send + 'static,
+    F: Future<Output = Response> + Send,
+{
+    loop {
+        let stream = listener.accept().await;
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let (read, mut write) = stream.into_split();
+            let mut lines = AsyncBufReader::new(read).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let response = match serde_json::from_str::<Request>(&line) {
+                    Ok(request) => handler(request).await,
+                    Err(err) => Response::error(format!("invalid request: {err}")),
+                };
+                let mut text = serde_json::to_string(&response).expect("response serializes");
+                text.push('\n');
+                if write.write_all(text.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// Control sockets of running servers, most recently started first. Sockets
+/// left behind by crashed processes are skipped.
+pub fn instances() -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(runtime_dir()) else {
+        return Vec::new();
+    };
+
+    let mut found: Vec<(SystemTime, PathBuf)> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "sock"))
+        .filter(|path| StdUnixStream::connect(path).is_ok())
+        .map(|path| {
+            let started = fs::metadata(&path)
+                .and_then(|m| m.modified())
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            (started, path)
+        })
+        .collect();
+    found.sort_by_key(|(started, _)| std::cmp::Reverse(*started));
+    found.into_iter().map(|(_, path)| path).collect()
+}
+
+/// The running server serving `path` (canonical), and its entry for it.
+pub fn find(path: &Path) -> Option<(PathBuf, DocumentInfo)> {
+    instances().into_iter().find_map(|socket| {
+        let response = send(&socket, &Request::List).ok()?;
+        let doc = response
+            .documents
+            .into_iter()
+            .find(|doc| doc.path == path)?;
+        Some((socket, doc))
+    })
+}
+
+/// The pid a control socket belongs to, from its `<pid>.sock` name.
+pub fn pid(socket: &Path) -> Option<u32> {
+    socket.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Send one request and wait for the answer. A server that does not accept
+/// the request or answer in time is reported as `TimedOut`.
+pub fn send(socket: &Path, request: &Request) -> io::Result<Response> {
+    let mut stream = StdUnixStream::connect(socket)?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_read_timeout(Some(request.timeout()))?;
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes()).map_err(timed_out)?;
+
+    let mut answer = String::new();
+    BufReader::new(stream)
+        .read_line(&mut answer)
+        .map_err(timed_out)?;
+    if answer.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "server closed the control connection",
+        ));
+    }
+    Ok(serde_json::from_str(&answer)?)
+}
+
+// A socket timeout surfaces as `WouldBlock` on Unix.
+fn timed_out(err: io::Error) -> io::Error {
+    match err.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+            io::Error::new(io::ErrorKind::TimedOut, "the server did not answer in time")
+        }
+        _ => err,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_and_responses_round_trip_as_json_lines() {
+        let request = Request::Open {
+            path: PathBuf::from("/notes/other.md"),
+        };
+        let text = serde_json::to_string(&request).unwrap();
+        assert_eq!(text, r#"{"cmd":"open","path":"/notes/other.md"}"#);
+        assert_eq!(serde_json::from_str::<Request>(&text).unwrap(), request);
+
+        let ok = serde_json::to_string(&Response::url("http://x/d/other/".into())).unwrap();
+        assert_eq!(ok, r#"{"ok":true,"url":"http://x/d/other/"}"#);
+        assert!(serde_json::from_str::<Request>(r#"{"cmd":"explode"}"#).is_err());
+        assert_eq!(
+            serde_json::from_str::<Request>(r#"{"cmd":"rebuild"}"#).unwrap(),
+            Request::Rebuild { path: None }
+        );
+        assert_eq!(
+            serde_json::from_str::<Request>(r#"{"cmd":"stop"}"#).unwrap(),
+            Request::Stop { path: None }
+        );
+        assert_eq!(pid(Path::new("/run/mdr/4242.sock")), Some(4242));
+    }
+
+    #[tokio::test]
+    async fn serve_answers_each_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ctl.sock");
+        let listener = SocketListener::bind(&path).unwrap();
+        let server = tokio::spawn(serve(listener, |request| async move {
+            match request {
+                Request::Open { path } => Response::url(format!("opened {}", path.display())),
+                _ => Response::error("unsupported"),
+            }
+        }));
+
+        let socket = path.clone();
+        let response = tokio::task::spawn_blocking(move || {
+            send(
+                &socket,
+                &Request::Open {
+                    path: PathBuf::from("a.md"),
+                },
+            )
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(response.url.as_deref(), Some("opened a.md"));
+
+        server.abort();
+        let _ = server.await;
+        assert!(!path.exists(), "socket is removed with the server");
+    }
+
+    #[test]
+    fn send_gives_up_on_a_server_that_never_answers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ctl.sock");
+        // Accepted by the kernel's backlog, but nobody ever reads or replies.
+        let _listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+        let started = std::time::Instant::now();
+        let err = send(&path, &Request::List).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(30));
+    }
+}
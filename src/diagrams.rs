@@ -0,0 +1,505 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use ring::digest::{digest, SHA256};
+
+// mermaid-cli starts a headless browser, so allow it a while; a tool that
+// hangs on some input must not stall every later build.
+const TOOL_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Diagrams kept in the cache; the least recently used beyond this are removed.
+const MAX_CACHED_DIAGRAMS: usize = 500;
+
+/// A local program that turns a fenced code block into SVG.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tool {
+    /// ` ```mermaid `, via mermaid-cli.
+    Mermaid,
+    /// ` ```dot ` or ` ```graphviz `.
+    Graphviz,
+    /// ` ```plantuml ` or ` ```puml `.
+    PlantUml,
+}
+
+impl Tool {
+    fn from_class(class: &str) -> Option<Self> {
+        match class {
+            "mermaid" => Some(Tool::Mermaid),
+            "dot" | "graphviz" => Some(Tool::Graphviz),
+            "plantuml" | "puml" => Some(Tool::PlantUml),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Tool::Mermaid => "mermaid",
+            Tool::Graphviz => "graphviz",
+            Tool::PlantUml => "plantuml",
+        }
+    }
+
+    fn program(self) -> &'static str {
+        match self {
+            Tool::Mermaid => "mmdc",
+            Tool::Graphviz => "dot",
+            Tool::PlantUml => "plantuml",
+        }
+    }
+
+    // Prints the tool's version, on stdout or stderr.
+    fn version_arg(self) -> &'static str {
+        match self {
+            Tool::Mermaid => "--version",
+            Tool::Graphviz => "-V",
+            Tool::PlantUml => "-version",
+        }
+    }
+
+    fn install_hint(self) -> &'static str {
+        match self {
+            Tool::Mermaid => "npm install -g @mermaid-js/mermaid-cli",
+            Tool::Graphviz => "install Graphviz",
+            Tool::PlantUml => "install PlantUML",
+        }
+    }
+
+    // Every tool reads the diagram on stdin and writes SVG to stdout.
+    fn args(self, key: &str) -> Vec<String> {
+        let args: &[&str] = match self {
+            // The SVG's styles are scoped to its id, so diagrams on one page
+            // need distinct ones.
+            Tool::Mermaid => &[
+                "--input",
+                "-",
+                "--output",
+                "-",
+                "--outputFormat",
+                "svg",
+                "--backgroundColor",
+                "transparent",
+                "--quiet",
+                "--svgId",
+            ],
+            Tool::Graphviz => &["-Tsvg"],
+            Tool::PlantUml => &["-tsvg", "-pipe", "-charset", "UTF-8"],
+        };
+        let mut args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        if self == Tool::Mermaid {
+            args.push(format!("mermaid-{}", &key[..16]));
+        }
+        args
+    }
+}
+
+/// Environment variable naming the command the Lua filter runs for each
+/// diagram block, as `<command> render-diagram <class>`. Unset, diagram
+/// blocks stay code.
+pub const COMMAND_VAR: &str = "MDR_DIAGRAM_COMMAND";
+
+/// What `mdr render-diagram` answers the Lua filter for one block: `ok` and
+/// the HTML for the diagram, or `error` and a message to show above the code.
+pub fn filter_reply(
+    class: &str,
+    source: &str,
+    render: impl FnOnce(Tool, &str) -> Result<String, String>,
+) -> String {
+    let Some(tool) = Tool::from_class(class) else {
+        return format!("error\nnot a diagram class: {class}");
+    };
+    match render(tool, source) {
+        Ok(svg) => format!(
+            "ok\n<div class=\"diagram {}\">{}</div>",
+            tool.name(),
+            strip_prolog(&svg)
+        ),
+        Err(err) => format!("error\n{err}"),
+    }
+}
+
+/// Whether the Markdown source has a fenced block the filter would render,
+/// such as ` ```mermaid ` or ` ``` {.dot} `.
+pub fn has_blocks(source: &str) -> bool {
+    source.lines().any(|line| {
+        let line = line.trim_start();
+        let info = match line
+            .strip_prefix("```")
+            .or_else(|| line.strip_prefix("~~~"))
+        {
+            Some(info) => info.trim_start_matches(['`', '~']),
+            None => return false,
+        };
+        info.split(|c: char| c.is_whitespace() || "{}.".contains(c))
+            .any(|word| Tool::from_class(word).is_some())
+    })
+}
+
+/// The messages of blocks the filter kept as code under an inline error,
+/// once each, so the build can log them.
+pub fn failures(html: &str) -> Vec<String> {
+    const OPEN: &str = "<div class=\"diagram-error\">";
+    let mut errors = Vec::new();
+    for (at, _) in html.match_indices(OPEN) {
+        let rest = html[at + OPEN.len()..].trim_start();
+        let Some(message) = rest
+            .strip_prefix("<p>")
+            .and_then(|rest| rest.split_once("</p>"))
+            .map(|(message, _)| decode(message))
+        else {
+            continue;
+        };
+        if !errors.contains(&message) {
+            errors.push(message);
+        }
+    }
+    errors
+}
+
+/// Render one diagram with its tool. SVG is cached on disk by a SHA-256 of the
+/// tool, its version, and the source, so live reloads and repeated exports
+/// only pay for diagrams that changed, and upgrading a tool re-renders.
+pub fn run_tool(tool: Tool, source: &str) -> Result<String, String> {
+    let key = cache_key(tool, &tool_version(tool), source);
+    let cached = cache_dir().map(|dir| dir.join(format!("{}-{key}.svg", tool.name())));
+    if let Some(svg) = cached
+        .as_ref()
+        .and_then(|path| fs::read_to_string(path).ok())
+    {
+        touch(cached.as_deref().expect("read from a cache path"));
+        return Ok(svg);
+    }
+
+    let program = tool.program();
+    let child = Command::new(program)
+        .args(tool.args(&key))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => format!(
+                "{} diagrams need `{program}` on PATH ({})",
+                tool.name(),
+                tool.install_hint()
+            ),
+            _ => format!("could not run {program}: {err}"),
+        })?;
+    let output = wait_with_timeout(child, source, TOOL_TIMEOUT)
+        .map_err(|err| format!("{program} failed: {err}"))?
+        .ok_or_else(|| format!("{program} timed out after {}s", TOOL_TIMEOUT.as_secs()))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(
+            match stderr.lines().map(str::trim).find(|l| !l.is_empty()) {
+                Some(line) => format!("{program}: {line}"),
+                None => format!("{program} exited with {}", output.status),
+            },
+        );
+    }
+    let svg =
+        String::from_utf8(output.stdout).map_err(|_| format!("{program} wrote invalid UTF-8"))?;
+
+    // Written aside and renamed, so a concurrent build never reads half a
+    // file. A cache that cannot be written only costs speed.
+    if let Some(path) = cached {
+        let dir = path.parent().expect("cache file has a parent");
+        let partial = path.with_extension(format!("{}.tmp", std::process::id()));
+        let written = fs::create_dir_all(dir)
+            .and_then(|()| fs::write(&partial, &svg))
+            .and_then(|()| fs::rename(&partial, &path));
+        if written.is_ok() {
+            prune(dir, MAX_CACHED_DIAGRAMS);
+        }
+    }
+    Ok(svg)
+}
+
+// Hex SHA-256, stable across Rust releases and platforms unlike the std
+// hasher, so entries stay valid between mdr versions.
+fn cache_key(tool: Tool, version: &str, source: &str) -> String {
+    let input = format!("{}\0{version}\0{source}", tool.name());
+    digest(&SHA256, input.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+// First line the tool prints for its version flag, looked up once per run.
+// Empty when the tool is missing; rendering then reports that.
+fn tool_version(tool: Tool) -> String {
+    static VERSIONS: Mutex<Option<HashMap<&'static str, String>>> = Mutex::new(None);
+    let mut versions = VERSIONS.lock().unwrap_or_else(|e| e.into_inner());
+    versions
+        .get_or_insert_with(HashMap::new)
+        .entry(tool.name())
+        .or_insert_with(|| {
+            let child = Command::new(tool.program())
+                .arg(tool.version_arg())
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn();
+            let Ok(Ok(Some(output))) =
+                child.map(|child| wait_with_timeout(child, "", Duration::from_secs(10)))
+            else {
+                return String::new();
+            };
+            let text = [output.stdout, output.stderr].concat();
+            String::from_utf8_lossy(&text)
+                .lines()
+                .map(str::trim)
+                .find(|line| !line.is_empty())
+                .unwrap_or_default()
+                .to_string()
+        })
+        .clone()
+}
+
+// Mark a cache entry as recently used, so pruning keeps it.
+fn touch(path: &Path) {
+    let _ = fs::File::options()
+        .append(true)
+        .open(path)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+}
+
+// Remove the least recently used entries beyond `keep`, plus partial writes
+// that were never renamed.
+fn prune(dir: &Path, keep: usize) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut svgs = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let modified = entry
+            .metadata()
+            .and_then(|meta| meta.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("svg") => svgs.push((modified, path)),
+            Some("tmp") if modified.elapsed().unwrap_or_default() > Duration::from_secs(3600) => {
+                let _ = fs::remove_file(&path);
+            }
+            _ => {}
+        }
+    }
+    if svgs.len() <= keep {
+        return;
+    }
+    svgs.sort_by_key(|(modified, _)| std::cmp::Reverse(*modified));
+    for (_, path) in &svgs[keep..] {
+        let _ = fs::remove_file(path);
+    }
+}
+
+// Feed `input` to the child and collect its output, killing it if it runs
+// past `timeout` (`Ok(None)`). Pipes are drained on threads so a chatty tool
+// cannot block on a full pipe.
+fn wait_with_timeout(
+    mut child: std::process::Child,
+    input: &str,
+    timeout: Duration,
+) -> io::Result<Option<Output>> {
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = input.to_string();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let mut stderr = child.stderr.take().expect("stderr is piped");
+    let out = thread::spawn(move || {
+        let mut buf = Vec::new();
+        stdout.read_to_end(&mut buf).map(|_| buf)
+    });
+    let err = thread::spawn(move || {
+        let mut buf = Vec::new();
+        stderr.read_to_end(&mut buf).map(|_| buf)
+    });
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            // Not joined: a grandchild (mermaid's browser) may still hold the
+            // pipes open. The threads end when it does.
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+
+    let _ = writer.join();
+    let stdout = out.join().expect("reader thread").unwrap_or_default();
+    let stderr = err.join().expect("reader thread").unwrap_or_default();
+    Ok(Some(Output {
+        status,
+        stdout,
+        stderr,
+    }))
+}
+
+// `$XDG_CACHE_HOME/mdr/diagrams`, or `~/.cache/mdr/diagrams`.
+fn cache_dir() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CACHE_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+    Some(base.join("mdr").join("diagrams"))
+}
+
+// An inline `<svg>` must not carry an XML declaration or doctype.
+fn strip_prolog(svg: &str) -> &str {
+    match svg.find("<svg") {
+        Some(at) => svg[at..].trim_end(),
+        None => svg.trim(),
+    }
+}
+
+fn decode(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_replies_carry_the_svg_or_the_error() {
+        let reply = filter_reply("dot", "digraph { a -> b }", |tool, source| {
+            Ok(format!(
+                "<?xml version=\"1.0\"?>\n<svg>{tool:?} {source}</svg>\n"
+            ))
+        });
+        assert_eq!(
+            reply,
+            "ok\n<div class=\"diagram graphviz\"><svg>Graphviz digraph { a -> b }</svg></div>"
+        );
+
+        let reply = filter_reply(
+            "puml",
+            "broken",
+            |_, _| Err("plantuml: syntax error".into()),
+        );
+        assert_eq!(reply, "error\nplantuml: syntax error");
+
+        let reply = filter_reply("rust", "fn main() {}", |_, _| unreachable!());
+        assert_eq!(reply, "error\nnot a diagram class: rust");
+    }
+
+    #[test]
+    fn finds_fenced_diagram_blocks() {
+        assert!(has_blocks("Intro\n\n```mermaid\ngraph TD; A-->B\n```\n"));
+        assert!(has_blocks("~~~ {.dot #deps}\ndigraph {}\n~~~\n"));
+        assert!(has_blocks("````` puml\n@startuml\n@enduml\n`````\n"));
+        assert!(!has_blocks("```rust\nlet dot = 1;\n```\n"));
+        assert!(!has_blocks("A mermaid, a dot, and plantuml in prose.\n"));
+    }
+
+    #[test]
+    fn collects_each_failure_once() {
+        let html = "<div class=\"diagram-error\">\n<p>mmdc: &lt;Parse&gt; error</p>\n\
+                    <pre class=\"mermaid\"><code>oops</code></pre>\n</div>\n\
+                    <div class=\"diagram mermaid\"><svg/></div>\n\
+                    <div class=\"diagram-error\">\n<p>mmdc: &lt;Parse&gt; error</p>\n</div>";
+        assert_eq!(failures(html), ["mmdc: <Parse> error"]);
+        assert!(failures("<div class=\"diagram mermaid\"><svg/></div>").is_empty());
+    }
+
+    #[test]
+    fn cache_keys_are_stable_and_cover_the_tool_version() {
+        let key = cache_key(
+            Tool::Graphviz,
+            "dot - graphviz version 2.43.0",
+            "digraph {}",
+        );
+        assert_eq!(key.len(), 64);
+        assert_eq!(
+            key,
+            cache_key(
+                Tool::Graphviz,
+                "dot - graphviz version 2.43.0",
+                "digraph {}"
+            )
+        );
+        assert_ne!(
+            key,
+            cache_key(Tool::Graphviz, "dot - graphviz version 9.0.0", "digraph {}")
+        );
+        assert_ne!(
+            key,
+            cache_key(
+                Tool::PlantUml,
+                "dot - graphviz version 2.43.0",
+                "digraph {}"
+            )
+        );
+        assert_eq!(
+            cache_key(Tool::Mermaid, "", ""),
+            "c964e131a751aa7b3224a8f1a3d9a976b1fc2767d58a3eb20b54dc1ed2257129"
+        );
+    }
+
+    #[test]
+    fn prune_keeps_the_most_recently_used_diagrams() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = SystemTime::now();
+        for (i, name) in ["old.svg", "mid.svg", "new.svg"].iter().enumerate() {
+            let path = dir.path().join(name);
+            fs::write(&path, "<svg/>").unwrap();
+            let file = fs::File::options().append(true).open(&path).unwrap();
+            file.set_modified(now - Duration::from_secs(100 * (3 - i as u64)))
+                .unwrap();
+        }
+        touch(&dir.path().join("old.svg"));
+
+        prune(dir.path(), 2);
+
+        assert!(
+            dir.path().join("old.svg").exists(),
+            "touched entry was pruned"
+        );
+        assert!(!dir.path().join("mid.svg").exists());
+        assert!(dir.path().join("new.svg").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tools_that_hang_are_killed() {
+        let child = Command::new("sleep")
+            .arg("30")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let started = Instant::now();
+        let output = wait_with_timeout(child, "", Duration::from_millis(200)).unwrap();
+        assert!(output.is_none());
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let child = Command::new("cat")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let output = wait_with_timeout(child, "<svg/>", Duration::from_secs(5))
+            .unwrap()
+            .expect("cat finishes");
+        assert_eq!(output.stdout, b"<svg/>");
+    }
+}
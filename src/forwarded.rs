@@ -0,0 +1,80 @@
+use axum::extract::Request;
+use axum::http::HeaderMap;
+use axum::middleware::Next;
+use axum::response::Response;
+
+/// Normalize a URL path prefix to `/a/b` form, or `""` for the root. Returns
+/// `None` for characters that would need escaping in a URL, header, or HTML
+/// attribute.
+pub fn normalize_prefix(raw: &str) -> Option<String> {
+    let trimmed = raw.trim().trim_matches('/');
+    if trimmed.is_empty() {
+        return Some(String::new());
+    }
+    let safe = trimmed
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "-._~/".contains(c));
+    let no_empty_segments = !trimmed.split('/').any(|seg| seg.is_empty() || seg == "..");
+    (safe && no_empty_segments).then(|| format!("/{trimmed}"))
+}
+
+/// The path prefix a browser sees in front of our routes: `X-Forwarded-Prefix`
+/// from a reverse proxy that strips it, followed by `--base-path`. Headers that
+/// do not normalize cleanly are ignored.
+pub fn public_prefix(headers: &HeaderMap, base_path: &str) -> String {
+    let forwarded = headers
+        .get("x-forwarded-prefix")
+        .and_then(|v| v.to_str().ok())
+        .and_then(normalize_prefix)
+        .unwrap_or_default();
+    format!("{forwarded}{base_path}")
+}
+
+/// Whether the browser reached us over HTTPS, either directly or through a
+/// proxy that terminated TLS and set `X-Forwarded-Proto`.
+pub fn is_https(headers: &HeaderMap, direct_tls: bool) -> bool {
+    direct_tls
+        || headers
+            .get("x-forwarded-proto")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(',').next())
+            .is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"))
+}
+
+/// Middleware dropping `X-Forwarded-Prefix` and `X-Forwarded-Proto` when no
+/// proxy is known to set them, so a client cannot steer the URLs, redirects,
+/// and cookie attributes derived from them.
+pub async fn strip(mut request: Request, next: Next) -> Response {
+    let headers = request.headers_mut();
+    headers.remove("x-forwarded-prefix");
+    headers.remove("x-forwarded-proto");
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn prefixes_are_normalized_or_rejected() {
+        assert_eq!(normalize_prefix("/").as_deref(), Some(""));
+        assert_eq!(normalize_prefix("preview/").as_deref(), Some("/preview"));
+        assert_eq!(normalize_prefix("/a/b").as_deref(), Some("/a/b"));
+        assert_eq!(normalize_prefix("/a//b"), None);
+        assert_eq!(normalize_prefix("/a/../b"), None);
+        assert_eq!(normalize_prefix("/\"><script>"), None);
+    }
+
+    #[test]
+    fn forwarded_headers_are_honored() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(public_prefix(&headers, "/docs"), "/docs");
+        assert!(!is_https(&headers, false));
+
+        headers.insert("x-forwarded-prefix", HeaderValue::from_static("/proxy/"));
+        headers.insert("x-forwarded-proto", HeaderValue::from_static("https, http"));
+        assert_eq!(public_prefix(&headers, "/docs"), "/proxy/docs");
+        assert!(is_https(&headers, false));
+    }
+}
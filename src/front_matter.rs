@@ -0,0 +1,523 @@
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+const BOM: char = '\u{feff}';
+
+/// How a document declares its metadata.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// A YAML block between `---` fences, as pandoc reads it.
+    Yaml,
+    /// A TOML block between `+++` fences, as Hugo writes it. Pandoc does not
+    /// understand these, so the document is rewritten before conversion.
+    Toml,
+    /// Pandoc's `% title` / `% author` / `% date` lines.
+    TitleBlock,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Theme {
+    Light,
+    Dark,
+    /// Follow the browser's `prefers-color-scheme`.
+    Auto,
+}
+
+impl Theme {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Theme::Light => "light",
+            Theme::Dark => "dark",
+            Theme::Auto => "auto",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MathEngine {
+    Katex,
+    Mathjax,
+    Mathml,
+    /// Leave TeX math as text (pandoc's `plain` method).
+    None,
+}
+
+/// The per-document pandoc options front matter may set. Deliberately an
+/// allowlist: a document must not be able to add filters or read files.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct PandocOptions {
+    pub toc: Option<bool>,
+    pub toc_depth: Option<u8>,
+    pub number_sections: Option<bool>,
+    pub shift_heading_level_by: Option<i8>,
+}
+
+/// What mdr itself reads from a document's front matter. Everything else in
+/// the block is left to pandoc and the template.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub date: Option<String>,
+    pub theme: Option<Theme>,
+    pub math: Option<MathEngine>,
+    pub draft: bool,
+    pub pandoc: PandocOptions,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FrontMatter {
+    pub format: Option<Format>,
+    pub metadata: Metadata,
+    /// Problems worth telling the author about; the document still renders.
+    pub warnings: Vec<String>,
+    fields: Map<String, Value>,
+    body_start: usize,
+    /// The heading promoted to the title, removed from what pandoc sees.
+    promoted: Option<Range<usize>>,
+    /// Its explicit `{#id}`, kept as an empty anchor so links still land.
+    promoted_id: Option<String>,
+}
+
+impl FrontMatter {
+    /// Byte offset of the document body, past any front matter.
+    pub fn body_start(&self) -> usize {
+        self.body_start
+    }
+
+    /// Use the first level-1 heading as the title when front matter has
+    /// none, taking it out of the body so it is not rendered twice.
+    pub fn promote_first_heading(&mut self, source: &str) {
+        if self.metadata.title.is_some() {
+            return;
+        }
+        if let Some((title, range)) = first_heading(source, self.body_start) {
+            let line = source[range.clone()].lines().next().unwrap_or_default();
+            self.promoted_id = explicit_id(line);
+            self.metadata.title = Some(title);
+            self.promoted = Some(range);
+        }
+    }
+
+    /// The source to hand pandoc instead of the file itself, when it would
+    /// not understand the original or a heading was promoted. TOML front
+    /// matter is replaced by an equivalent YAML (JSON) block.
+    pub fn pandoc_source(&self, source: &str) -> Option<String> {
+        if self.format != Some(Format::Toml) && self.promoted.is_none() {
+            return None;
+        }
+
+        let mut out = String::with_capacity(source.len());
+        if self.format == Some(Format::Toml) {
+            if !self.fields.is_empty() {
+                let block = serde_json::to_string(&self.fields).expect("front matter serializes");
+                out.push_str(&format!("---\n{block}\n---\n"));
+            }
+        } else {
+            out.push_str(&source[..self.body_start]);
+        }
+
+        match &self.promoted {
+            Some(heading) => {
+                // Pandoc merges metadata blocks; one that does not open the
+                // document needs a blank line before it.
+                if !out.is_empty() {
+                    out.push('\n');
+                }
+                let title = json!({ "title": self.metadata.title });
+                out.push_str(&format!("---\n{title}\n---\n"));
+                out.push_str(&source[self.body_start..heading.start]);
+                if let Some(id) = &self.promoted_id {
+                    out.push_str(&format!("<div id=\"{id}\"></div>\n\n"));
+                }
+                out.push_str(&source[heading.end..]);
+            }
+            None => out.push_str(&source[self.body_start..]),
+        }
+        Some(out)
+    }
+
+    fn read_fields(&mut self) {
+        let meta = &mut self.metadata;
+        let warnings = &mut self.warnings;
+        for (key, value) in &self.fields {
+            match key.as_str() {
+                "title" => meta.title = scalar(value).filter(|t| !t.trim().is_empty()),
+                "date" => meta.date = scalar(value),
+                "theme" => {
+                    meta.theme = match scalar(value).as_deref() {
+                        Some("light") => Some(Theme::Light),
+                        Some("dark") => Some(Theme::Dark),
+                        Some("auto") => Some(Theme::Auto),
+                        _ => {
+                            warnings.push(format!(
+                                "unknown theme {value}; expected light, dark or auto"
+                            ));
+                            None
+                        }
+                    }
+                }
+                "math" => {
+                    meta.math = match scalar(value).as_deref() {
+                        Some("katex") => Some(MathEngine::Katex),
+                        Some("mathjax") => Some(MathEngine::Mathjax),
+                        Some("mathml") => Some(MathEngine::Mathml),
+                        Some("none") => Some(MathEngine::None),
+                        _ => {
+                            warnings.push(format!(
+                                "unknown math engine {value}; expected katex, mathjax, mathml or none"
+                            ));
+                            None
+                        }
+                    }
+                }
+                "draft" => match value {
+                    Value::Bool(draft) => meta.draft = *draft,
+                    _ => warnings.push(format!("draft must be true or false, not {value}")),
+                },
+                "pandoc" => match PandocOptions::deserialize(value) {
+                    Ok(options) => meta.pandoc = options,
+                    Err(err) => warnings.push(format!("ignoring pandoc options: {err}")),
+                },
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Parse the front matter of the file at `path`. Unreadable files have none;
+/// the build reports them.
+pub fn read(path: &Path) -> FrontMatter {
+    fs::read_to_string(path)
+        .map(|source| parse(&source))
+        .unwrap_or_default()
+}
+
+pub fn parse(source: &str) -> FrontMatter {
+    let offset = if source.starts_with(BOM) {
+        BOM.len_utf8()
+    } else {
+        0
+    };
+    let text = &source[offset..];
+
+    if let Some((block, end)) = fenced_block(text, "+++") {
+        let mut front = FrontMatter {
+            format: Some(Format::Toml),
+            body_start: offset + end,
+            ..FrontMatter::default()
+        };
+        match toml::from_str::<toml::Table>(block) {
+            Ok(table) => front.fields = toml_fields(table),
+            Err(err) => front
+                .warnings
+                .push(format!("invalid TOML front matter: {}", err.message())),
+        }
+        front.read_fields();
+        return front;
+    }
+
+    if let Some((block, end)) = fenced_block(text, "---") {
+        // Like pandoc, only a mapping counts: anything else is a horizontal
+        // rule followed by ordinary text.
+        let mut front = FrontMatter {
+            format: Some(Format::Yaml),
+            body_start: offset + end,
+            ..FrontMatter::default()
+        };
+        match serde_yaml::from_str::<Value>(block) {
+            Ok(Value::Object(fields)) => front.fields = fields,
+            Ok(Value::Null) => {}
+            Ok(_) => return FrontMatter::default(),
+            Err(err) => front
+                .warnings
+                .push(format!("invalid YAML front matter: {err}")),
+        }
+        front.read_fields();
+        return front;
+    }
+
+    title_block(text, offset)
+}
+
+// The contents of a block opened by `fence` on the first line, and the offset
+// just past its closing fence. YAML blocks may also close with `...`.
+fn fenced_block<'a>(text: &'a str, fence: &str) -> Option<(&'a str, usize)> {
+    let first = text.find('\n')?;
+    if text[..first].trim_end() != fence {
+        return None;
+    }
+    let start = first + 1;
+    let mut pos = start;
+    while pos < text.len() {
+        let end = text[pos..].find('\n').map_or(text.len(), |i| pos + i + 1);
+        let line = text[pos..end].trim_end();
+        if pos == start && fence == "---" && line.is_empty() {
+            // `---` followed by a blank line is a horizontal rule.
+            return None;
+        }
+        if line == fence || (fence == "---" && line == "...") {
+            return Some((&text[start..pos], end));
+        }
+        pos = end;
+    }
+    None
+}
+
+fn title_block(text: &str, offset: usize) -> FrontMatter {
+    let block: Vec<&str> = text
+        .split_inclusive('\n')
+        .take_while(|line| line.starts_with('%'))
+        .collect();
+    let Some(title) = block.first() else {
+        return FrontMatter::default();
+    };
+    let date = block.get(2);
+
+    let non_empty = |s: &str| Some(s[1..].trim().to_string()).filter(|s| !s.is_empty());
+    FrontMatter {
+        format: Some(Format::TitleBlock),
+        metadata: Metadata {
+            title: non_empty(title),
+            date: date.and_then(|d| non_empty(d)),
+            ..Metadata::default()
+        },
+        body_start: offset + block.iter().map(|line| line.len()).sum::<usize>(),
+        ..FrontMatter::default()
+    }
+}
+
+// The first level-1 heading after `from` that is not inside a code fence: its
+// text and the byte range of its line(s).
+fn first_heading(source: &str, from: usize) -> Option<(String, Range<usize>)> {
+    let mut fence: Option<(char, usize)> = None;
+    let mut prev_blank = true;
+    let mut lines = source[from..]
+        .split_inclusive('\n')
+        .scan(from, |pos, line| {
+            let start = *pos;
+            *pos += line.len();
+            Some((start, line))
+        })
+        .peekable();
+
+    while let Some((start, line)) = lines.next() {
+        let indent = line.len() - line.trim_start_matches(' ').len();
+        let trimmed = line.trim();
+        if indent > 3 {
+            prev_blank = false;
+            continue;
+        }
+
+        let marker = trimmed.chars().next().filter(|c| *c == '`' || *c == '~');
+        if let Some(c) = marker {
+            let run = trimmed.chars().take_while(|&ch| ch == c).count();
+            if run >= 3 {
+                fence = match fence {
+                    None => Some((c, run)),
+                    Some((open, len)) if open == c && run >= len && trimmed.len() == run => None,
+                    other => other,
+                };
+                prev_blank = false;
+                continue;
+            }
+        }
+        if fence.is_some() {
+            continue;
+        }
+
+        if let Some(rest) = trimmed.strip_prefix('#') {
+            if rest.is_empty() || rest.starts_with([' ', '\t']) {
+                let title = clean_heading(rest);
+                if !title.is_empty() {
+                    return Some((title, start..start + line.len()));
+                }
+            }
+        } else if prev_blank && !trimmed.is_empty() {
+            let is_setext = lines.peek().is_some_and(|(_, next)| {
+                let next = next.trim_end();
+                let underline = next.trim_start_matches(' ');
+                next.len() - underline.len() <= 3
+                    && !underline.is_empty()
+                    && underline.chars().all(|c| c == '=')
+            });
+            if is_setext {
+                let (next_start, next) = lines.next().expect("peeked");
+                return Some((clean_heading(trimmed), start..next_start + next.len()));
+            }
+        }
+        prev_blank = trimmed.is_empty();
+    }
+    None
+}
+
+// Drop an ATX closing sequence and a trailing `{#id .class}` attribute block.
+fn clean_heading(text: &str) -> String {
+    let mut text = text.trim();
+    if text.ends_with('}') {
+        if let Some(open) = text.rfind(" {") {
+            text = text[..open].trim_end();
+        }
+    }
+    let unclosed = text.trim_end_matches('#');
+    if unclosed.is_empty() || unclosed.ends_with([' ', '\t']) {
+        text = unclosed.trim_end();
+    }
+    text.to_string()
+}
+
+// The `#id` in a heading's trailing `{#id .class}` attribute block.
+fn explicit_id(line: &str) -> Option<String> {
+    let line = line.trim();
+    let attrs = line.strip_suffix('}')?;
+    let attrs = &attrs[attrs.rfind(" {")? + 2..];
+    attrs
+        .split_whitespace()
+        .find_map(|token| token.strip_prefix('#'))
+        .filter(|id| !id.is_empty() && !id.contains(['"', '<', '>', '&']))
+        .map(str::to_string)
+}
+
+// TOML dates have no JSON equivalent; pandoc wants them as strings anyway.
+fn toml_fields(table: toml::Table) -> Map<String, Value> {
+    fn convert(value: toml::Value) -> Value {
+        match value {
+            toml::Value::String(s) => Value::String(s),
+            toml::Value::Integer(i) => Value::from(i),
+            toml::Value::Float(f) => Value::from(f),
+            toml::Value::Boolean(b) => Value::Bool(b),
+            toml::Value::Datetime(d) => Value::String(d.to_string()),
+            toml::Value::Array(items) => Value::Array(items.into_iter().map(convert).collect()),
+            toml::Value::Table(table) => Value::Object(toml_fields(table)),
+        }
+    }
+    table.into_iter().map(|(k, v)| (k, convert(v))).collect()
+}
+
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yaml_front_matter_reads_only_top_level_keys() {
+        let front = parse("---\nauthor:\n  title: Dr\ndate: 2025-12-23\n---\n# Body\n");
+        assert_eq!(front.format, Some(Format::Yaml));
+        assert_eq!(front.metadata.title, None);
+        assert_eq!(front.metadata.date.as_deref(), Some("2025-12-23"));
+
+        let front = parse("\u{feff}---\r\ntitle: Paper\r\ndraft: true\r\n...\r\nBody");
+        assert_eq!(front.metadata.title.as_deref(), Some("Paper"));
+        assert!(front.metadata.draft);
+        assert!(front.pandoc_source("").is_none());
+    }
+
+    #[test]
+    fn toml_front_matter_is_rewritten_for_pandoc() {
+        let source = "+++\ntitle = \"Hugo Page\"\ndate = 2024-01-02\ntheme = \"dark\"\n\
+                      [pandoc]\ntoc-depth = 2\n+++\nBody text\n";
+        let front = parse(source);
+        assert_eq!(front.format, Some(Format::Toml));
+        assert_eq!(front.metadata.title.as_deref(), Some("Hugo Page"));
+        assert_eq!(front.metadata.theme, Some(Theme::Dark));
+        assert_eq!(front.metadata.pandoc.toc_depth, Some(2));
+        assert!(front.warnings.is_empty(), "{:?}", front.warnings);
+
+        let rewritten = front.pandoc_source(source).unwrap();
+        assert!(rewritten.starts_with("---\n{"), "{rewritten}");
+        assert!(rewritten.contains(r#""date":"2024-01-02""#), "{rewritten}");
+        assert!(rewritten.ends_with("---\nBody text\n"), "{rewritten}");
+    }
+
+    #[test]
+    fn title_blocks_and_non_front_matter() {
+        let front = parse("% The Title\n% Author\n% 2020-01-01\n\nBody");
+        assert_eq!(front.format, Some(Format::TitleBlock));
+        assert_eq!(front.metadata.title.as_deref(), Some("The Title"));
+        assert_eq!(front.metadata.date.as_deref(), Some("2020-01-01"));
+        assert_eq!(parse("%\n% Author\n").metadata.title, None);
+
+        assert_eq!(parse("---\n\ntitle: rule\n---\n"), FrontMatter::default());
+        assert_eq!(parse("---\njust text\n---\n"), FrontMatter::default());
+        assert_eq!(parse("# Heading\ntitle: no\n"), FrontMatter::default());
+    }
+
+    #[test]
+    fn bad_values_warn_without_losing_the_rest() {
+        let front = parse("---\ntitle: Kept\nmath: tikz\npandoc:\n  lua-filter: x.lua\n---\n");
+        assert_eq!(front.metadata.title.as_deref(), Some("Kept"));
+        assert_eq!(front.metadata.math, None);
+        assert_eq!(front.metadata.pandoc, PandocOptions::default());
+        assert_eq!(front.warnings.len(), 2, "{:?}", front.warnings);
+
+        let front = parse("+++\ntitle = \n+++\nBody");
+        assert_eq!(front.format, Some(Format::Toml));
+        assert_eq!(front.warnings.len(), 1);
+        assert_eq!(
+            front.pandoc_source("+++\ntitle = \n+++\nBody").unwrap(),
+            "Body"
+        );
+    }
+
+    #[test]
+    fn first_level_one_heading_is_promoted() {
+        let source = "```sh\n# not a heading\n```\n\n## Sub\n\n# Project Name {#top}\n\nText\n";
+        let mut front = parse(source);
+        front.promote_first_heading(source);
+        assert_eq!(front.metadata.title.as_deref(), Some("Project Name"));
+        assert_eq!(
+            front.pandoc_source(source).unwrap(),
+            "---\n{\"title\":\"Project Name\"}\n---\n```sh\n# not a heading\n```\n\n## Sub\n\n<div id=\"top\"></div>\n\n\nText\n"
+        );
+
+        let source = "---\nauthor: Me\n---\nTitle Here\n==========\n\nText\n";
+        let mut front = parse(source);
+        front.promote_first_heading(source);
+        assert_eq!(front.metadata.title.as_deref(), Some("Title Here"));
+        assert_eq!(
+            front.pandoc_source(source).unwrap(),
+            "---\nauthor: Me\n---\n\n---\n{\"title\":\"Title Here\"}\n---\n\nText\n"
+        );
+
+        let source = "---\ntitle: Given\n---\n# Heading ##\n";
+        let mut front = parse(source);
+        front.promote_first_heading(source);
+        assert_eq!(front.metadata.title.as_deref(), Some("Given"));
+        assert!(front.pandoc_source(source).is_none());
+
+        assert_eq!(clean_heading(" The `mdr` tool ##"), "The `mdr` tool");
+        assert_eq!(clean_heading(" C#"), "C#");
+        assert!(first_heading("#hashtag\n    # indented code\n", 0).is_none());
+    }
+
+    #[test]
+    fn promoted_heading_keeps_its_explicit_id() {
+        let source = "# Project {.big #top}\n\nIntro.\n\n[back to top](#top)\n";
+        let mut front = parse(source);
+        front.promote_first_heading(source);
+        assert_eq!(front.metadata.title.as_deref(), Some("Project"));
+        assert_eq!(
+            front.pandoc_source(source).unwrap(),
+            "---\n{\"title\":\"Project\"}\n---\n<div id=\"top\"></div>\n\n\nIntro.\n\n[back to top](#top)\n"
+        );
+
+        let source = "# Project\n\nIntro.\n";
+        let mut front = parse(source);
+        front.promote_first_heading(source);
+        assert!(!front.pandoc_source(source).unwrap().contains("<div"));
+        assert_eq!(explicit_id("Title {#intro}"), Some("intro".into()));
+        assert_eq!(explicit_id("Set {x}"), None);
+    }
+}
@@ -0,0 +1,304 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use axum::body::Bytes;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    fn token(self) -> &'static str {
+        match self {
+            Encoding::Identity => "identity",
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+        }
+    }
+}
+
+/// Hash used for ETags. Only compared within one server run, where the
+/// standard hasher is deterministic.
+pub fn content_hash(value: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Pick the best encoding the client accepts: brotli, then gzip, else none.
+/// `q=0` rules an encoding out; other weights are not ranked.
+fn negotiate(headers: &HeaderMap) -> Encoding {
+    let Some(accept) = headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return Encoding::Identity;
+    };
+
+    let accepts = |name: &str| {
+        accept.split(',').any(|item| {
+            let mut parts = item.split(';');
+            let coding = parts.next().unwrap_or_default().trim();
+            let refused = parts.any(|p| {
+                p.trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    == Some(0.0)
+            });
+            (coding.eq_ignore_ascii_case(name) || coding == "*") && !refused
+        })
+    };
+
+    if accepts("br") {
+        Encoding::Brotli
+    } else if accepts("gzip") {
+        Encoding::Gzip
+    } else {
+        Encoding::Identity
+    }
+}
+
+fn compress(body: Vec<u8>, encoding: Encoding) -> Vec<u8> {
+    match encoding {
+        Encoding::Identity => body,
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder
+                .write_all(&body)
+                .and_then(|()| encoder.finish())
+                .expect("writing to a Vec cannot fail")
+        }
+        Encoding::Brotli => {
+            let mut out = Vec::new();
+            {
+                // Quality 5 keeps the first load after a rebuild snappy; the
+                // top levels are far slower for little gain on HTML.
+                let mut writer = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+                writer
+                    .write_all(&body)
+                    .expect("writing to a Vec cannot fail");
+            }
+            out
+        }
+    }
+}
+
+/// Compressed bodies keyed by ETag, so repeated loads of an unchanged page do
+/// not recompress it. When full, the least recently used body is evicted, so
+/// rebuilds age out old versions without dropping the other documents' pages.
+#[derive(Clone, Default)]
+pub struct BodyCache {
+    // Least recently used first; small enough that a linear scan is cheapest.
+    entries: Arc<Mutex<VecDeque<(String, Bytes)>>>,
+}
+
+const MAX_CACHED_BODIES: usize = 16;
+
+impl BodyCache {
+    fn get_or_insert(&self, etag: &str, make: impl FnOnce() -> Vec<u8>) -> Bytes {
+        let mut entries = self.entries.lock().expect("body cache lock");
+        if let Some(at) = entries.iter().position(|(tag, _)| tag == etag) {
+            let entry = entries.remove(at).expect("position is in bounds");
+            let body = entry.1.clone();
+            entries.push_back(entry);
+            return body;
+        }
+        if entries.len() >= MAX_CACHED_BODIES {
+            entries.pop_front();
+        }
+        let body = Bytes::from(make());
+        entries.push_back((etag.to_string(), body.clone()));
+        body
+    }
+}
+
+/// A response body plus the validators that describe it. `body` only runs
+/// when the client's copy is stale and no encoded copy is cached.
+pub struct Cached<F> {
+    pub content_type: &'static str,
+    /// Hash of everything that shapes the body except its encoding.
+    pub tag: u64,
+    pub last_modified: Option<SystemTime>,
+    pub body: F,
+}
+
+impl<F: FnOnce() -> Vec<u8>> Cached<F> {
+    /// Answer with `304 Not Modified` when the client's copy is current,
+    /// otherwise with the body in the best encoding the client accepts.
+    pub fn respond(self, request: &HeaderMap, cache: &BodyCache) -> Response {
+        let encoding = negotiate(request);
+        let etag = match encoding {
+            Encoding::Identity => format!("\"{:016x}\"", self.tag),
+            other => format!("\"{:016x}-{}\"", self.tag, other.token()),
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::ETAG,
+            HeaderValue::from_str(&etag).expect("hex etag"),
+        );
+        // The preview changes under the browser, so always revalidate.
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        headers.insert(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+        if let Some(modified) = self.last_modified {
+            let date = httpdate::fmt_http_date(modified);
+            headers.insert(
+                header::LAST_MODIFIED,
+                HeaderValue::from_str(&date).expect("HTTP date"),
+            );
+        }
+
+        if is_fresh(request, &etag, self.last_modified) {
+            return (StatusCode::NOT_MODIFIED, headers).into_response();
+        }
+
+        let body = self.body;
+        let encoded = cache.get_or_insert(&etag, || compress(body(), encoding));
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(self.content_type),
+        );
+        if encoding != Encoding::Identity {
+            headers.insert(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_static(encoding.token()),
+            );
+        }
+        (StatusCode::OK, headers, encoded).into_response()
+    }
+}
+
+// `If-None-Match` wins over `If-Modified-Since` (RFC 9110 §13.2.2).
+fn is_fresh(request: &HeaderMap, etag: &str, last_modified: Option<SystemTime>) -> bool {
+    if let Some(candidates) = request
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+    {
+        return candidates.split(',').any(|candidate| {
+            let candidate = candidate.trim();
+            candidate == "*" || candidate.trim_start_matches("W/") == etag
+        });
+    }
+
+    let since = request
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok());
+    match (since, last_modified) {
+        // HTTP dates have one-second resolution.
+        (Some(since), Some(modified)) => httpdate::HttpDate::from(modified) <= since.into(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::time::Duration;
+
+    fn headers(pairs: &[(header::HeaderName, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.insert(name.clone(), HeaderValue::from_str(value).unwrap());
+        }
+        map
+    }
+
+    #[test]
+    fn negotiation_prefers_brotli_and_honors_q_zero() {
+        let pick = |v: &str| negotiate(&headers(&[(header::ACCEPT_ENCODING, v)]));
+        assert_eq!(pick("gzip, deflate, br"), Encoding::Brotli);
+        assert_eq!(pick("gzip, br;q=0"), Encoding::Gzip);
+        assert_eq!(pick("identity"), Encoding::Identity);
+        assert_eq!(negotiate(&HeaderMap::new()), Encoding::Identity);
+    }
+
+    #[test]
+    fn gzip_round_trips() {
+        let body = b"<p>hello hello hello</p>".repeat(50);
+        let packed = compress(body.clone(), Encoding::Gzip);
+        let mut unpacked = Vec::new();
+        flate2::read::GzDecoder::new(packed.as_slice())
+            .read_to_end(&mut unpacked)
+            .unwrap();
+        assert!(packed.len() < body.len());
+        assert_eq!(unpacked, body);
+    }
+
+    #[test]
+    fn body_is_only_produced_on_a_cache_miss() {
+        let cache = BodyCache::default();
+        let cached = |body: fn() -> Vec<u8>| Cached {
+            content_type: "text/html; charset=utf-8",
+            tag: 0xff,
+            last_modified: None,
+            body,
+        };
+
+        let fresh = headers(&[(header::IF_NONE_MATCH, "\"00000000000000ff\"")]);
+        let response = cached(|| panic!("304 must not build the body")).respond(&fresh, &cache);
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let response = cached(|| b"<p>page</p>".to_vec()).respond(&HeaderMap::new(), &cache);
+        assert_eq!(response.status(), StatusCode::OK);
+        let response =
+            cached(|| panic!("cached body must be reused")).respond(&HeaderMap::new(), &cache);
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn cache_evicts_the_least_recently_used_body() {
+        let cache = BodyCache::default();
+        for i in 0..MAX_CACHED_BODIES {
+            cache.get_or_insert(&i.to_string(), Vec::new);
+        }
+        // Touch the oldest so the second oldest is evicted instead.
+        cache.get_or_insert("0", || panic!("0 is cached"));
+        cache.get_or_insert("new", Vec::new);
+
+        cache.get_or_insert("0", || panic!("recently used body was evicted"));
+        let mut rebuilt = false;
+        cache.get_or_insert("1", || {
+            rebuilt = true;
+            Vec::new()
+        });
+        assert!(rebuilt, "least recently used body should be evicted");
+    }
+
+    #[test]
+    fn validators_produce_not_modified() {
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let etag = "\"00000000000000ff\"";
+
+        assert!(is_fresh(
+            &headers(&[(header::IF_NONE_MATCH, "W/\"00000000000000ff\"")]),
+            etag,
+            None
+        ));
+        assert!(!is_fresh(
+            &headers(&[(header::IF_NONE_MATCH, "\"other\"")]),
+            etag,
+            Some(modified)
+        ));
+        assert!(is_fresh(
+            &headers(&[(
+                header::IF_MODIFIED_SINCE,
+                &httpdate::fmt_http_date(modified)
+            )]),
+            etag,
+            Some(modified + Duration::from_millis(400))
+        ));
+    }
+}
@@ -0,0 +1,99 @@
+use std::net::{IpAddr, SocketAddr};
+
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
+
+/// Addresses other devices can reach when the server binds `bind`, best first.
+///
+/// A specific address is returned as-is; an unspecified one (`0.0.0.0`, `::`)
+/// expands to every non-loopback interface address of the matching family
+/// (`::` also accepts IPv4 on dual-stack hosts).
+pub fn reachable_addrs(bind: IpAddr) -> Vec<IpAddr> {
+    if !bind.is_unspecified() {
+        return vec![bind];
+    }
+
+    let Ok(interfaces) = if_addrs::get_if_addrs() else {
+        return Vec::new();
+    };
+
+    let mut found: Vec<(bool, IpAddr)> = interfaces
+        .into_iter()
+        // IPv6 link-local addresses need a zone id that browsers do not accept.
+        .filter(|iface| !iface.is_loopback() && !iface.is_link_local())
+        .filter(|iface| bind.is_ipv6() || iface.ip().is_ipv4())
+        .map(|iface| (is_virtual(&iface.name), iface.ip()))
+        .collect();
+
+    found.sort_by_key(|(virt, ip)| (*virt, rank(ip)));
+    found.dedup_by_key(|(_, ip)| *ip);
+    found.into_iter().map(|(_, ip)| ip).collect()
+}
+
+// Container and VM bridges are rarely what a phone on the same Wi-Fi can reach.
+fn is_virtual(name: &str) -> bool {
+    [
+        "docker", "br-", "veth", "virbr", "vmnet", "vboxnet", "tun", "tap", "utun",
+    ]
+    .iter()
+    .any(|prefix| name.starts_with(prefix))
+}
+
+fn rank(ip: &IpAddr) -> u8 {
+    match ip {
+        IpAddr::V4(v4) if v4.is_private() => 0,
+        IpAddr::V4(_) => 1,
+        IpAddr::V6(_) => 2,
+    }
+}
+
+/// URL for `ip` on the server's port; `path` starts with `/`.
+pub fn url(scheme: &str, ip: IpAddr, port: u16, path: &str) -> String {
+    format!("{scheme}://{}{path}", SocketAddr::new(ip, port))
+}
+
+/// Render `data` as a QR code for the terminal, two modules per character.
+/// Colors are forced so the code scans on dark and light themes alike.
+pub fn qr_code(data: &str) -> Option<String> {
+    let code = QrCode::new(data.as_bytes()).ok()?;
+    let image = code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Dark)
+        .light_color(Dense1x2::Light)
+        .build();
+    Some(
+        image
+            .lines()
+            .map(|line| format!("\x1b[30;47m{line}\x1b[0m\n"))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn specific_bind_address_is_kept_and_formatted() {
+        let ip: IpAddr = "192.168.1.20".parse().unwrap();
+        assert_eq!(reachable_addrs(ip), vec![ip]);
+        assert_eq!(
+            url("http", "fe80::1".parse().unwrap(), 8080, "/?token=x"),
+            "http://[fe80::1]:8080/?token=x"
+        );
+    }
+
+    #[test]
+    fn unspecified_v4_bind_lists_only_non_loopback_v4() {
+        let addrs = reachable_addrs("0.0.0.0".parse().unwrap());
+        assert!(addrs.iter().all(|ip| ip.is_ipv4() && !ip.is_loopback()));
+    }
+
+    #[test]
+    fn qr_code_renders_square_block() {
+        let qr = qr_code("http://192.168.1.20:8080/").expect("encodes");
+        let rows: Vec<&str> = qr.lines().collect();
+        assert!(rows.len() > 10);
+        assert!(rows.iter().all(|r| r.starts_with("\x1b[30;47m")));
+    }
+}
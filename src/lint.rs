@@ -0,0 +1,692 @@
+use crate::front_matter;
+use crate::settings::{LintSettings, Severity};
+
+/// Something in the Markdown that mdr's pipeline (pandoc with
+/// `tex_math_single_backslash`, the sidenote filter, the theme) renders
+/// differently from what the author most likely meant.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rule {
+    /// `$$`, `\(` or `\[` (or `$\command`) with nothing closing it in the
+    /// same paragraph, so the TeX shows up as text.
+    UnclosedMath,
+    /// A footnote too long, or with too many blocks, to read as a sidenote.
+    LongSidenote,
+    /// `{-}` anywhere but the start of a footnote, where it is printed
+    /// instead of turning the note into a margin note.
+    MarginMarker,
+    /// A heading more than one level below the previous one.
+    HeadingSkip,
+    TrailingWhitespace,
+}
+
+impl Rule {
+    pub fn name(self) -> &'static str {
+        match self {
+            Rule::UnclosedMath => "unclosed-math",
+            Rule::LongSidenote => "long-sidenote",
+            Rule::MarginMarker => "margin-marker",
+            Rule::HeadingSkip => "heading-skip",
+            Rule::TrailingWhitespace => "trailing-whitespace",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Finding {
+    /// 1-based line in the source file.
+    pub line: usize,
+    pub rule: Rule,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// Rule severities and limits, from the `[lint]` config table.
+pub struct Options {
+    unclosed_math: Severity,
+    long_sidenote: Severity,
+    margin_marker: Severity,
+    heading_skip: Severity,
+    trailing_whitespace: Severity,
+    max_sidenote_words: usize,
+}
+
+impl Options {
+    pub const DEFAULT_MAX_SIDENOTE_WORDS: usize = 60;
+
+    pub fn from_settings(settings: &LintSettings) -> Self {
+        Options {
+            unclosed_math: settings.unclosed_math.unwrap_or(Severity::Error),
+            long_sidenote: settings.long_sidenote.unwrap_or(Severity::Warning),
+            margin_marker: settings.margin_marker.unwrap_or(Severity::Error),
+            heading_skip: settings.heading_skip.unwrap_or(Severity::Warning),
+            trailing_whitespace: settings.trailing_whitespace.unwrap_or(Severity::Warning),
+            max_sidenote_words: settings
+                .max_sidenote_words
+                .unwrap_or(Self::DEFAULT_MAX_SIDENOTE_WORDS),
+        }
+    }
+
+    fn severity(&self, rule: Rule) -> Severity {
+        match rule {
+            Rule::UnclosedMath => self.unclosed_math,
+            Rule::LongSidenote => self.long_sidenote,
+            Rule::MarginMarker => self.margin_marker,
+            Rule::HeadingSkip => self.heading_skip,
+            Rule::TrailingWhitespace => self.trailing_whitespace,
+        }
+    }
+}
+
+/// Run every rule that is not turned off over a Markdown document, front
+/// matter included. Findings are in line order.
+pub fn lint(source: &str, options: &Options) -> Vec<Finding> {
+    let body_start = front_matter::parse(source).body_start();
+    let first_line = source[..body_start].matches('\n').count();
+    let lines: Vec<(usize, &str)> = source
+        .lines()
+        .enumerate()
+        .skip(first_line)
+        .map(|(i, line)| (i + 1, line))
+        .collect();
+
+    let mut found = Vec::new();
+    let mut report = |line: usize, rule: Rule, message: String| {
+        let severity = options.severity(rule);
+        if severity != Severity::Off {
+            found.push(Finding {
+                line,
+                rule,
+                severity,
+                message,
+            });
+        }
+    };
+
+    let mut previous_level = None;
+    for block in blocks(&lines) {
+        match block {
+            Block::Heading { line, level, text } => {
+                if let Some(previous) = previous_level {
+                    if level > previous + 1 {
+                        report(
+                            line,
+                            Rule::HeadingSkip,
+                            format!("heading level jumps from h{previous} to h{level}"),
+                        );
+                    }
+                }
+                previous_level = Some(level);
+                check_math(&Paragraph::new(&[(line, text)]), &mut report);
+            }
+            Block::Paragraph(lines) => {
+                let paragraph = Paragraph::new(&lines);
+                check_math(&paragraph, &mut report);
+                check_inline_notes(&paragraph, options, &mut report);
+            }
+            Block::Note { label, paragraphs } => {
+                let paragraphs: Vec<Paragraph> =
+                    paragraphs.iter().map(|p| Paragraph::new(p)).collect();
+                for paragraph in &paragraphs {
+                    check_math(paragraph, &mut report);
+                }
+                check_note(&format!("[^{label}]"), &paragraphs, options, &mut report);
+            }
+        }
+    }
+
+    for (number, line) in source.lines().enumerate() {
+        let trimmed = line.trim_end_matches([' ', '\t']);
+        if trimmed.len() == line.len() {
+            continue;
+        }
+        let message = if trimmed.is_empty() {
+            "line is only whitespace".to_string()
+        } else if line[trimmed.len()..].starts_with("  ") {
+            "trailing whitespace (two or more spaces make an invisible line break; \
+             end the line with \\ instead)"
+                .to_string()
+        } else {
+            "trailing whitespace".to_string()
+        };
+        report(number + 1, Rule::TrailingWhitespace, message);
+    }
+
+    found.sort_by_key(|finding| finding.line);
+    found
+}
+
+// Numbered source lines.
+type Lines<'a> = Vec<(usize, &'a str)>;
+
+enum Block<'a> {
+    Heading {
+        line: usize,
+        level: usize,
+        text: &'a str,
+    },
+    Paragraph(Lines<'a>),
+    /// A footnote definition, one entry per paragraph or indented block.
+    Note {
+        label: &'a str,
+        paragraphs: Vec<Lines<'a>>,
+    },
+}
+
+// Just enough block structure for the rules: code (fenced, indented) and
+// HTML comments are dropped, footnote definitions keep their indented
+// continuation blocks.
+fn blocks<'a>(lines: &[(usize, &'a str)]) -> Vec<Block<'a>> {
+    let mut blocks = Vec::new();
+    let mut current: Lines<'a> = Vec::new();
+    // The footnote being defined, while indented blocks still belong to it.
+    let mut note: Option<(&'a str, Vec<Lines<'a>>)> = None;
+    let mut fence: Option<(char, usize)> = None;
+    let mut in_comment = false;
+    let mut after_blank = true;
+
+    fn flush<'a>(
+        current: &mut Lines<'a>,
+        note: &mut Option<(&'a str, Vec<Lines<'a>>)>,
+        blocks: &mut Vec<Block<'a>>,
+    ) {
+        if current.is_empty() {
+            return;
+        }
+        let lines = std::mem::take(current);
+        match note {
+            Some((_, paragraphs)) => paragraphs.push(lines),
+            None => blocks.push(Block::Paragraph(lines)),
+        }
+    }
+    fn end_note<'a>(note: &mut Option<(&'a str, Vec<Lines<'a>>)>, blocks: &mut Vec<Block<'a>>) {
+        if let Some((label, paragraphs)) = note.take() {
+            blocks.push(Block::Note { label, paragraphs });
+        }
+    }
+
+    for &(number, line) in lines {
+        if let Some((marker, len)) = fence {
+            if fence_marker(line).is_some_and(|(m, l)| m == marker && l >= len) {
+                fence = None;
+            }
+            continue;
+        }
+        if in_comment {
+            in_comment = !line.contains("-->");
+            continue;
+        }
+        if line.trim().is_empty() {
+            flush(&mut current, &mut note, &mut blocks);
+            after_blank = true;
+            continue;
+        }
+
+        let indented = line.starts_with('\t') || line.starts_with("    ");
+        if indented && current.is_empty() {
+            if note.is_some() {
+                current.push((number, line.trim_start()));
+            }
+            // Otherwise an indented code block (or a list item's
+            // continuation, which is skipped just the same).
+            after_blank = false;
+            continue;
+        }
+        if !indented && after_blank && current.is_empty() {
+            flush(&mut current, &mut note, &mut blocks);
+            end_note(&mut note, &mut blocks);
+        }
+        after_blank = false;
+
+        if let Some(marker) = fence_marker(line) {
+            flush(&mut current, &mut note, &mut blocks);
+            end_note(&mut note, &mut blocks);
+            fence = Some(marker);
+            continue;
+        }
+        if let Some(start) = line.find("<!--") {
+            in_comment = !line[start..].contains("-->");
+        }
+        if let Some((label, text)) = note_definition(line) {
+            flush(&mut current, &mut note, &mut blocks);
+            end_note(&mut note, &mut blocks);
+            note = Some((label, Vec::new()));
+            current.push((number, text));
+            continue;
+        }
+        if let Some((level, text)) = atx_heading(line) {
+            flush(&mut current, &mut note, &mut blocks);
+            end_note(&mut note, &mut blocks);
+            blocks.push(Block::Heading {
+                line: number,
+                level,
+                text,
+            });
+            after_blank = true;
+            continue;
+        }
+        if let Some(level) = setext_level(line) {
+            if let ([(text_line, text)], None) = (current.as_slice(), &note) {
+                blocks.push(Block::Heading {
+                    line: *text_line,
+                    level,
+                    text,
+                });
+                current.clear();
+                after_blank = true;
+                continue;
+            }
+        }
+        current.push((number, line));
+    }
+    flush(&mut current, &mut note, &mut blocks);
+    end_note(&mut note, &mut blocks);
+    blocks
+}
+
+fn fence_marker(line: &str) -> Option<(char, usize)> {
+    let trimmed = line.trim_start_matches(' ');
+    if line.len() - trimmed.len() > 3 {
+        return None;
+    }
+    let marker = trimmed.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = trimmed.chars().take_while(|c| *c == marker).count();
+    (len >= 3).then_some((marker, len))
+}
+
+fn note_definition(line: &str) -> Option<(&str, &str)> {
+    let rest = line.strip_prefix("[^")?;
+    let (label, text) = rest.split_once("]:")?;
+    if label.is_empty() || label.contains(char::is_whitespace) {
+        return None;
+    }
+    Some((label, text.trim_start()))
+}
+
+fn atx_heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.chars().take_while(|c| *c == '#').count();
+    let text = &line[level..];
+    if !(1..=6).contains(&level) || !(text.is_empty() || text.starts_with([' ', '\t'])) {
+        return None;
+    }
+    Some((level, text.trim()))
+}
+
+fn setext_level(line: &str) -> Option<usize> {
+    let trimmed = line.trim_end();
+    match trimmed.chars().next()? {
+        '=' if trimmed.chars().all(|c| c == '=') => Some(1),
+        '-' if trimmed.len() >= 2 && trimmed.chars().all(|c| c == '-') => Some(2),
+        _ => None,
+    }
+}
+
+// A paragraph's lines joined, with code spans blanked out so their contents
+// are not mistaken for math or markers.
+struct Paragraph {
+    text: String,
+    /// Byte offset in `text` where each source line starts, and its number.
+    starts: Vec<(usize, usize)>,
+}
+
+impl Paragraph {
+    fn new(lines: &[(usize, &str)]) -> Self {
+        let mut text = String::new();
+        let mut starts = Vec::with_capacity(lines.len());
+        for (number, line) in lines {
+            if !text.is_empty() {
+                text.push('\n');
+            }
+            starts.push((text.len(), *number));
+            text.push_str(line);
+        }
+        Paragraph {
+            text: blank_code_spans(&text),
+            starts,
+        }
+    }
+
+    fn line_at(&self, offset: usize) -> usize {
+        let index = self.starts.partition_point(|(start, _)| *start <= offset);
+        self.starts[index.saturating_sub(1)].1
+    }
+}
+
+fn blank_code_spans(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = bytes.to_vec();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'`' {
+            i += 1;
+            continue;
+        }
+        let ticks = bytes[i..].iter().take_while(|b| **b == b'`').count();
+        let open_end = i + ticks;
+        let mut j = open_end;
+        let mut close = None;
+        while j < bytes.len() {
+            if bytes[j] == b'`' {
+                let run = bytes[j..].iter().take_while(|b| **b == b'`').count();
+                if run == ticks {
+                    close = Some(j);
+                    break;
+                }
+                j += run;
+            } else {
+                j += 1;
+            }
+        }
+        match close {
+            Some(end) => {
+                for byte in &mut out[open_end..end] {
+                    if *byte != b'\n' {
+                        *byte = b' ';
+                    }
+                }
+                i = end + ticks;
+            }
+            None => i = open_end,
+        }
+    }
+    // Every byte of a multi-byte character inside a span became a space.
+    String::from_utf8(out).expect("blanking keeps UTF-8 valid")
+}
+
+fn check_math(paragraph: &Paragraph, report: &mut impl FnMut(usize, Rule, String)) {
+    let text = &paragraph.text;
+    let bytes = text.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let (open, close, what) = match bytes[i] {
+            b'\\' => match bytes.get(i + 1) {
+                Some(b'(') => ("\\(", "\\)", "inline math"),
+                Some(b'[') => ("\\[", "\\]", "display math"),
+                _ => {
+                    i += 2;
+                    continue;
+                }
+            },
+            b'$' if bytes.get(i + 1) == Some(&b'$') => ("$$", "$$", "display math"),
+            b'$' => {
+                let opens = bytes.get(i + 1).is_some_and(|b| !b.is_ascii_whitespace());
+                match opens.then(|| dollar_close(text, i + 1)).flatten() {
+                    Some(end) => i = end + 1,
+                    None if bytes.get(i + 1) == Some(&b'\\') => {
+                        report(
+                            paragraph.line_at(i),
+                            Rule::UnclosedMath,
+                            "`$` starts inline math that is never closed in this paragraph"
+                                .to_string(),
+                        );
+                        i += 1;
+                    }
+                    None => i += 1,
+                }
+                continue;
+            }
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        match text[i + open.len()..].find(close) {
+            Some(end) => i += open.len() + end + close.len(),
+            None => {
+                let hint = if open == "\\[" {
+                    "; with tex_math_single_backslash, write `[` for a literal bracket"
+                } else {
+                    ""
+                };
+                report(
+                    paragraph.line_at(i),
+                    Rule::UnclosedMath,
+                    format!("`{open}` starts {what} that is never closed with `{close}`{hint}"),
+                );
+                i += open.len();
+            }
+        }
+    }
+}
+
+// Pandoc's rule for a closing `$`: no space before it, no digit after it.
+fn dollar_close(text: &str, from: usize) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let mut i = from;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'$' if !bytes[i - 1].is_ascii_whitespace()
+                && !bytes.get(i + 1).is_some_and(u8::is_ascii_digit) =>
+            {
+                return Some(i);
+            }
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+// `^[...]` notes in a paragraph, plus any `{-}` outside of them.
+fn check_inline_notes(
+    paragraph: &Paragraph,
+    options: &Options,
+    report: &mut impl FnMut(usize, Rule, String),
+) {
+    let text = &paragraph.text;
+    let mut outside = 0;
+    let mut search = 0;
+    while let Some(found) = text[search..].find("^[") {
+        let start = search + found;
+        let Some(len) = bracketed_len(&text[start + 1..]) else {
+            break;
+        };
+        stray_markers(paragraph, outside..start, report);
+        let content = &text[start + 2..start + len];
+        let note = Paragraph {
+            text: content.to_string(),
+            starts: paragraph
+                .starts
+                .iter()
+                .map(|(offset, number)| (offset.saturating_sub(start + 2), *number))
+                .collect(),
+        };
+        check_note("inline note", &[note], options, report);
+        search = start + 1 + len;
+        outside = search;
+    }
+    stray_markers(paragraph, outside..text.len(), report);
+}
+
+// Length of `[...]` at the start of `text`, brackets included.
+fn bracketed_len(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '[' => depth += 1,
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i + 1);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn stray_markers(
+    paragraph: &Paragraph,
+    range: std::ops::Range<usize>,
+    report: &mut impl FnMut(usize, Rule, String),
+) {
+    for (at, _) in paragraph.text[range.clone()].match_indices("{-}") {
+        report(
+            paragraph.line_at(range.start + at),
+            Rule::MarginMarker,
+            "`{-}` outside a footnote is printed as is; a margin note starts with it: \
+             `^[{-} ...]` or `[^label]: {-} ...`"
+                .to_string(),
+        );
+    }
+}
+
+fn check_note(
+    name: &str,
+    paragraphs: &[Paragraph],
+    options: &Options,
+    report: &mut impl FnMut(usize, Rule, String),
+) {
+    let Some(first) = paragraphs.first() else {
+        return;
+    };
+    let line = first.line_at(0);
+
+    let lead = first.text.len() - first.text.trim_start().len();
+    let mut markers_from = 0;
+    if first.text[lead..].starts_with("{-}") {
+        markers_from = lead + "{-}".len();
+        let rest = &first.text[markers_from..];
+        if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+            report(
+                line,
+                Rule::MarginMarker,
+                format!("{name} starts with `{{-}}` but no space after it, so it is printed"),
+            );
+        }
+    }
+    for (index, paragraph) in paragraphs.iter().enumerate() {
+        let from = if index == 0 { markers_from } else { 0 };
+        for (at, _) in paragraph.text[from..].match_indices("{-}") {
+            report(
+                paragraph.line_at(from + at),
+                Rule::MarginMarker,
+                "`{-}` only makes a margin note at the very start of a footnote; \
+                 here it is printed as is"
+                    .to_string(),
+            );
+        }
+    }
+
+    if paragraphs.len() > 1 {
+        report(
+            line,
+            Rule::LongSidenote,
+            format!(
+                "{name} has {} blocks; the sidenote runs them together in one paragraph",
+                paragraphs.len()
+            ),
+        );
+    }
+    let words: usize = paragraphs
+        .iter()
+        .map(|p| p.text.split_whitespace().filter(|w| *w != "{-}").count())
+        .sum();
+    if words > options.max_sidenote_words {
+        report(
+            line,
+            Rule::LongSidenote,
+            format!(
+                "{name} is {words} words, more than the {} that fit a sidenote",
+                options.max_sidenote_words
+            ),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(source: &str) -> Vec<(usize, &'static str)> {
+        lint(source, &Options::from_settings(&LintSettings::default()))
+            .iter()
+            .map(|f| (f.line, f.rule.name()))
+            .collect()
+    }
+
+    #[test]
+    fn finds_unclosed_math_but_not_prices_or_code() {
+        let source = "\
+---
+title: Math
+---
+
+Costs $5 or $10, and $x^2$ is fine, as is \\(a\\) and `\\(`.
+
+Here \\[x =
+y\\] spans lines but \\(z never closes.
+
+$$E = mc^2
+
+$\\alpha and more
+";
+        assert_eq!(
+            rules(source),
+            [
+                (8, "unclosed-math"),
+                (10, "unclosed-math"),
+                (12, "unclosed-math")
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_footnotes_as_sidenotes() {
+        let long = "word ".repeat(61);
+        let source = format!(
+            "\
+# Doc
+
+Text.^[{{-}} A margin note.] More.^[Bad {{-}} marker.] Stray {{-}} here.
+
+## Unnumbered {{-}}
+
+[^1]: {{-}}No space.
+
+[^2]: First paragraph.
+
+    Second paragraph, which still belongs to the note.
+
+[^3]: {long}
+
+```
+{{-}} in code
+```
+"
+        );
+        assert_eq!(
+            rules(&source),
+            [
+                (3, "margin-marker"),
+                (3, "margin-marker"),
+                (7, "margin-marker"),
+                (9, "long-sidenote"),
+                (13, "long-sidenote"),
+                (13, "trailing-whitespace"),
+            ]
+        );
+    }
+
+    #[test]
+    fn flags_heading_skips_and_respects_severities() {
+        let source = "# One\n\n### Three\n\nTwo\n---\n\n#### Four  \n";
+        assert_eq!(
+            rules(source),
+            [
+                (3, "heading-skip"),
+                (8, "heading-skip"),
+                (8, "trailing-whitespace")
+            ]
+        );
+
+        let settings: LintSettings =
+            toml::from_str("heading-skip = \"error\"\ntrailing-whitespace = \"off\"\n").unwrap();
+        let found = lint(source, &Options::from_settings(&settings));
+        assert_eq!(found.len(), 2);
+        assert!(found.iter().all(|f| f.severity == Severity::Error));
+    }
+}
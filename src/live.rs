@@ -0,0 +1,144 @@
+use serde_json::{json, Value};
+
+/// Version of the JSON live reload protocol. Clients opt in by connecting to
+/// `/ws?protocol=<n>`; anything else gets the legacy bare `reload` text frames.
+pub const PROTOCOL_VERSION: u64 = 1;
+
+/// Server-to-client messages. Build notifications are fanned out to every
+/// connection through the broadcast channel; `Hello`, `Ping` and `Pong` are
+/// per-connection.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LiveMessage {
+    Hello,
+    BuildStarted,
+    Reload,
+    BuildError {
+        exit_code: i32,
+        stderr: String,
+    },
+    TitleChanged {
+        title: String,
+    },
+    ScrollTo {
+        id: String,
+    },
+    /// The page moved to `path`, relative to the base path; sent to tabs
+    /// showing the first document at `/` when `/` turns into an index.
+    Navigate {
+        path: String,
+    },
+    Ping,
+    Pong,
+}
+
+impl LiveMessage {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LiveMessage::Hello => "hello",
+            LiveMessage::BuildStarted => "build-started",
+            LiveMessage::Reload => "reload",
+            LiveMessage::BuildError { .. } => "build-error",
+            LiveMessage::TitleChanged { .. } => "title-changed",
+            LiveMessage::ScrollTo { .. } => "scroll-to",
+            LiveMessage::Navigate { .. } => "navigate",
+            LiveMessage::Ping => "ping",
+            LiveMessage::Pong => "pong",
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut msg = json!({ "v": PROTOCOL_VERSION, "type": self.kind() });
+        match self {
+            LiveMessage::Hello => {
+                msg["server"] = "mdr".into();
+                msg["version"] = env!("CARGO_PKG_VERSION").into();
+            }
+            LiveMessage::BuildError { exit_code, stderr } => {
+                msg["exit_code"] = (*exit_code).into();
+                msg["stderr"] = stderr.as_str().into();
+            }
+            LiveMessage::TitleChanged { title } => msg["title"] = title.as_str().into(),
+            LiveMessage::ScrollTo { id } => msg["id"] = id.as_str().into(),
+            LiveMessage::Navigate { path } => msg["path"] = path.as_str().into(),
+            LiveMessage::BuildStarted
+            | LiveMessage::Reload
+            | LiveMessage::Ping
+            | LiveMessage::Pong => {}
+        }
+        msg.to_string()
+    }
+}
+
+/// Client-to-server messages.
+#[derive(Debug, PartialEq)]
+pub enum ClientMessage {
+    Hello { version: u64 },
+    Ping,
+    Pong,
+    ScrollTo { id: String },
+    Unknown(String),
+}
+
+impl ClientMessage {
+    pub fn parse(text: &str) -> ClientMessage {
+        let Ok(value) = serde_json::from_str::<Value>(text) else {
+            return ClientMessage::Unknown(text.to_string());
+        };
+
+        match value.get("type").and_then(Value::as_str) {
+            Some("hello") => ClientMessage::Hello {
+                version: value.get("v").and_then(Value::as_u64).unwrap_or(0),
+            },
+            Some("ping") => ClientMessage::Ping,
+            Some("pong") => ClientMessage::Pong,
+            Some("scroll-to") => match value.get("id").and_then(Value::as_str) {
+                Some(id) if !id.is_empty() => ClientMessage::ScrollTo { id: id.to_string() },
+                _ => ClientMessage::Unknown(text.to_string()),
+            },
+            _ => ClientMessage::Unknown(text.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_serialize_with_version_and_type() {
+        let msg = LiveMessage::BuildError {
+            exit_code: 64,
+            stderr: "bad \"yaml\"".into(),
+        };
+        let value: Value = serde_json::from_str(&msg.to_json()).expect("valid json");
+
+        assert_eq!(value["v"], PROTOCOL_VERSION);
+        assert_eq!(value["type"], "build-error");
+        assert_eq!(value["exit_code"], 64);
+        assert_eq!(value["stderr"], "bad \"yaml\"");
+    }
+
+    #[test]
+    fn client_messages_parse_known_types() {
+        assert_eq!(
+            ClientMessage::parse(r#"{"v":1,"type":"hello"}"#),
+            ClientMessage::Hello { version: 1 }
+        );
+        assert_eq!(
+            ClientMessage::parse(r#"{"type":"ping"}"#),
+            ClientMessage::Ping
+        );
+        assert_eq!(
+            ClientMessage::parse(r#"{"type":"scroll-to","id":"intro"}"#),
+            ClientMessage::ScrollTo { id: "intro".into() }
+        );
+        assert!(matches!(
+            ClientMessage::parse("reload"),
+            ClientMessage::Unknown(_)
+        ));
+        assert!(matches!(
+            ClientMessage::parse(r#"{"type":"scroll-to"}"#),
+            ClientMessage::Unknown(_)
+        ));
+    }
+}
@@ -0,0 +1,216 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{Map, Value};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+impl Format {
+    pub fn parse(value: &str) -> Option<Format> {
+        match value {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+// Defaults apply until `init` runs, so argument errors still print as text.
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Configure the process-wide logger. `verbosity` is 0 for `-q`, 1 by default,
+/// and one more per `-v`.
+pub fn init(verbosity: u8, format: Format) {
+    let max_level = match verbosity {
+        0 => Level::Error,
+        1 => Level::Info,
+        2 => Level::Debug,
+        _ => Level::Trace,
+    };
+    MAX_LEVEL.store(max_level as u8, Ordering::Relaxed);
+    JSON.store(format == Format::Json, Ordering::Relaxed);
+}
+
+fn max_level() -> Level {
+    match MAX_LEVEL.load(Ordering::Relaxed) {
+        0 => Level::Error,
+        1 => Level::Warn,
+        2 => Level::Info,
+        3 => Level::Debug,
+        _ => Level::Trace,
+    }
+}
+
+pub fn enabled(level: Level) -> bool {
+    level <= max_level()
+}
+
+pub fn is_text() -> bool {
+    !JSON.load(Ordering::Relaxed)
+}
+
+/// Emit a structured event. In text mode only `message` is printed; in JSON
+/// mode `fields` are merged into the emitted object alongside it.
+pub fn event(level: Level, name: &str, fields: Value, message: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+
+    let line = if JSON.load(Ordering::Relaxed) {
+        format_json(level, name, fields, message)
+    } else {
+        format_text(level, max_level(), message)
+    };
+
+    let mut stderr = io::stderr().lock();
+    let _ = writeln!(stderr, "{line}");
+}
+
+fn format_text(level: Level, max_level: Level, message: fmt::Arguments) -> String {
+    let prefix = match level {
+        Level::Warn => "warning: ",
+        Level::Debug | Level::Trace => "debug: ",
+        Level::Error | Level::Info => "",
+    };
+
+    // Timestamps only help when there is enough output to correlate.
+    if max_level >= Level::Debug {
+        format!("{} mdr: {prefix}{message}", timestamp(SystemTime::now()))
+    } else {
+        format!("mdr: {prefix}{message}")
+    }
+}
+
+fn format_json(level: Level, name: &str, fields: Value, message: fmt::Arguments) -> String {
+    let mut obj = Map::new();
+    obj.insert("ts".into(), timestamp(SystemTime::now()).into());
+    obj.insert("level".into(), level.as_str().into());
+    obj.insert("event".into(), name.into());
+    obj.insert("message".into(), message.to_string().into());
+    if let Value::Object(extra) = fields {
+        for (key, value) in extra {
+            obj.entry(key).or_insert(value);
+        }
+    }
+    Value::Object(obj).to_string()
+}
+
+/// RFC 3339 UTC timestamp with millisecond precision.
+pub fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let millis = since_epoch.subsec_millis();
+
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{millis:03}Z",
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+// Howard Hinnant's days-to-civil conversion for the proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+macro_rules! log_at {
+    ($level:expr, $($arg:tt)*) => {
+        $crate::logging::event(
+            $level,
+            "log",
+            serde_json::Value::Null,
+            format_args!($($arg)*),
+        )
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => { log_at!($crate::logging::Level::Error, $($arg)*) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => { log_at!($crate::logging::Level::Warn, $($arg)*) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { log_at!($crate::logging::Level::Info, $($arg)*) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { log_at!($crate::logging::Level::Debug, $($arg)*) };
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => { log_at!($crate::logging::Level::Trace, $($arg)*) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn timestamp_formats_utc_rfc3339() {
+        let t = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        assert_eq!(timestamp(t), "2023-11-14T22:13:20.123Z");
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    }
+
+    #[test]
+    fn json_lines_merge_fields() {
+        let line = format_json(
+            Level::Info,
+            "build_finished",
+            serde_json::json!({ "duration_ms": 42, "level": "ignored" }),
+            format_args!("built in {} ms", 42),
+        );
+        let value: Value = serde_json::from_str(&line).expect("valid json");
+
+        assert_eq!(value["event"], "build_finished");
+        assert_eq!(value["level"], "info");
+        assert_eq!(value["message"], "built in 42 ms");
+        assert_eq!(value["duration_ms"], 42);
+    }
+}
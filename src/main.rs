@@ -1,18 +1,47 @@
+#[macro_use]
+mod logging;
+mod anchors;
+mod auth;
+mod browser;
+mod check;
+mod connection;
+#[cfg(unix)]
+mod control;
+mod diagrams;
+mod forwarded;
+mod front_matter;
+mod http_cache;
+mod lan;
+mod lint;
+mod live;
+mod pandoc_server;
+mod settings;
+mod tls;
+#[cfg(unix)]
+mod unix_socket;
+
+use std::collections::HashMap;
+use std::convert::Infallible;
 use std::env;
 use std::fs::{self, File};
 use std::io::IsTerminal;
 use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::path::{Path, PathBuf};
 use std::process::{self, Command};
-use std::sync::Arc;
-use std::time::{Duration, Instant};
-
-use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
-use axum::extract::State;
-use axum::http::{header, StatusCode};
-use axum::response::IntoResponse;
-use axum::routing::get;
-use axum::Router;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{FromRequestParts, Query, RawPathParams, State};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::routing::{get, post};
+use axum::{middleware, Json, Router};
+use futures_util::stream::{self, StreamExt};
 use notify::event::ModifyKind;
 use notify::{
     recommended_watcher, Config as NotifyConfig, EventKind, RecommendedWatcher, RecursiveMode,
@@ -20,12 +49,21 @@ use notify::{
 };
 use tokio::net::TcpListener;
 use tokio::select;
-use tokio::sync::{broadcast, mpsc, RwLock};
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Mutex, RwLock};
+
+use front_matter::{FrontMatter, MathEngine, Metadata};
+use live::{ClientMessage, LiveMessage};
+use logging::Level;
+use pandoc_server::PandocServer;
+use serde_json::json;
+use settings::{DocumentSettings, Settings, SlugStyle, TitleMode, TocPlacement};
+use tokio_rustls::rustls::ServerConfig;
 
 const TEMPLATE_HTML: &str = include_str!("../assets/template.html5");
 const THEME_CSS: &str = include_str!("../assets/css/theme.css");
 const SKYLIGHTING_CSS: &str = include_str!("../assets/css/skylighting-solarized-theme.css");
 const SIDENOTE_LUA: &str = include_str!("../assets/pandoc-sidenote.lua");
+const LIVE_JS: &str = include_str!("../assets/live.js");
 const DEFAULT_KATEX: &str = "https://cdn.jsdelivr.net/npm/katex@0.15.1/dist/";
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -37,8 +75,59 @@ struct Assets {
     skylighting_path: PathBuf,
 }
 
+// How Markdown documents are rendered, resolved from flags and config files.
+#[derive(Clone, Debug, Default)]
+struct RenderOptions {
+    title_from: TitleMode,
+    toc: Option<bool>,
+    toc_depth: Option<u8>,
+    toc_placement: TocPlacement,
+    number_sections: Option<bool>,
+    slugs: SlugStyle,
+    diagrams: bool,
+}
+
+struct Toc {
+    enabled: bool,
+    depth: Option<u8>,
+    numbered: bool,
+}
+
+impl RenderOptions {
+    fn from_settings(document: &DocumentSettings) -> Self {
+        RenderOptions {
+            title_from: document.title_from.unwrap_or_default(),
+            toc: document.toc,
+            toc_depth: document.toc_depth,
+            toc_placement: document.toc_placement.unwrap_or_default(),
+            number_sections: document.number_sections,
+            slugs: document.slugs.unwrap_or_default(),
+            diagrams: document.diagrams.unwrap_or(false),
+        }
+    }
+
+    // A document's own front matter is the most specific, so it wins.
+    fn toc(&self, metadata: &Metadata) -> Toc {
+        let doc = &metadata.pandoc;
+        Toc {
+            enabled: doc.toc.or(self.toc).unwrap_or(true),
+            depth: doc.toc_depth.or(self.toc_depth),
+            numbered: doc
+                .number_sections
+                .or(self.number_sections)
+                .unwrap_or(false),
+        }
+    }
+
+    fn pandoc_from(&self) -> &'static str {
+        match self.slugs {
+            SlugStyle::Pandoc => "markdown+tex_math_single_backslash",
+            SlugStyle::Github => "markdown+tex_math_single_backslash+gfm_auto_identifiers",
+        }
+    }
+}
+
 struct Config {
-    bin: String,
     watch: bool,
     serve: bool,
     port: u16,
@@ -48,9 +137,56 @@ struct Config {
     input_path: PathBuf,
     output_path: PathBuf,
     write_output: bool,
+    pandoc_server: bool,
+    pandoc_server_url: Option<String>,
+    verbosity: u8,
+    log_format: logging::Format,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    tls_self_signed: bool,
+    access_token: bool,
+    basic_auth: Option<(String, String)>,
+    open: Option<bool>,
+    socket: Option<PathBuf>,
+    base_path: String,
+    trust_proxy: bool,
+    title_from: Option<TitleMode>,
+    toc: Option<bool>,
+    toc_depth: Option<u8>,
+    toc_placement: Option<TocPlacement>,
+    number_sections: Option<bool>,
+    slugs: Option<SlugStyle>,
+    diagrams: Option<bool>,
+    check_links: Option<bool>,
+}
+
+// The in-memory preview together with its validators, replaced as one so a
+// response never pairs one build's ETag with another build's body.
+#[derive(Default)]
+struct Page {
+    html: Arc<str>,
+    // Hash of `html` and when it last changed; a rebuild that produces
+    // identical output keeps both, so browsers get 304s.
+    hash: Option<u64>,
+    modified: Option<SystemTime>,
+    // Whether the page lacks a `live.js` tag and the server must append one;
+    // decided once per build rather than on every request.
+    inject_live_script: bool,
+}
+
+impl Page {
+    fn replace(&mut self, html: String) {
+        let hash = Some(http_cache::content_hash(html.as_str()));
+        if hash != self.hash {
+            self.hash = hash;
+            self.modified = Some(SystemTime::now());
+        }
+        self.inject_live_script = !html.contains("/live.js");
+        self.html = html.into();
+    }
 }
 
-type SharedHtml = Arc<RwLock<String>>;
+type SharedHtml = Arc<RwLock<Page>>;
 
 #[derive(Clone)]
 enum BuildTarget {
@@ -58,87 +194,374 @@ enum BuildTarget {
     Memory(SharedHtml),
 }
 
+// Outcome of the most recent build, reported by `/api/status`.
+#[derive(Default)]
+struct BuildStatus {
+    finished_at: Option<SystemTime>,
+    duration_ms: u64,
+    success: bool,
+    exit_code: Option<i32>,
+    stderr: String,
+    builds: u64,
+    // Broken links in the last successful build, with `--check-links`.
+    link_problems: Vec<String>,
+}
+
+type SharedStatus = Arc<RwLock<BuildStatus>>;
+
+// Everything needed to rebuild the document; cloned into the watcher task.
+#[derive(Clone)]
+struct BuildJob {
+    input_path: PathBuf,
+    assets: Option<Assets>,
+    target: BuildTarget,
+    backend: PandocBackend,
+    render: RenderOptions,
+    check_links: bool,
+    status: SharedStatus,
+}
+
+// How in-memory previews invoke pandoc. Exports always spawn the pandoc CLI so
+// `--embed-resources` can inline everything.
+#[derive(Clone)]
+enum PandocBackend {
+    Process,
+    Server(Arc<PandocServer>),
+}
+
+// One file served by the preview server, at `/d/<slug>/`, with its own build
+// status and live reload channel.
+#[derive(Clone)]
+struct Document {
+    slug: String,
+    input_path: PathBuf,
+    html: SharedHtml,
+    status: SharedStatus,
+    live_tx: broadcast::Sender<LiveMessage>,
+    rebuild_tx: mpsc::Sender<oneshot::Sender<()>>,
+    // The task watching the file, aborted when the document is closed.
+    watcher: Arc<OnceLock<tokio::task::AbortHandle>>,
+    // Live reload connections to this document, for `/api/status`.
+    clients: Arc<AtomicUsize>,
+}
+
+impl Document {
+    // Route of the document below the base path.
+    fn path(&self) -> String {
+        format!("/d/{}/", self.slug)
+    }
+}
+
+// Documents served by one preview server, in the order they were opened. The
+// first is the one named on the command line; it also answers at `/` until a
+// second one arrives and `/` becomes an index.
+#[derive(Clone)]
+struct Documents {
+    list: Arc<RwLock<Vec<Document>>>,
+    assets: Assets,
+    backend: PandocBackend,
+    render: RenderOptions,
+    check_links: bool,
+    // Serializes `open` so one file is never registered twice.
+    opening: Arc<Mutex<()>>,
+}
+
+impl Documents {
+    fn new(
+        assets: Assets,
+        backend: PandocBackend,
+        render: RenderOptions,
+        check_links: bool,
+    ) -> Self {
+        Documents {
+            list: Arc::default(),
+            assets,
+            backend,
+            render,
+            check_links,
+            opening: Arc::default(),
+        }
+    }
+
+    async fn primary(&self) -> Document {
+        self.list.read().await[0].clone()
+    }
+
+    async fn get(&self, slug: &str) -> Option<Document> {
+        let list = self.list.read().await;
+        list.iter().find(|doc| doc.slug == slug).cloned()
+    }
+
+    async fn all(&self) -> Vec<Document> {
+        self.list.read().await.clone()
+    }
+
+    // A document for `input_path` with a slug no other document uses, plus the
+    // job that builds it and the receiver its watcher takes forced rebuilds from.
+    async fn prepare(
+        &self,
+        input_path: &Path,
+    ) -> (Document, BuildJob, mpsc::Receiver<oneshot::Sender<()>>) {
+        let taken: Vec<String> = self
+            .list
+            .read()
+            .await
+            .iter()
+            .map(|doc| doc.slug.clone())
+            .collect();
+        let html = SharedHtml::default();
+        let status = SharedStatus::default();
+        let (live_tx, _) = broadcast::channel(32);
+        let (rebuild_tx, rebuild_rx) = mpsc::channel(8);
+
+        let document = Document {
+            slug: unique_slug(input_path, &taken),
+            input_path: input_path.to_path_buf(),
+            html: html.clone(),
+            status: status.clone(),
+            live_tx,
+            rebuild_tx,
+            watcher: Arc::default(),
+            clients: Arc::default(),
+        };
+        let job = BuildJob {
+            input_path: input_path.to_path_buf(),
+            assets: (!is_html_input(input_path)).then(|| self.assets.clone()),
+            target: BuildTarget::Memory(html),
+            backend: self.backend.clone(),
+            render: self.render.clone(),
+            check_links: self.check_links,
+            status,
+        };
+        (document, job, rebuild_rx)
+    }
+
+    // Serve `input_path` as well, or find the document already serving it.
+    async fn open(&self, input_path: &Path) -> Result<Document, String> {
+        let canonical = input_path
+            .canonicalize()
+            .map_err(|err| format!("{}: {err}", input_path.display()))?;
+        let _opening = self.opening.lock().await;
+
+        for doc in self.all().await {
+            if doc.input_path.canonicalize().ok().as_ref() == Some(&canonical) {
+                return Ok(doc);
+            }
+        }
+
+        if !is_html_input(&canonical) && ensure_pandoc().is_err() {
+            return Err("pandoc not found on the server's PATH".into());
+        }
+
+        let (document, job, rebuild_rx) = self.prepare(&canonical).await;
+        job.run()
+            .await
+            .map_err(|code| format!("build of {} failed (exit {code})", canonical.display()))?;
+
+        let live_tx = document.live_tx.clone();
+        let watcher = tokio::spawn(async move {
+            let input = job.input_path.clone();
+            if let Err(code) = watch_and_rebuild(job, Some(live_tx), rebuild_rx).await {
+                warn!("stopped watching {} (exit {code})", input.display());
+            }
+        });
+        let _ = document.watcher.set(watcher.abort_handle());
+
+        let count = {
+            let mut list = self.list.write().await;
+            list.push(document.clone());
+            list.len()
+        };
+        logging::event(
+            Level::Info,
+            "document_opened",
+            json!({ "input": canonical.display().to_string(), "path": document.path() }),
+            format_args!("serving {} at {}", canonical.display(), document.path()),
+        );
+
+        // `/` is about to become the index; move tabs showing the first
+        // document there to its own route.
+        if count == 2 {
+            let primary = self.primary().await;
+            let path = primary.path().trim_start_matches('/').to_string();
+            let _ = primary.live_tx.send(LiveMessage::Navigate { path });
+        }
+
+        Ok(document)
+    }
+
+    // Stop serving the document for `canonical`. `Ok(false)` means it is the
+    // last one, which the caller answers by stopping the server instead.
+    async fn close(&self, canonical: &Path) -> Result<bool, String> {
+        let _opening = self.opening.lock().await;
+        let mut list = self.list.write().await;
+        let index = list
+            .iter()
+            .position(|doc| doc.input_path.canonicalize().ok().as_deref() == Some(canonical))
+            .ok_or("no such document on this server")?;
+        if list.len() == 1 {
+            return Ok(false);
+        }
+        let document = list.remove(index);
+        drop(list);
+
+        if let Some(watcher) = document.watcher.get() {
+            watcher.abort();
+        }
+        // Send its tabs from `/d/<slug>/` up to `/`, which now lists what is
+        // left (or shows the one remaining document).
+        let _ = document.live_tx.send(LiveMessage::Navigate {
+            path: "../../".into(),
+        });
+        logging::event(
+            Level::Info,
+            "document_closed",
+            json!({ "input": canonical.display().to_string(), "path": document.path() }),
+            format_args!("stopped serving {}", canonical.display()),
+        );
+        Ok(true)
+    }
+}
+
+// URL-safe name for a document's route, from its file name.
+fn unique_slug(input_path: &Path, taken: &[String]) -> String {
+    let stem = input_path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let mut base = String::new();
+    for c in stem.chars() {
+        if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+            base.push(c);
+        } else if !base.ends_with('-') {
+            base.push('-');
+        }
+    }
+    let base = base.trim_matches(|c| c == '-' || c == '.');
+    let base = if base.is_empty() { "doc" } else { base };
+
+    let mut slug = base.to_string();
+    let mut n = 2;
+    while taken.contains(&slug) {
+        slug = format!("{base}-{n}");
+        n += 1;
+    }
+    slug
+}
+
 #[tokio::main]
 async fn main() {
-    if let Err(code) = run().await {
+    let result = run().await;
+    // Tasks still holding the pandoc server are never dropped before exit.
+    pandoc_server::kill_spawned();
+    if let Err(code) = result {
         process::exit(code);
     }
 }
 
 async fn run() -> Result<(), i32> {
+    let args: Vec<String> = env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("open") => return run_open(&args[2..]),
+        Some("ctl") => return run_ctl(&args[2..]),
+        Some("check") => return run_check(&args[2..]),
+        Some("lint") => return run_lint(&args[2..]),
+        Some("render-diagram") => return run_render_diagram(&args[2..]),
+        _ => {}
+    }
+
     let config = parse_args()?;
+    logging::init(config.verbosity, config.log_format);
+    let settings = Settings::load(&config.input_path).map_err(|err| {
+        error!("invalid config: {err}");
+        78
+    })?;
+
     let is_html = is_html_input(&config.input_path);
+    let defaults = RenderOptions::from_settings(&settings.document);
+    let render = RenderOptions {
+        title_from: config.title_from.unwrap_or(defaults.title_from),
+        toc: config.toc.or(defaults.toc),
+        toc_depth: config.toc_depth.or(defaults.toc_depth),
+        toc_placement: config.toc_placement.unwrap_or(defaults.toc_placement),
+        number_sections: config.number_sections.or(defaults.number_sections),
+        slugs: config.slugs.unwrap_or(defaults.slugs),
+        diagrams: config.diagrams.unwrap_or(defaults.diagrams),
+    };
 
     if !is_html {
-        ensure_pandoc(&config.bin);
+        ensure_pandoc()?;
     }
 
     if config.no_clobber && config.write_output {
-        confirm_overwrite(&config.output_path, &config.bin);
+        confirm_overwrite(&config.output_path)?;
     }
 
-    let (temp, assets): (Option<PathBuf>, Option<Assets>) = if is_html {
+    // Load certificates before the first build so a bad path fails fast.
+    let tls = load_tls(&config)?;
+
+    // Held until `run` returns so the directory is removed on every exit path.
+    // The server may be asked to open Markdown later, so it always needs them.
+    let (_temp, assets): (Option<TempRoot>, Option<Assets>) = if is_html && !config.serve {
         (None, None)
     } else {
-        let t = match temp_root() {
+        sweep_stale_temp_dirs(&env::temp_dir());
+        let t = match TempRoot::create() {
             Ok(dir) => dir,
             Err(err) => {
-                eprintln!("mdr: failed to create temp dir: {err}");
+                error!("failed to create temp dir: {err}");
                 return Err(1);
             }
         };
-        let a = match materialize_assets(&t) {
-            Ok(a) => a,
-            Err(code) => {
-                cleanup(&t);
-                return Err(code);
-            }
-        };
+        let a = materialize_assets(t.path())?;
         (Some(t), Some(a))
     };
 
-    let result = if config.serve {
-        let html = Arc::new(RwLock::new(String::new()));
+    if config.pandoc_server && !config.serve {
+        warn!("--pandoc-server only applies to the live preview; exports spawn pandoc");
+    }
+
+    let backend = if config.pandoc_server && config.serve && !is_html {
+        start_pandoc_server(&config)
+    } else {
+        PandocBackend::Process
+    };
+
+    if config.serve {
+        let assets = assets.expect("serve mode materializes assets");
+        let check_links = config
+            .check_links
+            .or(settings.serve.check_links)
+            .unwrap_or(false);
+        let documents = Documents::new(assets, backend, render, check_links);
+        let (document, job, rebuild_rx) = documents.prepare(&config.input_path).await;
 
-        if let Err(code) = build_to_target(
-            &config.input_path,
-            assets.as_ref(),
-            &BuildTarget::Memory(html.clone()),
-        )
-        .await
-        {
-            if let Some(ref t) = temp {
-                cleanup(t);
-            }
-            return Err(code);
-        }
+        job.run().await?;
+        documents.list.write().await.push(document);
 
-        run_serve_mode(&config, assets, html).await
+        run_serve_mode(&config, &settings, documents, job, rebuild_rx, tls).await
     } else {
-        if let Err(code) = build_to_target(
-            &config.input_path,
-            assets.as_ref(),
-            &BuildTarget::File(config.output_path.clone()),
-        )
-        .await
-        {
-            if let Some(ref t) = temp {
-                cleanup(t);
-            }
-            return Err(code);
+        let job = BuildJob {
+            input_path: config.input_path.clone(),
+            assets,
+            target: BuildTarget::File(config.output_path.clone()),
+            backend,
+            render,
+            check_links: false,
+            status: SharedStatus::default(),
+        };
+
+        if !is_html && front_matter::read(&config.input_path).metadata.draft {
+            warn!("{} is marked as a draft", config.input_path.display());
         }
+        job.run().await?;
 
         if config.watch {
-            run_watch_mode(&config, assets).await
+            run_watch_mode(&config, job).await
         } else {
             Ok(())
         }
-    };
-
-    if let Some(ref t) = temp {
-        cleanup(t);
     }
-    result
 }
 
 fn usage(bin: &str) {
@@ -155,6 +578,14 @@ fn usage(bin: &str) {
 
 {b}USAGE{r}
   {b}{bin}{r} [options] <input.md | input.html>
+  {b}{bin} open{r} <file>         Serve another file from the running preview and print its URL.
+  {b}{bin} ctl{r} list | url <file> | rebuild [<file>] | stop [<file>]
+                          Talk to running previews, found by the files they serve.
+  {b}{bin} check{r} [--external | --offline] <file>...
+                          Report broken anchors, relative links, and images (and URLs with --external).
+  {b}{bin} lint{r} [--strict] <file>...
+                          Report unclosed math, oversized sidenotes, misplaced {{-}} markers, heading
+                          skips, and trailing whitespace; fails on errors (and warnings with --strict).
 
 {b}HOW IT BEHAVES{r}
   - {b}No -o/--output{r}: serves from memory and auto-rebuilds at http://127.0.0.1:8080 (watch + server on); if 8080 is busy, it tries 8081, 8082, ...; no HTML file is written.
@@ -167,10 +598,31 @@ fn usage(bin: &str) {
   {c}-P{r}, {c}--public{r}          Bind to 0.0.0.0 so other devices can view the preview.
   {c}--port{r} <port>         HTTP port for the preview server ({d}default 8080{r}; explicit value disables auto-increment fallback).
   {c}--host{r} <host>         Host/interface to bind ({d}default 127.0.0.1{r}).
+  {c}--socket{r} <path>       Listen on a Unix domain socket instead of TCP (removed on exit).
+  {c}--base-path{r} <path>    Serve every route under this URL prefix (e.g. {c}/docs{r}).
+  {c}--trust-proxy{r}         Honor X-Forwarded-Prefix/Proto without {c}--base-path{r}.
+  {c}--tls-cert{r} <file>     Serve the preview over HTTPS with this PEM certificate chain.
+  {c}--tls-key{r} <file>      PEM private key for {c}--tls-cert{r}.
+  {c}--tls-self-signed{r}     Serve over HTTPS with a generated self-signed certificate.
+  {c}--token{r}               Require a generated access token (printed in the preview URL).
+  {c}--basic-auth{r} <u:p>    Require HTTP basic auth ({d}or set MDR_BASIC_AUTH{r}).
+  {c}--check-links{r}         Check links after every build and list broken ones over the preview.
+  {c}--open{r}, {c}--no-open{r}     Open the preview in a browser ({d}default from [serve] open in config{r}).
   {c}-o{r}, {c}--output{r} [<file>] Output HTML path; omit <file> to keep the default name.
   {c}-n{r}, {c}--no-clobber{r}      Ask before overwriting an existing output file.
+  {c}--toc{r}, {c}--no-toc{r}         Render a table of contents ({d}default on{r}).
+  {c}--toc-depth{r} <n>       Deepest heading level in the table of contents (1-6).
+  {c}--toc-placement{r} <p>   {c}sidebar{r} ({d}default, sticky with scroll-spy{r}), {c}top{r}, or {c}collapsible{r}.
+  {c}--number-sections{r}     Number headings and their table of contents entries.
+  {c}--slugs{r} <style>       Heading IDs as {c}pandoc{r} ({d}default{r}) or {c}github{r} generates them.
+  {c}--diagrams{r}, {c}--no-diagrams{r}  Render mermaid, dot, and plantuml blocks with local tools ({d}default off{r}).
+  {c}--title-from{r} <src>    Title when front matter has none: {c}heading{r} (first {c}#{r} heading), {c}filename{r} ({d}default{r}), or {c}none{r}.
+  {c}--pandoc-server{r} [<url>]  Render previews through a long-running `pandoc server` ({d}spawned if <url> is omitted{r}).
+  {c}-q{r}, {c}--quiet{r}           Only print errors.
+  {c}-v{r}, {c}--verbose{r}         More detail and timestamps; repeat ({c}-vv{r}) for trace output.
+  {c}--log-format{r} <fmt>    Diagnostics as {c}text{r} ({d}default{r}) or {c}json{r} (one event per line).
   {c}-h{r}, {c}--help{r}            Show this message.
-  {c}-v{r}, {c}--version{r}         Show version and exit.
+  {c}-V{r}, {c}--version{r}         Show version and exit.
 
 {b}EXAMPLES{r}
   {bin} README.md
@@ -200,6 +652,27 @@ fn parse_args() -> Result<Config, i32> {
     let mut no_clobber = false;
     let mut output: Option<PathBuf> = None;
     let mut output_flag = false;
+    let mut pandoc_server = false;
+    let mut pandoc_server_url: Option<String> = None;
+    let mut verbosity: u8 = 1;
+    let mut log_format = logging::Format::Text;
+    let mut tls_cert: Option<PathBuf> = None;
+    let mut tls_key: Option<PathBuf> = None;
+    let mut tls_self_signed = false;
+    let mut access_token = false;
+    let mut open: Option<bool> = None;
+    let mut socket: Option<PathBuf> = None;
+    let mut base_path = String::new();
+    let mut trust_proxy = false;
+    let mut title_from: Option<TitleMode> = None;
+    let mut toc: Option<bool> = None;
+    let mut toc_depth: Option<u8> = None;
+    let mut toc_placement: Option<TocPlacement> = None;
+    let mut number_sections: Option<bool> = None;
+    let mut slugs: Option<SlugStyle> = None;
+    let mut check_links: Option<bool> = None;
+    let mut diagrams: Option<bool> = None;
+    let mut basic_auth: Option<String> = None;
     let mut positional: Vec<String> = Vec::new();
 
     while let Some(arg) = args.next() {
@@ -208,35 +681,158 @@ fn parse_args() -> Result<Config, i32> {
             "-P" | "--public" => {
                 host = "0.0.0.0".into();
             }
-            "-v" | "--version" => {
+            "-q" | "--quiet" => verbosity = 0,
+            "--verbose" => verbosity = verbosity.saturating_add(1),
+            // `-v`, `-vv`, `-vvv`, ...: one level per `v`.
+            flag if flag.starts_with("-v") && flag[1..].bytes().all(|b| b == b'v') => {
+                let count = u8::try_from(flag.len() - 1).unwrap_or(u8::MAX);
+                verbosity = verbosity.saturating_add(count);
+            }
+            "--log-format" => {
+                let Some(val) = args.next() else {
+                    error!("--log-format requires a value");
+                    return Err(64);
+                };
+                log_format = match logging::Format::parse(&val) {
+                    Some(f) => f,
+                    None => {
+                        error!("invalid log format: {val} (expected text or json)");
+                        return Err(64);
+                    }
+                };
+            }
+            "-V" | "--version" => {
                 eprintln!("{bin} {VERSION}");
-                process::exit(0);
+                return Err(0);
             }
             "-h" | "--help" => {
                 usage(&bin);
-                process::exit(0);
+                return Err(0);
             }
             "--port" | "-p" => {
                 let Some(val) = args.next() else {
-                    eprintln!("{bin}: --port requires a value");
+                    error!("--port requires a value");
                     return Err(64);
                 };
                 port_explicit = true;
                 port = match val.parse::<u16>() {
                     Ok(p) => p,
                     Err(_) => {
-                        eprintln!("{bin}: invalid port: {val}");
+                        error!("invalid port: {val}");
                         return Err(64);
                     }
                 };
             }
             "--host" => {
                 let Some(val) = args.next() else {
-                    eprintln!("{bin}: --host requires a value");
+                    error!("--host requires a value");
                     return Err(64);
                 };
                 host = val;
             }
+            "--tls-cert" | "--tls-key" => {
+                let Some(val) = args.next() else {
+                    error!("{arg} requires a value");
+                    return Err(64);
+                };
+                if arg == "--tls-cert" {
+                    tls_cert = Some(PathBuf::from(val));
+                } else {
+                    tls_key = Some(PathBuf::from(val));
+                }
+            }
+            "--tls-self-signed" => tls_self_signed = true,
+            "--token" => access_token = true,
+            "--socket" => {
+                let Some(val) = args.next() else {
+                    error!("--socket requires a value");
+                    return Err(64);
+                };
+                socket = Some(PathBuf::from(val));
+            }
+            "--base-path" => {
+                let Some(val) = args.next() else {
+                    error!("--base-path requires a value");
+                    return Err(64);
+                };
+                base_path = match forwarded::normalize_prefix(&val) {
+                    Some(path) => path,
+                    None => {
+                        error!("invalid base path: {val}");
+                        return Err(64);
+                    }
+                };
+            }
+            "--trust-proxy" => trust_proxy = true,
+            "--title-from" => {
+                let Some(val) = args.next() else {
+                    error!("--title-from requires a value");
+                    return Err(64);
+                };
+                title_from = match TitleMode::parse(&val) {
+                    Some(mode) => Some(mode),
+                    None => {
+                        error!("invalid title source: {val} (expected heading, filename, or none)");
+                        return Err(64);
+                    }
+                };
+            }
+            "--toc" => toc = Some(true),
+            "--no-toc" => toc = Some(false),
+            "--toc-depth" => {
+                let Some(val) = args.next() else {
+                    error!("--toc-depth requires a value");
+                    return Err(64);
+                };
+                toc_depth = match val.parse::<u8>() {
+                    Ok(depth @ 1..=6) => Some(depth),
+                    _ => {
+                        error!("invalid TOC depth: {val} (expected 1-6)");
+                        return Err(64);
+                    }
+                };
+            }
+            "--toc-placement" => {
+                let Some(val) = args.next() else {
+                    error!("--toc-placement requires a value");
+                    return Err(64);
+                };
+                toc_placement = match TocPlacement::parse(&val) {
+                    Some(placement) => Some(placement),
+                    None => {
+                        error!(
+                            "invalid TOC placement: {val} (expected sidebar, top, or collapsible)"
+                        );
+                        return Err(64);
+                    }
+                };
+            }
+            "--number-sections" => number_sections = Some(true),
+            "--slugs" => {
+                let Some(val) = args.next() else {
+                    error!("--slugs requires a value");
+                    return Err(64);
+                };
+                slugs = match SlugStyle::parse(&val) {
+                    Some(style) => Some(style),
+                    None => {
+                        error!("invalid slug style: {val} (expected pandoc or github)");
+                        return Err(64);
+                    }
+                };
+            }
+            "--check-links" => check_links = Some(true),
+            "--diagrams" => diagrams = Some(true),
+            "--no-diagrams" => diagrams = Some(false),
+            "--open" => open = Some(true),
+            "--no-open" => open = Some(false),
+            "--basic-auth" => {
+                let Some(val) = args.next() else {
+                    error!("--basic-auth requires a value");
+                    return Err(64);
+                };
+                basic_auth = Some(val);
+            }
             "-n" | "--no-clobber" => no_clobber = true,
             "-o" | "--output" => {
                 output_flag = true;
@@ -247,8 +843,20 @@ fn parse_args() -> Result<Config, i32> {
                     }
                 }
             }
+            "--pandoc-server" => {
+                pandoc_server = true;
+                if let Some(next) = args.peek() {
+                    if pandoc_server::looks_like_url(next) {
+                        pandoc_server_url = args.next();
+                    }
+                }
+            }
+            _ if arg.starts_with("--pandoc-server=") => {
+                pandoc_server = true;
+                pandoc_server_url = Some(arg["--pandoc-server=".len()..].to_string());
+            }
             _ if arg.starts_with('-') => {
-                eprintln!("{bin}: unknown option: {arg}");
+                error!("unknown option: {arg}");
                 usage(&bin);
                 return Err(64);
             }
@@ -270,7 +878,7 @@ fn parse_args() -> Result<Config, i32> {
     let output_provided = output_flag;
 
     if output_path.as_os_str().is_empty() {
-        eprintln!("mdr: could not derive output path from input");
+        error!("could not derive output path from input");
         return Err(64);
     }
 
@@ -280,8 +888,72 @@ fn parse_args() -> Result<Config, i32> {
         watch = true;
     }
 
+    if tls_cert.is_some() != tls_key.is_some() {
+        error!("--tls-cert and --tls-key must be given together");
+        return Err(64);
+    }
+    if tls_self_signed && tls_cert.is_some() {
+        error!("--tls-self-signed cannot be combined with --tls-cert/--tls-key");
+        return Err(64);
+    }
+    if (tls_self_signed || tls_cert.is_some()) && !serve {
+        error!("TLS options only apply to the preview server; drop -o/--output");
+        return Err(64);
+    }
+
+    if basic_auth.is_some() && !serve {
+        error!("--basic-auth only applies to the preview server; drop -o/--output");
+        return Err(64);
+    }
+    // Exports ignore the environment default, so a stale value cannot break them.
+    let basic_auth =
+        basic_auth.or_else(|| serve.then(|| env::var("MDR_BASIC_AUTH").ok()).flatten());
+    let basic_auth = match basic_auth.filter(|v| !v.is_empty()) {
+        Some(val) => match auth::parse_basic(&val) {
+            Some(creds) => Some(creds),
+            None => {
+                error!("invalid basic auth credentials (expected user:password)");
+                return Err(64);
+            }
+        },
+        None => None,
+    };
+    if access_token && !serve {
+        error!("--token only applies to the preview server; drop -o/--output");
+        return Err(64);
+    }
+    if check_links == Some(true) && !serve {
+        error!("--check-links only applies to the preview server; use mdr check for exports");
+        return Err(64);
+    }
+    if open == Some(true) && !serve {
+        error!("--open only applies to the preview server; drop -o/--output");
+        return Err(64);
+    }
+    if socket.is_some() {
+        if cfg!(not(unix)) {
+            error!("--socket is only supported on Unix");
+            return Err(64);
+        }
+        if !serve {
+            error!("--socket only applies to the preview server; drop -o/--output");
+            return Err(64);
+        }
+        if port_explicit || host != "127.0.0.1" {
+            error!("--socket replaces --host, --port, and --public");
+            return Err(64);
+        }
+        if tls_self_signed || tls_cert.is_some() {
+            error!("--socket serves plain HTTP; terminate TLS at the proxy instead");
+            return Err(64);
+        }
+        if open == Some(true) {
+            error!("--open cannot be combined with --socket");
+            return Err(64);
+        }
+    }
+
     Ok(Config {
-        bin,
         watch,
         serve,
         port,
@@ -291,75 +963,147 @@ fn parse_args() -> Result<Config, i32> {
         input_path,
         output_path,
         write_output: output_provided,
+        pandoc_server,
+        pandoc_server_url,
+        verbosity,
+        log_format,
+        tls_cert,
+        tls_key,
+        tls_self_signed,
+        access_token,
+        basic_auth,
+        open,
+        socket,
+        base_path,
+        trust_proxy,
+        title_from,
+        toc,
+        toc_depth,
+        toc_placement,
+        number_sections,
+        slugs,
+        diagrams,
+        check_links,
     })
 }
 
-fn temp_root() -> io::Result<PathBuf> {
-    let mut dir = env::temp_dir();
-    dir.push(format!("mdr-{}", process::id()));
-    fs::create_dir_all(&dir)?;
-    Ok(dir)
+// Per-process scratch directory for materialized assets; removed on drop.
+struct TempRoot {
+    path: PathBuf,
 }
 
-fn katex_url() -> String {
-    let mut url = env::var("MDR_KATEX").unwrap_or_else(|_| DEFAULT_KATEX.to_string());
-    if !url.ends_with('/') {
-        url.push('/');
+// Marks a temp dir as ours, so the sweep never deletes someone else's
+// `mdr-<n>` directory.
+const TEMP_MARKER: &str = ".mdr-temp";
+
+impl TempRoot {
+    fn create() -> io::Result<Self> {
+        let mut path = env::temp_dir();
+        path.push(format!("mdr-{}", process::id()));
+        fs::create_dir_all(&path)?;
+        fs::write(path.join(TEMP_MARKER), "")?;
+        Ok(TempRoot { path })
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
     }
-    url
 }
 
-fn has_title_metadata(path: &Path) -> bool {
-    let Ok(content) = fs::read_to_string(path) else {
-        return false;
+impl Drop for TempRoot {
+    fn drop(&mut self) {
+        cleanup(&self.path);
+    }
+}
+
+// Remove `mdr-<pid>` directories left behind by processes that were killed
+// before they could clean up (SIGKILL, power loss, ...).
+fn sweep_stale_temp_dirs(base: &Path) {
+    let Ok(entries) = fs::read_dir(base) else {
+        return;
     };
 
-    // YAML metadata block
-    if content.starts_with("---\n") {
-        if let Some(end) = content[4..].find("\n---") {
-            let header = &content[4..4 + end];
-            if header
-                .lines()
-                .any(|l| l.trim_start().to_ascii_lowercase().starts_with("title:"))
-            {
-                return true;
-            }
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let Some(pid) = name
+            .to_str()
+            .and_then(|n| n.strip_prefix("mdr-"))
+            .and_then(|p| p.parse::<u32>().ok())
+        else {
+            continue;
+        };
+
+        if pid == process::id() || process_alive(pid) {
+            continue;
         }
-    }
 
-    // Pandoc %-style metadata
-    if let Some(first_line) = content.lines().next() {
-        if first_line.starts_with('%') && first_line.len() > 1 {
-            return true;
+        // `symlink_metadata`, so a link to another directory is never followed.
+        let path = entry.path();
+        let ours = fs::symlink_metadata(&path)
+            .is_ok_and(|meta| meta.is_dir() && owned_by_current_user(&meta))
+            && path.join(TEMP_MARKER).is_file();
+        if ours {
+            let _ = fs::remove_dir_all(&path);
         }
     }
+}
 
-    false
+#[cfg(unix)]
+fn owned_by_current_user(meta: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    meta.uid() == unsafe { libc::getuid() }
+}
+
+#[cfg(not(unix))]
+fn owned_by_current_user(_meta: &fs::Metadata) -> bool {
+    true
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // Signal 0 only checks for existence; EPERM means it exists but belongs to
+    // someone else.
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return true;
+    }
+    io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    // Without a cheap liveness check, never remove another process's directory.
+    true
+}
+
+fn katex_url() -> String {
+    let mut url = env::var("MDR_KATEX").unwrap_or_else(|_| DEFAULT_KATEX.to_string());
+    if !url.ends_with('/') {
+        url.push('/');
+    }
+    url
 }
 
-fn ensure_pandoc(bin: &str) {
+fn ensure_pandoc() -> Result<(), i32> {
     match Command::new("pandoc")
         .arg("--version")
         .stdout(process::Stdio::null())
         .stderr(process::Stdio::null())
         .status()
     {
-        Ok(status) if status.success() => {}
+        Ok(status) if status.success() => Ok(()),
         Ok(_) | Err(_) => {
-            eprintln!(
-                "{bin}: pandoc not found. Please install pandoc and ensure it is on your PATH."
-            );
-            process::exit(127);
+            error!("pandoc not found. Please install pandoc and ensure it is on your PATH.");
+            Err(127)
         }
     }
 }
 
-fn confirm_overwrite(path: &Path, bin: &str) {
+fn confirm_overwrite(path: &Path) -> Result<(), i32> {
     if path.exists() {
-        eprintln!(
-            "{bin}: warning: output file already exists: {}",
-            path.display()
-        );
+        warn!("output file already exists: {}", path.display());
         eprint!("Overwrite? [y/N]: ");
         let _ = io::stderr().flush();
 
@@ -368,16 +1112,17 @@ fn confirm_overwrite(path: &Path, bin: &str) {
             Ok(_) => {
                 let normalized = response.trim().to_ascii_lowercase();
                 if normalized != "y" && normalized != "yes" {
-                    eprintln!("{bin}: aborting; not overwriting existing file");
-                    process::exit(1);
+                    error!("aborting; not overwriting existing file");
+                    return Err(1);
                 }
             }
             Err(err) => {
-                eprintln!("{bin}: failed to read confirmation: {err}");
-                process::exit(1);
+                error!("failed to read confirmation: {err}");
+                return Err(1);
             }
         }
     }
+    Ok(())
 }
 
 fn materialize_assets(temp: &Path) -> Result<Assets, i32> {
@@ -396,7 +1141,7 @@ fn materialize_assets(temp: &Path) -> Result<Assets, i32> {
 
     for (path, contents) in writes {
         if let Err(err) = write_file(path, contents) {
-            eprintln!("mdr: failed to write {path:?}: {err}");
+            error!("failed to write {path:?}: {err}");
             return Err(1);
         }
     }
@@ -409,86 +1154,285 @@ fn materialize_assets(temp: &Path) -> Result<Assets, i32> {
     })
 }
 
-async fn run_build_once(input_path: &Path, output_path: &Path, assets: &Assets) -> Result<(), i32> {
+async fn run_build_once(
+    input_path: &Path,
+    output_path: &Path,
+    assets: &Assets,
+    render: &RenderOptions,
+) -> (Result<(), i32>, String) {
     let input = input_path.to_path_buf();
     let output = output_path.to_path_buf();
     let assets = assets.clone();
+    let render = render.clone();
 
-    tokio::task::spawn_blocking(move || build_to_file(&input, &output, &assets))
-        .await
-        .map_err(|_| {
-            eprintln!("mdr: build task panicked");
-            1
-        })?
+    tokio::task::spawn_blocking(move || {
+        let mut stderr = String::new();
+        let result = build_to_file(&input, &output, &assets, &render, &mut stderr);
+        (result, stderr)
+    })
+    .await
+    .unwrap_or_else(|_| {
+        error!("build task panicked");
+        (Err(1), String::new())
+    })
 }
 
 async fn run_build_into_memory(
     input_path: &Path,
     assets: &Assets,
+    render: &RenderOptions,
     html: &SharedHtml,
-) -> Result<(), i32> {
+    backend: &PandocBackend,
+) -> (Result<(), i32>, String) {
     let input = input_path.to_path_buf();
     let assets = assets.clone();
+    let render = render.clone();
+    let backend = backend.clone();
 
-    let rendered = tokio::task::spawn_blocking(move || build_to_string(&input, &assets))
-        .await
-        .map_err(|_| {
-            eprintln!("mdr: build task panicked");
-            1
-        })??;
+    let (rendered, stderr) = tokio::task::spawn_blocking(move || {
+        let mut stderr = String::new();
+        let result = build_to_string(&input, &assets, &render, &backend, &mut stderr);
+        (result, stderr)
+    })
+    .await
+    .unwrap_or_else(|_| {
+        error!("build task panicked");
+        (Err(1), String::new())
+    });
 
-    let mut buf = html.write().await;
-    *buf = rendered;
-    Ok(())
-}
+    match rendered {
+        Ok(rendered) => {
+            html.write().await.replace(rendered);
+            (Ok(()), stderr)
+        }
+        Err(code) => (Err(code), stderr),
+    }
+}
 
-fn build_to_file(input_path: &Path, output_path: &Path, assets: &Assets) -> Result<(), i32> {
-    let mut cmd = make_pandoc_command(input_path, assets, Some(output_path));
-    let status = cmd.status();
+fn build_to_file(
+    input_path: &Path,
+    output_path: &Path,
+    assets: &Assets,
+    render: &RenderOptions,
+    stderr: &mut String,
+) -> Result<(), i32> {
+    let (front, stdin) = read_front_matter(input_path, render);
+    let cmd = make_pandoc_command(
+        input_path,
+        &front.metadata,
+        render,
+        assets,
+        Some(output_path),
+        stdin.is_some(),
+    );
+    trace!("running {cmd:?}");
+    let output = run_pandoc(cmd, stdin);
 
-    match status {
-        Ok(code) if code.success() => Ok(()),
-        Ok(code) => {
-            let code = code.code().unwrap_or(-1);
-            eprintln!("mdr: pandoc failed with exit code {code}");
+    match output {
+        Ok(out) if out.status.success() => {
+            forward_pandoc_stderr(&out.stderr, Level::Warn, stderr);
+            if let Ok(html) = fs::read_to_string(output_path) {
+                warn_duplicate_ids(input_path, &html);
+                warn_diagram_failures(input_path, &html);
+            }
+            Ok(())
+        }
+        Ok(out) => {
+            forward_pandoc_stderr(&out.stderr, Level::Error, stderr);
+            let code = out.status.code().unwrap_or(-1);
+            error!("pandoc failed with exit code {code}");
             Err(code)
         }
         Err(err) => {
-            eprintln!("mdr: failed to spawn pandoc: {err}");
+            error!("failed to spawn pandoc: {err}");
             Err(127)
         }
     }
 }
 
-fn build_to_string(input_path: &Path, assets: &Assets) -> Result<String, i32> {
-    let mut cmd = make_pandoc_command(input_path, assets, None);
-    let output = cmd.output();
+fn build_to_string(
+    input_path: &Path,
+    assets: &Assets,
+    render: &RenderOptions,
+    backend: &PandocBackend,
+    stderr: &mut String,
+) -> Result<String, i32> {
+    let (front, stdin) = read_front_matter(input_path, render);
+    if let PandocBackend::Server(server) = backend {
+        if let Some(html) = build_with_server(input_path, &front, render, server) {
+            warn_duplicate_ids(input_path, &html);
+            return Ok(html);
+        }
+    }
+
+    let cmd = make_pandoc_command(
+        input_path,
+        &front.metadata,
+        render,
+        assets,
+        None,
+        stdin.is_some(),
+    );
+    trace!("running {cmd:?}");
+    let output = run_pandoc(cmd, stdin);
 
     match output {
-        Ok(out) if out.status.success() => match String::from_utf8(out.stdout) {
-            Ok(html) => Ok(html),
-            Err(err) => {
-                eprintln!("mdr: pandoc output was not valid UTF-8: {err}");
-                Err(1)
+        Ok(out) if out.status.success() => {
+            forward_pandoc_stderr(&out.stderr, Level::Warn, stderr);
+            match String::from_utf8(out.stdout) {
+                Ok(html) => {
+                    warn_duplicate_ids(input_path, &html);
+                    warn_diagram_failures(input_path, &html);
+                    Ok(html)
+                }
+                Err(err) => {
+                    error!("pandoc output was not valid UTF-8: {err}");
+                    Err(1)
+                }
             }
-        },
+        }
         Ok(out) => {
+            forward_pandoc_stderr(&out.stderr, Level::Error, stderr);
             let code = out.status.code().unwrap_or(-1);
-            eprintln!("mdr: pandoc failed with exit code {code}");
+            error!("pandoc failed with exit code {code}");
             Err(code)
         }
         Err(err) => {
-            eprintln!("mdr: failed to spawn pandoc: {err}");
+            error!("failed to spawn pandoc: {err}");
             Err(127)
         }
     }
 }
 
-fn make_pandoc_command(input_path: &Path, assets: &Assets, output_path: Option<&Path>) -> Command {
+// Documents pandoc cannot read as they are (TOML front matter, a promoted
+// heading) come back with the source to send on stdin instead of the file.
+fn read_front_matter(input_path: &Path, render: &RenderOptions) -> (FrontMatter, Option<String>) {
+    let Ok(source) = fs::read_to_string(input_path) else {
+        return (FrontMatter::default(), None);
+    };
+    let mut front = front_matter::parse(&source);
+    if render.title_from == TitleMode::Heading {
+        front.promote_first_heading(&source);
+    }
+    for warning in &front.warnings {
+        warn!("{}: {warning}", input_path.display());
+    }
+    let stdin = front.pandoc_source(&source);
+    (front, stdin)
+}
+
+fn run_pandoc(mut cmd: Command, stdin: Option<String>) -> io::Result<process::Output> {
+    let Some(stdin) = stdin else {
+        return cmd.output();
+    };
+    let mut child = cmd
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()?;
+    // Written from another thread so a large document cannot deadlock against
+    // pandoc filling its stdout pipe.
+    let mut pipe = child.stdin.take().expect("stdin is piped");
+    let writer = std::thread::spawn(move || pipe.write_all(stdin.as_bytes()));
+    let output = child.wait_with_output()?;
+    match writer.join().expect("stdin writer panicked") {
+        // Pandoc exiting early reports its own error.
+        Err(err) if err.kind() != io::ErrorKind::BrokenPipe => Err(err),
+        _ => Ok(output),
+    }
+}
+
+fn warn_duplicate_ids(input_path: &Path, html: &str) {
+    for warning in anchors::duplicate_warnings(&anchors::headings(html)) {
+        let input = input_path.display().to_string();
+        logging::event(
+            Level::Warn,
+            "duplicate_heading_id",
+            json!({ "input": input, "message": warning }),
+            format_args!("{input}: {warning}"),
+        );
+    }
+    // Always worth a warning, unlike other broken links that only
+    // `--check-links` looks for.
+    for problem in check::unknown_crossrefs(html) {
+        log_broken_link(input_path, &problem);
+    }
+}
+
+fn log_broken_link(input_path: &Path, problem: &check::Problem) {
+    let input = input_path.display().to_string();
+    logging::event(
+        Level::Warn,
+        "broken_link",
+        json!({ "input": input, "target": problem.target, "message": problem.message }),
+        format_args!("{input}: {}", problem.message),
+    );
+}
+
+// The Lua filter runs as pandoc's child and cannot log for us, so blocks it
+// left as code under an error are reported once pandoc is done.
+fn warn_diagram_failures(input_path: &Path, html: &str) {
+    for err in diagrams::failures(html) {
+        let input = input_path.display().to_string();
+        logging::event(
+            Level::Warn,
+            "diagram_failed",
+            json!({ "input": input, "message": err }),
+            format_args!("{input}: {err}"),
+        );
+    }
+}
+
+// Pandoc's own diagnostics are captured rather than inherited so they respect
+// `--quiet`, stay parseable under `--log-format json`, and can be reported by
+// `/api/status`.
+fn forward_pandoc_stderr(stderr: &[u8], level: Level, sink: &mut String) {
+    let text = String::from_utf8_lossy(stderr);
+    sink.push_str(&text);
+    for line in text.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        logging::event(
+            level,
+            "pandoc_output",
+            json!({ "line": line }),
+            format_args!("pandoc: {line}"),
+        );
+    }
+}
+
+fn make_pandoc_command(
+    input_path: &Path,
+    metadata: &Metadata,
+    render: &RenderOptions,
+    assets: &Assets,
+    output_path: Option<&Path>,
+    from_stdin: bool,
+) -> Command {
     let mut cmd = Command::new("pandoc");
-    cmd.arg(format!("--katex={}", katex_url()))
-        .arg("--from")
-        .arg("markdown+tex_math_single_backslash")
+    match metadata.math.unwrap_or(MathEngine::Katex) {
+        MathEngine::Katex => {
+            cmd.arg(format!("--katex={}", katex_url()));
+        }
+        MathEngine::Mathjax => {
+            cmd.arg("--mathjax");
+        }
+        MathEngine::Mathml => {
+            cmd.arg("--mathml");
+        }
+        MathEngine::None => {}
+    }
+    // The filter renders diagram blocks by running this binary again; without
+    // the variable they stay code.
+    cmd.env_remove(diagrams::COMMAND_VAR);
+    if render.diagrams {
+        if let Ok(exe) = env::current_exe() {
+            cmd.env(diagrams::COMMAND_VAR, exe);
+        }
+    }
+    cmd.arg("--from")
+        .arg(render.pandoc_from())
         .arg("--embed-resources")
         .arg("--lua-filter")
         .arg(&assets.lua_path)
@@ -496,12 +1440,19 @@ fn make_pandoc_command(input_path: &Path, assets: &Assets, output_path: Option<&
         .arg("html5+smart")
         .arg("--standalone");
 
-    if !has_title_metadata(input_path) {
-        let fallback_title = input_path
-            .file_stem()
-            .and_then(|s| s.to_str())
-            .unwrap_or("Document");
-        cmd.arg("--metadata").arg(format!("title={fallback_title}"));
+    // Without a title block the file name still names the browser tab.
+    let hide_header = render.title_from == TitleMode::None;
+    if metadata.title.is_none() {
+        let key = if hide_header { "pagetitle" } else { "title" };
+        cmd.arg("--metadata")
+            .arg(format!("{key}={}", fallback_title(input_path)));
+    }
+    if hide_header {
+        cmd.arg("--variable").arg("hide-header");
+    }
+    if let Some(theme) = metadata.theme {
+        cmd.arg("--variable")
+            .arg(format!("theme={}", theme.as_str()));
     }
 
     cmd.arg("--template")
@@ -509,30 +1460,273 @@ fn make_pandoc_command(input_path: &Path, assets: &Assets, output_path: Option<&
         .arg("--css")
         .arg(&assets.theme_path)
         .arg("--css")
-        .arg(&assets.skylighting_path)
-        .arg("--toc")
-        .arg("--wrap=none");
+        .arg(&assets.skylighting_path);
+
+    let toc = render.toc(metadata);
+    if toc.enabled {
+        cmd.arg("--toc")
+            .arg("--variable")
+            .arg(format!("toc-placement={}", render.toc_placement.as_str()));
+    }
+    if let Some(depth) = toc.depth {
+        cmd.arg(format!("--toc-depth={depth}"));
+    }
+    if toc.numbered {
+        cmd.arg("--number-sections");
+    }
+    if let Some(shift) = metadata.pandoc.shift_heading_level_by {
+        cmd.arg(format!("--shift-heading-level-by={shift}"));
+    }
+    cmd.arg("--wrap=none");
 
     if let Some(out) = output_path {
         cmd.arg("--output").arg(out);
     }
 
-    cmd.arg(input_path);
+    if from_stdin {
+        // Relative images still resolve next to the document, as they would
+        // if pandoc read the file itself.
+        if let Some(dir) = input_path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            if let Ok(paths) = env::join_paths([dir, Path::new(".")]) {
+                cmd.arg("--resource-path").arg(paths);
+            }
+        }
+    } else {
+        cmd.arg(input_path);
+    }
     cmd
 }
 
-async fn build_to_target(
+fn fallback_title(input_path: &Path) -> &str {
+    input_path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("Document")
+}
+
+// Returns `None` whenever the server cannot produce the same page as the CLI
+// (documents that need the Lua filter, server errors), so the caller falls back
+// to spawning pandoc.
+fn build_with_server(
     input_path: &Path,
-    assets: Option<&Assets>,
-    target: &BuildTarget,
-) -> Result<(), i32> {
-    match (target, assets) {
-        (BuildTarget::File(path), Some(assets)) => run_build_once(input_path, path, assets).await,
-        (BuildTarget::File(path), None) => copy_html_file(input_path, path),
-        (BuildTarget::Memory(html), Some(assets)) => {
-            run_build_into_memory(input_path, assets, html).await
+    front: &FrontMatter,
+    render: &RenderOptions,
+    server: &PandocServer,
+) -> Option<String> {
+    let source = fs::read_to_string(input_path).ok()?;
+    if requires_lua_filter(&source) || (render.diagrams && diagrams::has_blocks(&source)) {
+        debug!("document needs the Lua filter; spawning pandoc instead of using the server");
+        return None;
+    }
+    let source = front.pandoc_source(&source).unwrap_or(source);
+
+    match server.convert(&make_server_request(
+        input_path,
+        &front.metadata,
+        render,
+        &source,
+    )) {
+        Ok(html) => Some(html),
+        Err(err) => {
+            warn!(
+                "pandoc server at {} failed ({err}); falling back to spawning pandoc",
+                server.addr()
+            );
+            None
+        }
+    }
+}
+
+// `pandoc server` runs conversions sandboxed and cannot execute Lua filters, so
+// anything the filter would rewrite (notes, callouts, numbered or margin
+// figures) has to go through the CLI.
+fn requires_lua_filter(source: &str) -> bool {
+    lua_filter_triggers()
+        .iter()
+        .any(|marker| source.contains(marker))
+}
+
+// The markers the filter declares in its `-- trigger: <marker>  <what>` lines,
+// so the list lives next to the code that handles them.
+fn lua_filter_triggers() -> &'static [&'static str] {
+    static TRIGGERS: OnceLock<Vec<&'static str>> = OnceLock::new();
+    TRIGGERS.get_or_init(|| {
+        SIDENOTE_LUA
+            .lines()
+            .filter_map(|line| line.strip_prefix("-- trigger:"))
+            .filter_map(|rest| rest.split_whitespace().next())
+            .collect()
+    })
+}
+
+// Mirrors `make_pandoc_command` for the server's JSON API. The server cannot read
+// our temp files, so the template is sent inline and the CSS is inlined as a
+// header include instead of being embedded by pandoc.
+fn make_server_request(
+    input_path: &Path,
+    metadata: &Metadata,
+    render: &RenderOptions,
+    source: &str,
+) -> serde_json::Value {
+    let mut variables = serde_json::Map::new();
+    variables.insert(
+        "header-includes".into(),
+        format!("<style>\n{THEME_CSS}\n{SKYLIGHTING_CSS}\n</style>").into(),
+    );
+
+    let hide_header = render.title_from == TitleMode::None;
+    if metadata.title.is_none() {
+        let fallback_title = fallback_title(input_path);
+        if !hide_header {
+            variables.insert("title".into(), fallback_title.into());
+        }
+        variables.insert("pagetitle".into(), fallback_title.into());
+    }
+    if hide_header {
+        variables.insert("hide-header".into(), true.into());
+    }
+    if let Some(theme) = metadata.theme {
+        variables.insert("theme".into(), theme.as_str().into());
+    }
+
+    let math = match metadata.math.unwrap_or(MathEngine::Katex) {
+        MathEngine::Katex => json!({ "method": "katex", "url": katex_url() }),
+        MathEngine::Mathjax => json!({ "method": "mathjax" }),
+        MathEngine::Mathml => json!({ "method": "mathml" }),
+        MathEngine::None => json!({ "method": "plain" }),
+    };
+
+    let toc = render.toc(metadata);
+    variables.insert("toc-placement".into(), render.toc_placement.as_str().into());
+    let mut request = json!({
+        "text": source,
+        "from": render.pandoc_from(),
+        "to": "html5+smart",
+        "standalone": true,
+        "template": TEMPLATE_HTML,
+        "html-math-method": math,
+        "table-of-contents": toc.enabled,
+        "number-sections": toc.numbered,
+        "wrap": "none",
+        "variables": variables,
+    });
+    if let Some(depth) = toc.depth {
+        request["toc-depth"] = depth.into();
+    }
+    if let Some(shift) = metadata.pandoc.shift_heading_level_by {
+        request["shift-heading-level-by"] = shift.into();
+    }
+    request
+}
+
+fn start_pandoc_server(config: &Config) -> PandocBackend {
+    let started = match config.pandoc_server_url.as_deref() {
+        Some(url) => PandocServer::connect(url),
+        None => PandocServer::spawn(),
+    };
+
+    match started {
+        Ok(server) => {
+            info!("rendering previews via pandoc server at {}", server.addr());
+            PandocBackend::Server(Arc::new(server))
+        }
+        Err(err) => {
+            warn!("pandoc server unavailable ({err}); spawning pandoc per build");
+            PandocBackend::Process
+        }
+    }
+}
+
+impl BuildJob {
+    async fn run(&self) -> Result<(), i32> {
+        let input = self.input_path.display().to_string();
+        logging::event(
+            Level::Info,
+            "build_started",
+            json!({ "input": input }),
+            format_args!("building {input}"),
+        );
+
+        let started = Instant::now();
+        let (result, stderr) = self.build().await;
+        let duration_ms = started.elapsed().as_millis() as u64;
+
+        match result {
+            Ok(()) => logging::event(
+                Level::Info,
+                "build_finished",
+                json!({ "input": input, "duration_ms": duration_ms }),
+                format_args!("built {input} in {duration_ms} ms"),
+            ),
+            Err(code) => logging::event(
+                Level::Error,
+                "build_failed",
+                json!({ "input": input, "duration_ms": duration_ms, "exit_code": code }),
+                format_args!("build of {input} failed after {duration_ms} ms"),
+            ),
+        }
+
+        let link_problems = match (&self.target, &result) {
+            (BuildTarget::Memory(html), Ok(())) if self.check_links => {
+                let html = Arc::clone(&html.read().await.html);
+                Some(self.link_problems(&html))
+            }
+            _ => None,
+        };
+
+        let mut status = self.status.write().await;
+        status.finished_at = Some(SystemTime::now());
+        status.duration_ms = duration_ms;
+        status.success = result.is_ok();
+        status.exit_code = result.err();
+        status.stderr = stderr;
+        status.builds += 1;
+        if let Some(problems) = link_problems {
+            status.link_problems = problems;
+        }
+
+        result
+    }
+
+    fn link_problems(&self, html: &str) -> Vec<String> {
+        let base_dir = match self.input_path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        check::check_html(html, base_dir, None)
+            .into_iter()
+            .map(|problem| {
+                // Unknown cross-references were logged with the build.
+                if !problem.target.starts_with('@') {
+                    log_broken_link(&self.input_path, &problem);
+                }
+                problem.message
+            })
+            .collect()
+    }
+
+    // Title of the in-memory document, if this job renders one.
+    async fn title(&self) -> Option<String> {
+        match &self.target {
+            BuildTarget::Memory(html) => extract_title(&html.read().await.html),
+            BuildTarget::File(_) => None,
+        }
+    }
+
+    async fn build(&self) -> (Result<(), i32>, String) {
+        let input_path = self.input_path.as_path();
+        match (&self.target, &self.assets) {
+            (BuildTarget::File(path), Some(assets)) => {
+                run_build_once(input_path, path, assets, &self.render).await
+            }
+            (BuildTarget::File(path), None) => (copy_html_file(input_path, path), String::new()),
+            (BuildTarget::Memory(html), Some(assets)) => {
+                run_build_into_memory(input_path, assets, &self.render, html, &self.backend).await
+            }
+            (BuildTarget::Memory(html), None) => {
+                (read_html_into_memory(input_path, html).await, String::new())
+            }
         }
-        (BuildTarget::Memory(html), None) => read_html_into_memory(input_path, html).await,
     }
 }
 
@@ -545,93 +1739,689 @@ fn is_html_input(path: &Path) -> bool {
 
 async fn read_html_into_memory(input_path: &Path, html: &SharedHtml) -> Result<(), i32> {
     let content = fs::read_to_string(input_path).map_err(|err| {
-        eprintln!("mdr: failed to read {}: {err}", input_path.display());
+        error!("failed to read {}: {err}", input_path.display());
         1
     })?;
-    let mut buf = html.write().await;
-    *buf = content;
+    html.write().await.replace(content);
     Ok(())
 }
 
 fn copy_html_file(input_path: &Path, output_path: &Path) -> Result<(), i32> {
     fs::copy(input_path, output_path).map_err(|err| {
-        eprintln!(
-            "mdr: failed to copy {} to {}: {err}",
+        error!(
+            "failed to copy {} to {}: {err}",
             input_path.display(),
             output_path.display()
         );
         1
     })?;
-    Ok(())
+    Ok(())
+}
+
+fn load_tls(config: &Config) -> Result<Option<Arc<ServerConfig>>, i32> {
+    let loaded = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => tls::load(cert, key),
+        _ if config.tls_self_signed => {
+            let generated = tls::self_signed(&config.host);
+            if generated.is_ok() {
+                info!("using a self-signed certificate; browsers will ask you to trust it");
+            }
+            generated
+        }
+        _ => return Ok(None),
+    };
+    loaded.map(Some).map_err(|err| {
+        error!("failed to set up TLS: {err}");
+        1
+    })
+}
+
+async fn run_watch_mode(config: &Config, job: BuildJob) -> Result<(), i32> {
+    info!(
+        "watching {} for changes (press Ctrl+C to stop)",
+        config.input_path.display()
+    );
+
+    // Forced rebuilds only come from the HTTP API, so nothing ever sends here.
+    let (_, rebuild_rx) = mpsc::channel(1);
+
+    select! {
+        res = watch_and_rebuild(job, None, rebuild_rx) => res,
+        signal = shutdown_signal() => {
+            log_shutdown(signal);
+            Ok(())
+        }
+    }
+}
+
+async fn run_serve_mode(
+    config: &Config,
+    settings: &Settings,
+    documents: Documents,
+    job: BuildJob,
+    rebuild_rx: mpsc::Receiver<oneshot::Sender<()>>,
+    tls: Option<Arc<ServerConfig>>,
+) -> Result<(), i32> {
+    let token = if config.access_token {
+        Some(auth::generate_token().map_err(|err| {
+            error!("failed to generate access token: {err}");
+            1
+        })?)
+    } else {
+        None
+    };
+    let auth = Arc::new(auth::Auth {
+        token,
+        basic: config.basic_auth.clone(),
+        secure: tls.is_some(),
+        base_path: config.base_path.clone(),
+    });
+
+    let options = ServerOptions {
+        port: config.port,
+        port_explicit: config.port_explicit,
+        host: config.host.clone(),
+        tls,
+        auth,
+        // Browsers cannot open a Unix socket, so a config default is ignored.
+        open: config.socket.is_none() && config.open.or(settings.serve.open).unwrap_or(false),
+        browser: settings.serve.browser.clone(),
+        socket: config.socket.clone(),
+    };
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let clients = Arc::new(AtomicUsize::new(0));
+    let live_tx = documents.primary().await.live_tx;
+
+    let state = AppState {
+        documents,
+        shutdown_rx,
+        clients: clients.clone(),
+        base_path: config.base_path.clone(),
+        trust_proxy: config.trust_proxy,
+        bodies: http_cache::BodyCache::default(),
+        links: Arc::default(),
+    };
+
+    let mut watch_handle = tokio::spawn(watch_and_rebuild(job, Some(live_tx), rebuild_rx));
+    let _ = state
+        .documents
+        .primary()
+        .await
+        .watcher
+        .set(watch_handle.abort_handle());
+    // Closing the first document with `mdr ctl stop <file>` aborts its watcher
+    // while the others keep being served.
+    let primary_watch = async {
+        match (&mut watch_handle).await {
+            Err(err) if err.is_cancelled() => std::future::pending().await,
+            res => res.unwrap_or(Err(1)),
+        }
+    };
+
+    let (stop_tx, mut stop_rx) = mpsc::channel(1);
+    #[cfg(unix)]
+    let control_handle = start_control_socket(state.clone(), stop_tx);
+    #[cfg(not(unix))]
+    drop(stop_tx);
+
+    let mut server_handle = tokio::spawn(run_http_server(options, state));
+
+    let mut server_done = false;
+    let result = select! {
+        res = primary_watch => res,
+        res = &mut server_handle => {
+            server_done = true;
+            res.unwrap_or(Err(1))
+        }
+        signal = stop_requested(&mut stop_rx) => {
+            log_shutdown(signal);
+            let _ = shutdown_tx.send(true);
+            drain_clients(&clients, Duration::from_secs(1)).await;
+            server_done = tokio::time::timeout(Duration::from_secs(1), &mut server_handle)
+                .await
+                .is_ok();
+            Ok(())
+        }
+    };
+
+    watch_handle.abort();
+    #[cfg(unix)]
+    if let Some(handle) = control_handle {
+        // Dropping the listener removes the control socket.
+        handle.abort();
+        let _ = handle.await;
+    }
+    if !server_done {
+        // Let the cancelled server drop its listener so a socket file is removed.
+        server_handle.abort();
+        let _ = server_handle.await;
+    }
+
+    result
+}
+
+// A termination signal, or `mdr ctl stop` on the control socket.
+async fn stop_requested(stop_rx: &mut mpsc::Receiver<()>) -> &'static str {
+    select! {
+        signal = shutdown_signal() => signal,
+        Some(()) = stop_rx.recv() => "stop request",
+    }
+}
+
+// Listen for `mdr open` and `mdr ctl` on this process's control socket. Without
+// one the preview still works; it just cannot be reached that way.
+#[cfg(unix)]
+fn start_control_socket(
+    state: AppState,
+    stop_tx: mpsc::Sender<()>,
+) -> Option<tokio::task::JoinHandle<()>> {
+    let listener = match control::bind() {
+        Ok(listener) => listener,
+        Err(err) => {
+            warn!("control socket unavailable ({err}); `mdr open` and `mdr ctl` cannot reach this server");
+            return None;
+        }
+    };
+    debug!("control socket at {}", listener.path().display());
+
+    Some(tokio::spawn(control::serve(listener, move |request| {
+        handle_control(state.clone(), stop_tx.clone(), request)
+    })))
+}
+
+#[cfg(unix)]
+async fn handle_control(
+    state: AppState,
+    stop_tx: mpsc::Sender<()>,
+    request: control::Request,
+) -> control::Response {
+    match request {
+        control::Request::Open { path } => match state.documents.open(&path).await {
+            Ok(doc) => control::Response::url(state.url(&doc.path())),
+            Err(err) => control::Response::error(err),
+        },
+        control::Request::List => {
+            let mut documents = Vec::new();
+            for doc in state.documents.all().await {
+                documents.push(document_info(&state, &doc).await);
+            }
+            control::Response::documents(documents)
+        }
+        control::Request::Rebuild { path } => {
+            let mut targets = Vec::new();
+            for doc in state.documents.all().await {
+                let info = document_info(&state, &doc).await;
+                if path.as_ref().is_none_or(|path| *path == info.path) {
+                    targets.push((doc, info));
+                }
+            }
+            if targets.is_empty() {
+                return control::Response::error("no such document on this server");
+            }
+
+            let mut rebuilt = Vec::new();
+            for (doc, info) in targets {
+                let (done_tx, done_rx) = oneshot::channel();
+                if doc.rebuild_tx.send(done_tx).await.is_err() || done_rx.await.is_err() {
+                    return control::Response::error(format!(
+                        "rebuild of {} unavailable; its watcher has stopped",
+                        info.path.display()
+                    ));
+                }
+                rebuilt.push(info);
+            }
+            control::Response::documents(rebuilt)
+        }
+        control::Request::Stop { path } => {
+            if let Some(path) = path {
+                match state.documents.close(&path).await {
+                    Ok(true) => {
+                        return control::Response {
+                            ok: true,
+                            ..control::Response::default()
+                        }
+                    }
+                    Ok(false) => {}
+                    Err(err) => return control::Response::error(err),
+                }
+            }
+            let _ = stop_tx.try_send(());
+            control::Response {
+                ok: true,
+                stopping: true,
+                ..control::Response::default()
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn document_info(state: &AppState, doc: &Document) -> control::DocumentInfo {
+    control::DocumentInfo {
+        path: doc
+            .input_path
+            .canonicalize()
+            .unwrap_or_else(|_| doc.input_path.clone()),
+        url: state.url(&doc.path()),
+        title: extract_title(&doc.html.read().await.html),
+    }
+}
+
+// `mdr open <file>`: print the URL of the preview already serving `file`, or
+// ask the most recently started server to serve it too.
+#[cfg(unix)]
+fn run_open(args: &[String]) -> Result<(), i32> {
+    logging::init(1, logging::Format::Text);
+    let [file] = args else {
+        error!("usage: mdr open <file>");
+        return Err(64);
+    };
+    let path = canonical_input(file)?;
+
+    if let Some((_, doc)) = control::find(&path) {
+        println!("{}", doc.url);
+        return Ok(());
+    }
+    let Some(socket) = control::instances().into_iter().next() else {
+        error!("no running mdr preview found; start one with `mdr <file>`");
+        return Err(69);
+    };
+    let response = control_request(&socket, &control::Request::Open { path })?;
+    println!("{}", response.url.unwrap_or_default());
+    Ok(())
+}
+
+// `mdr ctl <command>`: talk to running preview servers, told apart by the
+// files they serve.
+#[cfg(unix)]
+fn run_ctl(args: &[String]) -> Result<(), i32> {
+    logging::init(1, logging::Format::Text);
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["list"] => {
+            for socket in control::instances() {
+                let Ok(response) = control::send(&socket, &control::Request::List) else {
+                    continue;
+                };
+                let pid = control::pid(&socket).map_or(String::new(), |pid| pid.to_string());
+                for doc in response.documents {
+                    println!("{pid}\t{}\t{}", doc.url, doc.path.display());
+                }
+            }
+            Ok(())
+        }
+        ["url", file] => {
+            let (_, doc) = serving_instance(file)?;
+            println!("{}", doc.url);
+            Ok(())
+        }
+        [command @ ("rebuild" | "stop"), rest @ ..] if rest.len() <= 1 => {
+            let (socket, path) = match rest.first() {
+                Some(file) => {
+                    let (socket, doc) = serving_instance(file)?;
+                    (socket, Some(doc.path))
+                }
+                None => (only_instance()?, None),
+            };
+
+            if *command == "rebuild" {
+                let response = control_request(&socket, &control::Request::Rebuild { path })?;
+                for doc in response.documents {
+                    println!("{}", doc.url);
+                }
+                return Ok(());
+            }
+
+            let response = control_request(&socket, &control::Request::Stop { path })?;
+            if !response.stopping {
+                return Ok(());
+            }
+            // Return once the server is gone, so scripts can start another.
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while socket.exists() && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            Ok(())
+        }
+        _ => {
+            error!("usage: mdr ctl list | url <file> | rebuild [<file>] | stop [<file>]");
+            Err(64)
+        }
+    }
+}
+
+// `mdr check [--external | --offline] <file>...`: report links, anchors and
+// images that lead nowhere. Exits 1 when any are found.
+fn run_check(args: &[String]) -> Result<(), i32> {
+    logging::init(1, logging::Format::Text);
+    let mut external: Option<bool> = None;
+    let mut files = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--external" => external = Some(true),
+            "--offline" => external = Some(false),
+            _ if arg.starts_with('-') => {
+                error!("unknown option: {arg}");
+                error!("usage: mdr check [--external | --offline] <file>...");
+                return Err(64);
+            }
+            _ => files.push(PathBuf::from(arg)),
+        }
+    }
+    if files.is_empty() {
+        error!("usage: mdr check [--external | --offline] <file>...");
+        return Err(64);
+    }
+    if files.iter().any(|file| !is_html_input(file)) {
+        ensure_pandoc()?;
+    }
+
+    let mut broken = 0;
+    for file in &files {
+        let settings = Settings::load(file).map_err(|err| {
+            error!("invalid config: {err}");
+            78
+        })?;
+        let source = fs::read_to_string(file).map_err(|err| {
+            error!("{}: {err}", file.display());
+            66
+        })?;
+        let html = if is_html_input(file) {
+            source.clone()
+        } else {
+            render_for_check(file, &RenderOptions::from_settings(&settings.document))?
+        };
+
+        let checker = match external.or(settings.check.external) {
+            Some(true) => Some(check::CommandChecker::new(
+                settings
+                    .check
+                    .url_command
+                    .as_deref()
+                    .unwrap_or(check::CommandChecker::DEFAULT),
+            )),
+            _ => None,
+        };
+        let base_dir = match file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        for problem in check::check_html(
+            &html,
+            base_dir,
+            checker.as_ref().map(|c| c as &dyn check::UrlChecker),
+        ) {
+            broken += 1;
+            match check::line_of(&source, &problem.target) {
+                Some(line) => println!("{}:{line}: {}", file.display(), problem.message),
+                None => println!("{}: {}", file.display(), problem.message),
+            }
+        }
+    }
+
+    if broken > 0 {
+        info!("{broken} broken link(s)");
+        return Err(1);
+    }
+    Ok(())
+}
+
+// A body-only rendering with the same IDs as the preview but without
+// `--embed-resources`, so image paths survive to be checked.
+fn render_for_check(input_path: &Path, render: &RenderOptions) -> Result<String, i32> {
+    let (_, stdin) = read_front_matter(input_path, render);
+    let stdin = match stdin {
+        Some(source) => source,
+        None => fs::read_to_string(input_path).map_err(|err| {
+            error!("{}: {err}", input_path.display());
+            66
+        })?,
+    };
+    let mut cmd = Command::new("pandoc");
+    cmd.arg("--from")
+        .arg(render.pandoc_from())
+        .arg("--to")
+        .arg("html5");
+    let output = run_pandoc(cmd, Some(stdin)).map_err(|err| {
+        error!("failed to spawn pandoc: {err}");
+        127
+    })?;
+    let mut stderr = String::new();
+    if !output.status.success() {
+        forward_pandoc_stderr(&output.stderr, Level::Error, &mut stderr);
+        return Err(output.status.code().unwrap_or(1));
+    }
+    String::from_utf8(output.stdout).map_err(|err| {
+        error!("pandoc output was not valid UTF-8: {err}");
+        1
+    })
+}
+
+// `mdr lint [--strict] <file>...`: report Markdown that mdr would render
+// differently than intended, one `file:line: severity: message [rule]` per
+// finding. Exits 1 on errors, or on warnings too with `--strict`.
+fn run_lint(args: &[String]) -> Result<(), i32> {
+    const USAGE: &str = "usage: mdr lint [--strict] <file>...";
+    logging::init(1, logging::Format::Text);
+    let mut strict = false;
+    let mut files = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--strict" => strict = true,
+            _ if arg.starts_with('-') => {
+                error!("unknown option: {arg}");
+                error!("{USAGE}");
+                return Err(64);
+            }
+            _ => files.push(PathBuf::from(arg)),
+        }
+    }
+    if files.is_empty() {
+        error!("{USAGE}");
+        return Err(64);
+    }
+    if let Some(file) = files.iter().find(|file| is_html_input(file)) {
+        error!("{}: mdr lint only reads Markdown", file.display());
+        return Err(64);
+    }
+
+    let (mut errors, mut warnings) = (0, 0);
+    for file in &files {
+        let settings = Settings::load(file).map_err(|err| {
+            error!("invalid config: {err}");
+            78
+        })?;
+        let source = fs::read_to_string(file).map_err(|err| {
+            error!("{}: {err}", file.display());
+            66
+        })?;
+        let options = lint::Options::from_settings(&settings.lint);
+        for finding in lint::lint(&source, &options) {
+            match finding.severity {
+                settings::Severity::Error => errors += 1,
+                _ => warnings += 1,
+            }
+            println!(
+                "{}:{}: {}: {} [{}]",
+                file.display(),
+                finding.line,
+                finding.severity.as_str(),
+                finding.message,
+                finding.rule.name()
+            );
+        }
+    }
+
+    if errors + warnings > 0 {
+        info!("{errors} error(s), {warnings} warning(s)");
+    }
+    if errors > 0 || (strict && warnings > 0) {
+        return Err(1);
+    }
+    Ok(())
+}
+
+// Run by the Lua filter for each diagram block, not by people: the source
+// arrives on stdin and the reply goes to stdout (see `diagrams::filter_reply`).
+fn run_render_diagram(args: &[String]) -> Result<(), i32> {
+    let [class] = args else {
+        error!("usage: mdr render-diagram <class> < source");
+        return Err(64);
+    };
+    let mut source = String::new();
+    if let Err(err) = io::Read::read_to_string(&mut io::stdin(), &mut source) {
+        error!("failed to read the diagram source: {err}");
+        return Err(1);
+    }
+    let reply = diagrams::filter_reply(class, &source, diagrams::run_tool);
+    io::stdout().write_all(reply.as_bytes()).map_err(|_| 1)
+}
+
+#[cfg(not(unix))]
+fn run_open(_args: &[String]) -> Result<(), i32> {
+    logging::init(1, logging::Format::Text);
+    error!("mdr open needs a Unix control socket");
+    Err(64)
+}
+
+#[cfg(not(unix))]
+fn run_ctl(_args: &[String]) -> Result<(), i32> {
+    logging::init(1, logging::Format::Text);
+    error!("mdr ctl needs a Unix control socket");
+    Err(64)
+}
+
+#[cfg(unix)]
+fn canonical_input(file: &str) -> Result<PathBuf, i32> {
+    Path::new(file).canonicalize().map_err(|err| {
+        error!("{file}: {err}");
+        66
+    })
+}
+
+// The server whose documents include `file`.
+#[cfg(unix)]
+fn serving_instance(file: &str) -> Result<(PathBuf, control::DocumentInfo), i32> {
+    let path = canonical_input(file)?;
+    if control::instances().is_empty() {
+        error!("no running mdr preview found");
+        return Err(69);
+    }
+    control::find(&path).ok_or_else(|| {
+        error!("no running mdr preview serves {}", path.display());
+        1
+    })
+}
+
+// Commands without a file need exactly one server to be unambiguous.
+#[cfg(unix)]
+fn only_instance() -> Result<PathBuf, i32> {
+    match control::instances().as_slice() {
+        [] => {
+            error!("no running mdr preview found");
+            Err(69)
+        }
+        [socket] => Ok(socket.clone()),
+        several => {
+            error!(
+                "{} previews are running; name a file to pick one",
+                several.len()
+            );
+            Err(64)
+        }
+    }
+}
+
+// Send `request` and insist on success; failures are logged.
+#[cfg(unix)]
+fn control_request(socket: &Path, request: &control::Request) -> Result<control::Response, i32> {
+    let response = control::send(socket, request).map_err(|err| {
+        error!("failed to reach {}: {err}", socket.display());
+        69
+    })?;
+    if !response.ok {
+        error!(
+            "{}",
+            response
+                .error
+                .as_deref()
+                .unwrap_or("the server refused the request")
+        );
+        return Err(1);
+    }
+    Ok(response)
 }
 
-async fn run_watch_mode(config: &Config, assets: Option<Assets>) -> Result<(), i32> {
-    eprintln!(
-        "{}: watching {} for changes (press Ctrl+C to stop)",
-        config.bin,
-        config.input_path.display()
+fn log_shutdown(signal: &str) {
+    logging::event(
+        Level::Info,
+        "shutdown",
+        json!({ "signal": signal }),
+        format_args!("received {signal}; shutting down"),
     );
-
-    watch_and_rebuild(
-        config.bin.clone(),
-        config.input_path.clone(),
-        assets,
-        BuildTarget::File(config.output_path.clone()),
-        None,
-    )
-    .await
 }
 
-async fn run_serve_mode(config: &Config, assets: Option<Assets>, html: SharedHtml) -> Result<(), i32> {
-    let (reload_tx, _) = broadcast::channel(32);
+// Resolves with the name of the first termination signal received.
+async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::SignalKind;
+
+        select! {
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+            _ = wait_for_signal(SignalKind::terminate()) => "SIGTERM",
+            _ = wait_for_signal(SignalKind::hangup()) => "SIGHUP",
+        }
+    }
 
-    let mut watch_handle = tokio::spawn(watch_and_rebuild(
-        config.bin.clone(),
-        config.input_path.clone(),
-        assets,
-        BuildTarget::Memory(html.clone()),
-        Some(reload_tx.clone()),
-    ));
-
-    let mut server_handle = tokio::spawn(run_http_server(
-        config.bin.clone(),
-        html,
-        config.port,
-        config.port_explicit,
-        config.host.clone(),
-        reload_tx,
-    ));
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl-C"
+    }
+}
 
-    let result = select! {
-        res = &mut watch_handle => res.unwrap_or_else(|_| Err(1)),
-        res = &mut server_handle => res.unwrap_or_else(|_| Err(1)),
-    };
+#[cfg(unix)]
+async fn wait_for_signal(kind: tokio::signal::unix::SignalKind) {
+    match tokio::signal::unix::signal(kind) {
+        Ok(mut stream) => {
+            stream.recv().await;
+        }
+        Err(_) => std::future::pending().await,
+    }
+}
 
-    watch_handle.abort();
-    server_handle.abort();
+async fn shutdown_requested(rx: &mut watch::Receiver<bool>) {
+    let _ = rx.wait_for(|stop| *stop).await;
+}
 
-    result
+// Give WebSocket handlers a moment to send their close frames.
+async fn drain_clients(clients: &AtomicUsize, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    while clients.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
 }
 
 async fn watch_and_rebuild(
-    bin: String,
-    input_path: PathBuf,
-    assets: Option<Assets>,
-    target: BuildTarget,
-    reload_tx: Option<broadcast::Sender<()>>,
+    job: BuildJob,
+    live_tx: Option<broadcast::Sender<LiveMessage>>,
+    mut rebuild_rx: mpsc::Receiver<oneshot::Sender<()>>,
 ) -> Result<(), i32> {
+    let input_path = job.input_path.clone();
     let (tx, mut rx) = mpsc::unbounded_channel();
 
     let mut watcher: RecommendedWatcher = recommended_watcher(move |res| {
         let _ = tx.send(res);
     })
     .map_err(|err| {
-        eprintln!("mdr: failed to start watcher: {err}");
+        error!("failed to start watcher: {err}");
         1
     })?;
 
     if let Err(err) = watcher.configure(NotifyConfig::default()) {
-        eprintln!("mdr: watcher configuration failed: {err}");
+        error!("watcher configuration failed: {err}");
         return Err(1);
     }
 
@@ -643,8 +2433,8 @@ async fn watch_and_rebuild(
     let canonical_input = match input_path.canonicalize() {
         Ok(path) => path,
         Err(err) => {
-            eprintln!(
-                "{bin}: warning: could not canonicalize input {}: {err}",
+            warn!(
+                "could not canonicalize input {}: {err}",
                 input_path.display()
             );
             input_path.clone()
@@ -652,62 +2442,62 @@ async fn watch_and_rebuild(
     };
 
     if let Err(err) = watcher.watch(&watch_target, RecursiveMode::NonRecursive) {
-        eprintln!("mdr: unable to watch {}: {err}", watch_target.display());
+        error!("unable to watch {}: {err}", watch_target.display());
         return Err(1);
     }
 
     let debounce = Duration::from_millis(250);
     let mut last_build = Instant::now() - debounce;
-    let ctrl_c = tokio::signal::ctrl_c();
-    tokio::pin!(ctrl_c);
+    let mut title = job.title().await;
 
     loop {
         select! {
-            _ = &mut ctrl_c => {
-                eprintln!("{bin}: stopping watch");
-                break;
-            }
-            Some(res) = rx.recv() => {
-                match res {
-                    Ok(event) => {
-                        if !relevant_event(&event)
-                            || !event_targets_input(&event, &input_path, &watch_target, &canonical_input)
-                        {
-                            continue;
-                        }
-
-                        if last_build.elapsed() < debounce {
-                            continue;
-                        }
-
-                        if !input_path.exists() {
-                            eprintln!(
-                                "{bin}: input file {} is missing; waiting for it to reappear",
-                                input_path.display()
-                            );
-                            continue;
-                        }
-
-                        if let Err(code) = build_to_target(&input_path, assets.as_ref(), &target).await {
-                            if code == 127 {
-                                return Err(code);
-                            }
-                            last_build = Instant::now();
-                            continue;
-                        }
-
-                        last_build = Instant::now();
+            Some(res) = rx.recv() => match res {
+                Ok(event) => {
+                    if !relevant_event(&event)
+                        || !event_targets_input(&event, &input_path, &watch_target, &canonical_input)
+                    {
+                        continue;
+                    }
 
-                        if let Some(ref tx) = reload_tx {
-                            let _ = tx.send(());
-                        }
+                    if last_build.elapsed() < debounce {
+                        trace!("ignoring {:?} within debounce window", event.kind);
+                        continue;
+                    }
 
-                        eprintln!("{bin}: change detected; rebuild complete");
+                    if !input_path.exists() {
+                        warn!(
+                            "input file {} is missing; waiting for it to reappear",
+                            input_path.display()
+                        );
+                        continue;
                     }
-                    Err(err) => {
-                        eprintln!("mdr: watch error: {err}");
+
+                    logging::event(
+                        Level::Info,
+                        "file_changed",
+                        json!({ "path": input_path.display().to_string() }),
+                        format_args!("change detected in {}", input_path.display()),
+                    );
+
+                    let result = rebuild_and_notify(&job, live_tx.as_ref(), &mut title).await;
+                    last_build = Instant::now();
+                    if result == Err(127) {
+                        return Err(127);
                     }
                 }
+                Err(err) => {
+                    error!("watch error: {err}");
+                }
+            },
+            Some(done) = rebuild_rx.recv() => {
+                info!("rebuild requested");
+                let result = rebuild_and_notify(&job, live_tx.as_ref(), &mut title).await;
+                last_build = Instant::now();
+                let _ = done.send(());
+                if result == Err(127) {
+                    return Err(127);
+                }
             }
             else => break,
         }
@@ -716,50 +2506,341 @@ async fn watch_and_rebuild(
     Ok(())
 }
 
-async fn run_http_server(
-    bin: String,
-    html: SharedHtml,
+// Runs a build and tells live clients about it: `build-started` first, then
+// `title-changed` + `reload` on success or `build-error` on failure.
+async fn rebuild_and_notify(
+    job: &BuildJob,
+    live_tx: Option<&broadcast::Sender<LiveMessage>>,
+    last_title: &mut Option<String>,
+) -> Result<(), i32> {
+    let Some(tx) = live_tx else {
+        return job.run().await;
+    };
+
+    let _ = tx.send(LiveMessage::BuildStarted);
+    let result = job.run().await;
+
+    match result {
+        Ok(()) => {
+            let title = job.title().await;
+            if title != *last_title {
+                if let Some(ref t) = title {
+                    let _ = tx.send(LiveMessage::TitleChanged { title: t.clone() });
+                }
+                *last_title = title;
+            }
+            let _ = tx.send(LiveMessage::Reload);
+        }
+        Err(exit_code) => {
+            let stderr = job.status.read().await.stderr.clone();
+            let _ = tx.send(LiveMessage::BuildError { exit_code, stderr });
+        }
+    }
+
+    result
+}
+
+// How and where the preview server listens.
+struct ServerOptions {
     port: u16,
     port_explicit: bool,
     host: String,
-    reload_tx: broadcast::Sender<()>,
-) -> Result<(), i32> {
+    tls: Option<Arc<ServerConfig>>,
+    auth: Arc<auth::Auth>,
+    open: bool,
+    browser: Option<String>,
+    socket: Option<PathBuf>,
+}
+
+async fn run_http_server(options: ServerOptions, state: AppState) -> Result<(), i32> {
+    let ServerOptions {
+        mut port,
+        port_explicit,
+        host,
+        tls,
+        auth,
+        open,
+        browser,
+        socket,
+    } = options;
+
+    #[cfg(unix)]
+    if let Some(path) = socket {
+        return run_socket_server(&path, auth, state).await;
+    }
+    #[cfg(not(unix))]
+    let _ = socket;
+
+    let input_path = state.documents.primary().await.input_path;
+
+    // Coming back on the same port lets a tab left open from the last run
+    // reconnect instead of opening another one.
+    if open && !port_explicit {
+        if let Some(previous) = remembered_port(&input_path) {
+            port = previous;
+        }
+    }
+
     let listener = bind_http_listener(host.as_str(), port, !port_explicit)
         .await
         .map_err(|err| {
-            eprintln!("mdr: failed to bind HTTP server: {err}");
+            error!("failed to bind HTTP server: {err}");
             1
         })?;
 
     let addr = listener.local_addr().map_err(|err| {
-        eprintln!("mdr: failed to read server address: {err}");
+        error!("failed to read server address: {err}");
         1
     })?;
 
+    let scheme = if tls.is_some() { "https" } else { "http" };
+
     if !port_explicit && addr.port() != port {
-        eprintln!(
-            "{bin}: port {port} is in use; serving instead on http://{}:{}/",
+        warn!(
+            "port {port} is in use; serving instead on {scheme}://{}:{}/",
             host,
             addr.port()
         );
     }
 
-    eprintln!("{bin}: serving in-memory HTML at http://{addr}/ (live reload enabled)");
+    if !addr.ip().is_loopback() {
+        if tls.is_none() {
+            warn!("serving plaintext HTTP on {host}; use --tls-cert/--tls-key or --tls-self-signed on shared networks");
+        }
+        if !auth.enabled() {
+            warn!("anyone who can reach {host} can view this preview; use --token or --basic-auth");
+        }
+    }
+
+    // The token rides along once; the server swaps it for a cookie.
+    let query = token_query(&auth);
+    let path = format!("{}/{query}", auth.base_path);
+
+    // Nobody can open `0.0.0.0`: show loopback for this machine and list the
+    // interface addresses other devices can use.
+    let (url, lan_urls) = if addr.ip().is_unspecified() {
+        let local: IpAddr = if addr.is_ipv4() {
+            Ipv4Addr::LOCALHOST.into()
+        } else {
+            Ipv6Addr::LOCALHOST.into()
+        };
+        let lan_urls: Vec<String> = lan::reachable_addrs(addr.ip())
+            .into_iter()
+            .map(|ip| lan::url(scheme, ip, addr.port(), &path))
+            .collect();
+        (lan::url(scheme, local, addr.port(), &path), lan_urls)
+    } else {
+        (lan::url(scheme, addr.ip(), addr.port(), &path), Vec::new())
+    };
+
+    let origin = url[..url.len() - path.len()].to_string();
+    let _ = state.links.set((origin, query));
+
+    logging::event(
+        Level::Info,
+        "server_listening",
+        json!({ "url": url, "lan_urls": lan_urls }),
+        format_args!("serving in-memory HTML at {url} (live reload enabled)"),
+    );
+    for lan_url in &lan_urls {
+        info!("  on your network: {lan_url}");
+    }
+    if addr.ip().is_unspecified() && lan_urls.is_empty() {
+        warn!("no non-loopback network interfaces found");
+    }
+
+    let shared_url = lan_urls
+        .first()
+        .or((!addr.ip().is_loopback()).then_some(&url));
+    if let Some(shared_url) = shared_url {
+        print_qr_code(shared_url);
+    }
+
+    if open {
+        remember_port(&input_path, addr.port());
+        tokio::spawn(open_browser(url.clone(), browser, state.clients.clone()));
+    }
+
+    let mut shutdown_rx = state.shutdown_rx.clone();
+    let shutdown = async move { shutdown_requested(&mut shutdown_rx).await };
+    let app = router(state, auth);
+
+    let served = match tls {
+        Some(config) => tls::serve(listener, config, app, shutdown).await,
+        None => {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown)
+                .await
+        }
+    };
+    served.map_err(|err| {
+        error!("server error: {err}");
+        1
+    })
+}
+
+#[cfg(unix)]
+async fn run_socket_server(path: &Path, auth: Arc<auth::Auth>, state: AppState) -> Result<(), i32> {
+    let listener = unix_socket::SocketListener::bind(path).map_err(|err| {
+        error!("failed to bind {}: {err}", path.display());
+        1
+    })?;
 
-    let state = AppState { html, reload_tx };
+    let socket = listener.path().display().to_string();
+    let _ = state.links.set((String::new(), token_query(&auth)));
+    logging::event(
+        Level::Info,
+        "server_listening",
+        json!({ "socket": socket }),
+        format_args!("serving in-memory HTML on unix:{socket} (live reload enabled)"),
+    );
+
+    let mut shutdown_rx = state.shutdown_rx.clone();
+    let shutdown = async move { shutdown_requested(&mut shutdown_rx).await };
+    listener
+        .serve(router(state, auth), shutdown)
+        .await
+        .map_err(|err| {
+            error!("server error: {err}");
+            1
+        })
+}
+
+fn token_query(auth: &auth::Auth) -> String {
+    match &auth.token {
+        Some(token) => format!("?token={token}"),
+        None => String::new(),
+    }
+}
 
-    let app = Router::new()
-        .route("/", get(serve_output))
+// Endpoints every document has. At the top level they address the first
+// document, under `/d/<slug>` the named one.
+fn document_routes() -> Router<AppState> {
+    Router::new()
         .route("/live.js", get(live_js))
         .route("/ws", get(ws_handler))
+        .route("/events", get(sse_handler))
+        .route("/api/status", get(api_status))
+        .route("/api/version", get(api_version))
+        .route("/api/rebuild", post(api_rebuild))
+}
+
+fn router(state: AppState, auth: Arc<auth::Auth>) -> Router {
+    let base_path = state.base_path.clone();
+    let trust_proxy = state.trust_proxy || !base_path.is_empty();
+    let page_state = state.clone();
+    let routes = Router::new()
+        .route("/", get(root_page))
+        .route("/d/:doc/", get(serve_output))
+        // Relative, so it survives proxy prefixes.
+        .route(
+            "/d/:doc",
+            get(|Doc(doc): Doc| async move { Redirect::temporary(&format!("{}/", doc.slug)) }),
+        )
+        .merge(document_routes())
+        .nest("/d/:doc", document_routes())
         .with_state(state);
 
-    axum::serve(listener, app).await.map_err(|err| {
-        eprintln!("mdr: server error: {err}");
-        1
+    // Under a base path, `/` points visitors at the preview.
+    let app = if base_path.is_empty() {
+        routes
+    } else {
+        // `nest` only matches `/base` itself, not `/base/`.
+        let page = Router::new()
+            .route(&format!("{base_path}/"), get(root_page))
+            .with_state(page_state);
+        let nested = Router::new().nest(&base_path, routes).merge(page);
+        nested.route(
+            "/",
+            get(move |headers: HeaderMap| async move {
+                let prefix = forwarded::public_prefix(&headers, &base_path);
+                Redirect::temporary(&format!("{prefix}/"))
+            }),
+        )
+    };
+    let app = app.layer(middleware::from_fn_with_state(auth, auth::require));
+    // Outermost, so neither auth nor the handlers see spoofed headers.
+    if trust_proxy {
+        app
+    } else {
+        app.layer(middleware::from_fn(forwarded::strip))
+    }
+}
+
+// Give a tab left open by a previous run a moment to reconnect (live.js
+// retries every 500 ms) before launching a new one.
+async fn open_browser(url: String, command: Option<String>, clients: Arc<AtomicUsize>) {
+    let deadline = Instant::now() + Duration::from_millis(1500);
+    while Instant::now() < deadline {
+        if clients.load(Ordering::SeqCst) > 0 {
+            debug!("an existing preview tab reconnected; not opening a browser");
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    logging::event(
+        Level::Info,
+        "browser_opened",
+        json!({ "url": url }),
+        format_args!("opening {url} in a browser"),
+    );
+    if let Err(err) = browser::open(&url, command.as_deref()) {
+        warn!("failed to open a browser: {err}");
+    }
+}
+
+fn port_state_path() -> Option<PathBuf> {
+    let base = env::var_os("XDG_STATE_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))?;
+    Some(base.join("mdr").join("ports"))
+}
+
+// Lines of `<port>\t<canonical input path>`.
+fn remembered_port(input: &Path) -> Option<u16> {
+    let key = input.canonicalize().ok()?;
+    let text = fs::read_to_string(port_state_path()?).ok()?;
+    text.lines().find_map(|line| {
+        let (port, path) = line.split_once('\t')?;
+        (Path::new(path) == key).then(|| port.parse().ok())?
     })
 }
 
+fn remember_port(input: &Path, port: u16) {
+    let (Some(state), Ok(key)) = (port_state_path(), input.canonicalize()) else {
+        return;
+    };
+    let key = key.display().to_string();
+    let mut lines: Vec<String> = fs::read_to_string(&state)
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| line.split_once('\t').is_some_and(|(_, path)| path != key))
+        .map(str::to_string)
+        .collect();
+    lines.insert(0, format!("{port}\t{key}"));
+    lines.truncate(100);
+
+    let written = state
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|()| fs::write(&state, lines.join("\n") + "\n"));
+    if let Err(err) = written {
+        debug!("unable to remember port in {}: {err}", state.display());
+    }
+}
+
+// Only for people at a terminal; logs and pipes get the URLs alone.
+fn print_qr_code(url: &str) {
+    if !io::stderr().is_terminal() || !logging::is_text() || !logging::enabled(Level::Info) {
+        return;
+    }
+    if let Some(code) = lan::qr_code(url) {
+        eprint!("\n{code}\n");
+    }
+}
+
 async fn bind_http_listener(
     host: &str,
     port: u16,
@@ -784,59 +2865,396 @@ async fn bind_http_listener(
 
 #[derive(Clone)]
 struct AppState {
-    html: SharedHtml,
-    reload_tx: broadcast::Sender<()>,
+    documents: Documents,
+    shutdown_rx: watch::Receiver<bool>,
+    clients: Arc<AtomicUsize>,
+    base_path: String,
+    // Honor `X-Forwarded-*` from clients: `--trust-proxy` or `--base-path`.
+    trust_proxy: bool,
+    bodies: http_cache::BodyCache,
+    // Origin browsers use and the `?token=` query to append, known once the
+    // server is listening; `mdr open` answers with URLs built from them.
+    links: Arc<OnceLock<(String, String)>>,
 }
 
-async fn serve_output(State(state): State<AppState>) -> impl IntoResponse {
-    let mut html = state.html.read().await.clone();
-    if !html.contains("/live.js") {
-        html.push_str("\n<script src=\"/live.js\"></script>\n");
+impl AppState {
+    fn url(&self, path: &str) -> String {
+        let (origin, query) = self.links.get().cloned().unwrap_or_default();
+        format!("{origin}{}{path}{query}", self.base_path)
     }
+}
 
-    (
-        StatusCode::OK,
-        [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
-        html,
-    )
+// The document a request is for: the `:doc` route segment, or the first
+// document for the top-level routes.
+struct Doc(Document);
+
+#[axum::async_trait]
+impl FromRequestParts<AppState> for Doc {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Response> {
+        let params = Option::<RawPathParams>::from_request_parts(parts, state)
+            .await
+            .unwrap_or_default();
+        let slug = params.and_then(|params| {
+            params
+                .iter()
+                .find(|(key, _)| *key == "doc")
+                .map(|(_, value)| value.to_string())
+        });
+        let found = match slug {
+            Some(slug) => state.documents.get(&slug).await,
+            None => Some(state.documents.primary().await),
+        };
+        found
+            .map(Doc)
+            .ok_or_else(|| (StatusCode::NOT_FOUND, "no such document\n").into_response())
+    }
 }
 
-async fn live_js() -> impl IntoResponse {
-    const SCRIPT: &str = r#"(() => {
-const proto = location.protocol === "https:" ? "wss://" : "ws://";
-const ws = new WebSocket(proto + location.host + "/ws");
-ws.onmessage = () => location.reload();
-ws.onclose = () => setTimeout(() => location.reload(), 1000);
-})();"#;
-    (
-        StatusCode::OK,
-        [(header::CONTENT_TYPE, "application/javascript")],
-        SCRIPT,
-    )
+// The document itself while it is the only one, an index once there are more.
+async fn root_page(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    let documents = state.documents.all().await;
+    if let [only] = documents.as_slice() {
+        let prefix = forwarded::public_prefix(&headers, &state.base_path);
+        return document_page(&state, only, &headers, &prefix).await;
+    }
+
+    let mut items = String::new();
+    for doc in &documents {
+        let title = extract_title(&doc.html.read().await.html).unwrap_or_else(|| doc.slug.clone());
+        items.push_str(&format!(
+            "<li><a href=\"d/{}/\">{}</a> <code>{}</code></li>\n",
+            doc.slug,
+            escape_html(&title),
+            escape_html(&doc.input_path.display().to_string()),
+        ));
+    }
+    let page = format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>mdr</title>\n<style>body{{font:16px/1.5 system-ui,sans-serif;max-width:40em;\
+         margin:3em auto;padding:0 1em}}li{{margin:.4em 0}}code{{color:#777}}</style>\n\
+         </head>\n<body>\n<h1>Documents</h1>\n<ul>\n{items}</ul>\n</body>\n</html>\n"
+    );
+
+    http_cache::Cached {
+        content_type: "text/html; charset=utf-8",
+        tag: http_cache::content_hash(&page),
+        last_modified: None,
+        body: || page.into_bytes(),
+    }
+    .respond(&headers, &state.bodies)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+async fn serve_output(
+    State(state): State<AppState>,
+    Doc(doc): Doc,
+    headers: HeaderMap,
+) -> Response {
+    let prefix = forwarded::public_prefix(&headers, &state.base_path);
+    let script_dir = format!("{prefix}/d/{}", doc.slug);
+    document_page(&state, &doc, &headers, &script_dir).await
+}
+
+async fn document_page(
+    state: &AppState,
+    doc: &Document,
+    headers: &HeaderMap,
+    script_dir: &str,
+) -> Response {
+    // Validators and body come from one snapshot; the HTML itself is only
+    // copied when the client's copy is stale and no encoded body is cached.
+    let (html, hash, modified, inject) = {
+        let page = doc.html.read().await;
+        (
+            Arc::clone(&page.html),
+            page.hash,
+            page.modified,
+            page.inject_live_script,
+        )
+    };
+    http_cache::Cached {
+        content_type: "text/html; charset=utf-8",
+        // The injected script URL depends on the route and the request's prefix.
+        tag: http_cache::content_hash((hash, script_dir)),
+        last_modified: modified,
+        body: || {
+            let mut body = String::with_capacity(html.len() + 64);
+            body.push_str(&html);
+            if inject {
+                // live.js finds its endpoints relative to its own URL.
+                body.push_str(&format!(
+                    "\n<script src=\"{script_dir}/live.js\"></script>\n"
+                ));
+            }
+            body.into_bytes()
+        },
+    }
+    .respond(headers, &state.bodies)
+}
+
+async fn api_status(Doc(doc): Doc) -> impl IntoResponse {
+    Json(status_json(&doc).await)
+}
+
+// Queues a rebuild on the watcher task and answers once it has finished, so the
+// response reflects the new build.
+async fn api_rebuild(Doc(doc): Doc) -> impl IntoResponse {
+    let (done_tx, done_rx) = oneshot::channel();
+    if doc.rebuild_tx.send(done_tx).await.is_err() || done_rx.await.is_err() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({ "error": "rebuild unavailable; watcher has stopped" })),
+        );
+    }
+    (StatusCode::OK, Json(status_json(&doc).await))
+}
+
+async fn status_json(doc: &Document) -> serde_json::Value {
+    let title = extract_title(&doc.html.read().await.html);
+    let status = doc.status.read().await;
+    json!({
+        "input": doc.input_path.display().to_string(),
+        "path": doc.path(),
+        "title": title,
+        "clients": doc.clients.load(Ordering::SeqCst),
+        "builds": status.builds,
+        "last_build": status.finished_at.map(logging::timestamp),
+        "duration_ms": status.duration_ms,
+        "success": status.success,
+        "exit_code": status.exit_code,
+        "stderr": status.stderr,
+        "link_problems": status.link_problems,
+    })
+}
+
+// The template always emits `<title>`, so this also covers HTML inputs.
+fn extract_title(html: &str) -> Option<String> {
+    let start = html.find("<title>")? + "<title>".len();
+    let end = start + html[start..].find("</title>")?;
+    let title = html[start..end]
+        .trim()
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+    (!title.is_empty()).then_some(title)
+}
+
+async fn live_js(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    http_cache::Cached {
+        content_type: "application/javascript",
+        tag: http_cache::content_hash(LIVE_JS),
+        last_modified: None,
+        body: || LIVE_JS.as_bytes().to_vec(),
+    }
+    .respond(&headers, &state.bodies)
 }
 
-async fn ws_handler(State(state): State<AppState>, ws: WebSocketUpgrade) -> impl IntoResponse {
-    let mut rx = state.reload_tx.subscribe();
+async fn ws_handler(
+    State(state): State<AppState>,
+    Doc(doc): Doc,
+    Query(params): Query<HashMap<String, String>>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    // Clients that do not ask for the JSON protocol keep getting bare "reload".
+    let protocol = params.get("protocol").and_then(|v| v.parse::<u64>().ok());
+    let typed = protocol.is_some_and(|v| v >= 1);
     ws.on_upgrade(move |socket| async move {
-        if let Err(err) = handle_ws(socket, &mut rx).await {
-            eprintln!("mdr: websocket error: {err}");
+        let _client = ClientGuard::connect(&state, &doc, "websocket", protocol);
+        if let Err(err) = handle_ws(socket, &state, &doc, typed).await {
+            warn!("websocket error: {err}");
         }
     })
 }
 
+// Counts a live reload connection for its document's `/api/status` and, across
+// all documents, for shutdown draining.
+struct ClientGuard {
+    clients: Arc<AtomicUsize>,
+    document_clients: Arc<AtomicUsize>,
+}
+
+impl ClientGuard {
+    fn connect(state: &AppState, doc: &Document, transport: &str, protocol: Option<u64>) -> Self {
+        doc.clients.fetch_add(1, Ordering::SeqCst);
+        let connected = state.clients.fetch_add(1, Ordering::SeqCst) + 1;
+        logging::event(
+            Level::Info,
+            "client_connected",
+            json!({ "clients": connected, "transport": transport, "protocol": protocol }),
+            format_args!("live reload client connected via {transport} ({connected} total)"),
+        );
+        ClientGuard {
+            clients: Arc::clone(&state.clients),
+            document_clients: Arc::clone(&doc.clients),
+        }
+    }
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.document_clients.fetch_sub(1, Ordering::SeqCst);
+        let remaining = self.clients.fetch_sub(1, Ordering::SeqCst) - 1;
+        logging::event(
+            Level::Info,
+            "client_disconnected",
+            json!({ "clients": remaining }),
+            format_args!("live reload client disconnected ({remaining} remaining)"),
+        );
+    }
+}
+
+// Server-Sent Events carry the same JSON messages as `/ws?protocol=1` for
+// clients behind proxies that strip WebSocket upgrades. The channel is one-way,
+// so there are no pings; `KeepAlive` comments hold the connection open instead.
+async fn sse_handler(State(state): State<AppState>, Doc(doc): Doc) -> impl IntoResponse {
+    let client = ClientGuard::connect(&state, &doc, "sse", Some(live::PROTOCOL_VERSION));
+    let rx = doc.live_tx.subscribe();
+    let shutdown_rx = state.shutdown_rx.clone();
+
+    let hello = stream::once(async {
+        Ok::<_, Infallible>(Event::default().data(LiveMessage::Hello.to_json()))
+    });
+    let updates = stream::unfold(
+        (client, rx, shutdown_rx),
+        |(client, mut rx, mut shutdown_rx)| async move {
+            loop {
+                select! {
+                    msg = rx.recv() => match msg {
+                        Ok(msg) => {
+                            let event = Event::default().data(msg.to_json());
+                            return Some((Ok(event), (client, rx, shutdown_rx)));
+                        }
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => return None,
+                    },
+                    _ = shutdown_requested(&mut shutdown_rx) => return None,
+                }
+            }
+        },
+    );
+
+    Sse::new(hello.chain(updates)).keep_alive(KeepAlive::default())
+}
+
+// Last-resort live reload: clients poll this with `If-None-Match` and reload
+// when the ETag changes. The pid keeps tags from matching across restarts.
+async fn api_version(Doc(doc): Doc, headers: HeaderMap) -> Response {
+    let status = doc.status.read().await;
+    let version = format!("{}-{}", process::id(), status.builds);
+    let etag = format!("\"{version}\"");
+
+    let fresh = headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|value| value.as_bytes() == etag.as_bytes());
+    if fresh {
+        return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+    }
+
+    (
+        StatusCode::OK,
+        [
+            (header::ETAG, etag),
+            (header::CACHE_CONTROL, "no-cache".to_string()),
+        ],
+        Json(json!({
+            "version": version,
+            "success": status.success,
+            "exit_code": status.exit_code,
+        })),
+    )
+        .into_response()
+}
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(90);
+
 async fn handle_ws(
     mut socket: WebSocket,
-    rx: &mut broadcast::Receiver<()>,
+    state: &AppState,
+    doc: &Document,
+    typed: bool,
 ) -> Result<(), axum::Error> {
+    let mut rx = doc.live_tx.subscribe();
+    let mut shutdown_rx = state.shutdown_rx.clone();
+    let mut heartbeat = tokio::time::interval_at(
+        tokio::time::Instant::now() + HEARTBEAT_INTERVAL,
+        HEARTBEAT_INTERVAL,
+    );
+    let mut last_seen = Instant::now();
+
+    if typed {
+        socket
+            .send(Message::Text(LiveMessage::Hello.to_json()))
+            .await?;
+    }
+
     loop {
-        match rx.recv().await {
-            Ok(_) => {
-                if socket.send(Message::Text("reload".into())).await.is_err() {
+        select! {
+            msg = rx.recv() => match msg {
+                Ok(msg) => {
+                    let text = if typed {
+                        msg.to_json()
+                    } else if msg == LiveMessage::Reload {
+                        "reload".to_string()
+                    } else {
+                        continue;
+                    };
+                    if socket.send(Message::Text(text)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            },
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Text(text))) => {
+                    last_seen = Instant::now();
+                    match ClientMessage::parse(&text) {
+                        ClientMessage::Ping => {
+                            socket.send(Message::Text(LiveMessage::Pong.to_json())).await?;
+                        }
+                        ClientMessage::ScrollTo { id } => {
+                            let _ = doc.live_tx.send(LiveMessage::ScrollTo { id });
+                        }
+                        ClientMessage::Hello { version } => {
+                            debug!("live reload client speaks protocol v{version}");
+                        }
+                        ClientMessage::Pong => {}
+                        ClientMessage::Unknown(text) => {
+                            debug!("ignoring unknown live reload message: {text}");
+                        }
+                    }
+                }
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => last_seen = Instant::now(),
+                Some(Err(err)) => return Err(err),
+            },
+            _ = heartbeat.tick(), if typed => {
+                if last_seen.elapsed() > HEARTBEAT_TIMEOUT {
+                    debug!("live reload client missed heartbeats; closing");
                     break;
                 }
+                socket.send(Message::Text(LiveMessage::Ping.to_json())).await?;
+            }
+            _ = shutdown_requested(&mut shutdown_rx) => {
+                let frame = CloseFrame {
+                    code: close_code::AWAY,
+                    reason: "mdr shutting down".into(),
+                };
+                let _ = socket.send(Message::Close(Some(frame))).await;
+                return Ok(());
             }
-            Err(broadcast::error::RecvError::Closed) => break,
-            Err(broadcast::error::RecvError::Lagged(_)) => continue,
         }
     }
 
@@ -847,7 +3265,7 @@ async fn handle_ws(
 fn cleanup(temp: &Path) {
     if let Err(err) = fs::remove_dir_all(temp) {
         // Not fatal; leave directory behind for inspection.
-        eprintln!("mdr: warning: unable to remove temp dir {temp:?}: {err}");
+        warn!("unable to remove temp dir {temp:?}: {err}");
     }
 }
 
@@ -875,7 +3293,7 @@ fn event_targets_input(
         };
 
         if let Ok(canon) = candidate.canonicalize() {
-            if &canon == canonical_input {
+            if canon == canonical_input {
                 return true;
             }
         }
@@ -940,4 +3358,119 @@ mod tests {
 
         assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
     }
+
+    #[test]
+    fn sweep_removes_only_dead_process_dirs() {
+        let base = tempfile::tempdir().expect("tempdir");
+        let dead = base.path().join(format!("mdr-{}", i32::MAX));
+        let alive = base.path().join(format!("mdr-{}", process::id()));
+        let unrelated = base.path().join("mdr-notes");
+        let unmarked = base.path().join(format!("mdr-{}", i32::MAX - 1));
+        for dir in [&dead, &alive, &unrelated, &unmarked] {
+            fs::create_dir_all(dir).expect("create dir");
+        }
+        for dir in [&dead, &alive, &unrelated] {
+            fs::write(dir.join(TEMP_MARKER), "").expect("write marker");
+        }
+
+        sweep_stale_temp_dirs(base.path());
+
+        assert!(!dead.exists(), "stale dir should be removed");
+        assert!(alive.exists(), "live process dir must be kept");
+        assert!(unrelated.exists(), "non-pid dirs must be ignored");
+        assert!(unmarked.exists(), "dirs without the marker must be kept");
+    }
+
+    #[test]
+    fn extract_title_decodes_entities() {
+        let html = "<head>\n  <title>Q&amp;A &lt;draft&gt;</title>\n</head>";
+        assert_eq!(extract_title(html).as_deref(), Some("Q&A <draft>"));
+        assert_eq!(extract_title("<title> </title>"), None);
+        assert_eq!(extract_title("<p>no head</p>"), None);
+    }
+
+    #[test]
+    fn slugs_are_url_safe_and_unique() {
+        let taken = vec!["notes".to_string(), "notes-2".to_string()];
+        assert_eq!(unique_slug(Path::new("/a/Notes.md"), &taken), "notes-3");
+        assert_eq!(
+            unique_slug(Path::new("My Draft (v2).md"), &[]),
+            "my-draft-v2"
+        );
+        assert_eq!(unique_slug(Path::new("/a/!!!.md"), &[]), "doc");
+    }
+
+    #[test]
+    fn requires_lua_filter_detects_footnotes() {
+        assert!(requires_lua_filter("Text.[^1]\n\n[^1]: Note."));
+        assert!(requires_lua_filter("Inline^[note] here."));
+        assert!(!requires_lua_filter("# Plain\n\nNo notes at all."));
+        assert!(requires_lua_filter("> [!WARNING]\n> Careful."));
+        assert!(requires_lua_filter("::: tip\nFaster.\n:::"));
+        assert!(requires_lua_filter(
+            "![Plot](plot.png){#fig:plot}\n\nSee @fig:plot."
+        ));
+        assert!(requires_lua_filter("See @tbl:missing."));
+        assert_eq!(lua_filter_triggers().len(), 9);
+    }
+
+    #[test]
+    fn server_request_inlines_template_css_and_fallback_title() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let input = dir.path().join("notes.md");
+        fs::write(&input, "Body").expect("write input");
+
+        let req = make_server_request(
+            &input,
+            &Metadata::default(),
+            &RenderOptions::default(),
+            "Body",
+        );
+
+        assert_eq!(req["text"], "Body");
+        assert_eq!(req["standalone"], true);
+        assert_eq!(req["template"], TEMPLATE_HTML);
+        assert_eq!(req["variables"]["title"], "notes");
+        assert!(req["variables"]["header-includes"]
+            .as_str()
+            .expect("header-includes string")
+            .contains("--color-sidenote"));
+    }
+
+    #[test]
+    fn front_matter_toc_options_win_over_flags_and_config() {
+        let render = RenderOptions {
+            toc: Some(false),
+            toc_depth: Some(2),
+            ..RenderOptions::default()
+        };
+        let plain = Metadata::default();
+        let toc = render.toc(&plain);
+        assert!(!toc.enabled);
+        assert_eq!(toc.depth, Some(2));
+        assert!(!toc.numbered);
+
+        let front = front_matter::parse("---\npandoc:\n  toc: true\n  toc-depth: 4\n---\n");
+        let toc = render.toc(&front.metadata);
+        assert!(toc.enabled);
+        assert_eq!(toc.depth, Some(4));
+    }
+
+    #[test]
+    fn server_request_follows_front_matter() {
+        let front = front_matter::parse(
+            "---\ntitle: Paper\ntheme: dark\nmath: mathjax\npandoc:\n  toc: false\n---\nBody",
+        );
+        let req = make_server_request(
+            Path::new("notes.md"),
+            &front.metadata,
+            &RenderOptions::default(),
+            "Body",
+        );
+
+        assert!(req["variables"].get("title").is_none());
+        assert_eq!(req["variables"]["theme"], "dark");
+        assert_eq!(req["html-math-method"]["method"], "mathjax");
+        assert_eq!(req["table-of-contents"], false);
+    }
 }
@@ -0,0 +1,380 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener as StdTcpListener, TcpStream, ToSocketAddrs};
+use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Servers this process spawned. `Drop` cannot be relied on to stop them:
+// `process::exit` skips destructors, and tasks sharing the server are never
+// dropped before it. `kill_spawned` runs on every exit path instead.
+static SPAWNED: Mutex<Vec<Child>> = Mutex::new(Vec::new());
+
+/// Stop every `pandoc server` this process spawned.
+pub fn kill_spawned() {
+    let children = std::mem::take(&mut *SPAWNED.lock().unwrap_or_else(|e| e.into_inner()));
+    for mut child in children {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+fn kill_child(pid: u32) {
+    let mut spawned = SPAWNED.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(at) = spawned.iter().position(|child| child.id() == pid) {
+        let mut child = spawned.swap_remove(at);
+        drop(spawned);
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+/// A running `pandoc server` instance, either spawned by mdr or provided by the user.
+///
+/// Conversions are sent as JSON over plain HTTP/1.1 so a rebuild costs one local
+/// request instead of a fresh pandoc process.
+pub struct PandocServer {
+    addr: String,
+    // Pid of the server we spawned, registered in `SPAWNED`.
+    child: Option<u32>,
+}
+
+impl PandocServer {
+    /// Spawn `pandoc server` on a free loopback port and wait until it accepts connections.
+    pub fn spawn() -> io::Result<Self> {
+        let port = StdTcpListener::bind(("127.0.0.1", 0))?.local_addr()?.port();
+
+        let child = Command::new("pandoc")
+            .arg("server")
+            .arg("--port")
+            .arg(port.to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        let server = PandocServer {
+            addr: format!("127.0.0.1:{port}"),
+            child: Some(child.id()),
+        };
+        SPAWNED
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(child);
+        server.wait_ready(STARTUP_TIMEOUT)?;
+        Ok(server)
+    }
+
+    /// Use an already running server at `url` (`http://host:port` or `host:port`).
+    pub fn connect(url: &str) -> io::Result<Self> {
+        let server = PandocServer {
+            addr: parse_server_addr(url)?,
+            child: None,
+        };
+        server.wait_ready(Duration::from_millis(500))?;
+        Ok(server)
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    fn wait_ready(&self, timeout: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let err = match connect_with_timeout(&self.addr, remaining.min(CONNECT_TIMEOUT)) {
+                Ok(_) => return Ok(()),
+                Err(err) => err,
+            };
+
+            if let Some(status) = self.child_exit_status()? {
+                return Err(io::Error::other(format!(
+                    "pandoc server exited during startup ({status})"
+                )));
+            }
+
+            if Instant::now() >= deadline {
+                return Err(err);
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    fn child_exit_status(&self) -> io::Result<Option<std::process::ExitStatus>> {
+        let Some(pid) = self.child else {
+            return Ok(None);
+        };
+        let mut spawned = SPAWNED.lock().unwrap_or_else(|e| e.into_inner());
+        match spawned.iter_mut().find(|child| child.id() == pid) {
+            Some(child) => child.try_wait(),
+            None => Ok(None),
+        }
+    }
+
+    /// POST a conversion request and return the rendered document.
+    pub fn convert(&self, request: &Value) -> io::Result<String> {
+        let body = request.to_string();
+        let mut stream = connect_with_timeout(&self.addr, CONNECT_TIMEOUT)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+        write!(
+            stream,
+            "POST / HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nAccept: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.addr,
+            body.len()
+        )?;
+        stream.write_all(body.as_bytes())?;
+        stream.flush()?;
+
+        let mut raw = Vec::new();
+        stream.read_to_end(&mut raw)?;
+        let (status, body) = parse_http_response(&raw)?;
+
+        if status != 200 {
+            let detail = String::from_utf8_lossy(&body).trim().to_string();
+            return Err(io::Error::other(format!("HTTP {status}: {detail}")));
+        }
+
+        parse_convert_response(&body)
+    }
+}
+
+impl Drop for PandocServer {
+    fn drop(&mut self) {
+        if let Some(pid) = self.child {
+            kill_child(pid);
+        }
+    }
+}
+
+fn connect_with_timeout(addr: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no address resolved");
+    for sock in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&sock, timeout.max(Duration::from_millis(1))) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+/// Whether a command-line value is a server address rather than an input file:
+/// a `scheme://` URL or `host:port` with a numeric port.
+pub fn looks_like_url(arg: &str) -> bool {
+    if let Some((scheme, _)) = arg.split_once("://") {
+        return !scheme.is_empty() && scheme.chars().all(|c| c.is_ascii_alphanumeric());
+    }
+    match arg.rsplit_once(':') {
+        Some((host, port)) => {
+            !host.is_empty()
+                && !host.contains(['/', '\\'])
+                && !port.is_empty()
+                && port.chars().all(|c| c.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
+fn parse_server_addr(url: &str) -> io::Result<String> {
+    let rest = url.strip_prefix("http://").unwrap_or(url);
+    if url.starts_with("https://") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "https pandoc servers are not supported",
+        ));
+    }
+    let authority = rest.split('/').next().unwrap_or_default();
+    if authority.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid pandoc server url: {url}"),
+        ));
+    }
+    if authority.contains(':') {
+        Ok(authority.to_string())
+    } else {
+        Ok(format!("{authority}:3030"))
+    }
+}
+
+fn parse_http_response(raw: &[u8]) -> io::Result<(u16, Vec<u8>)> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+    let split = raw
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| invalid("truncated HTTP response"))?;
+    let head = std::str::from_utf8(&raw[..split]).map_err(|_| invalid("invalid HTTP headers"))?;
+    let body = &raw[split + 4..];
+
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|l| l.split_whitespace().nth(1))
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or_else(|| invalid("invalid HTTP status line"))?;
+
+    let chunked = lines.any(|l| {
+        let l = l.to_ascii_lowercase();
+        l.starts_with("transfer-encoding:") && l.contains("chunked")
+    });
+
+    let body = if chunked {
+        decode_chunked(body).ok_or_else(|| invalid("invalid chunked body"))?
+    } else {
+        body.to_vec()
+    };
+
+    Ok((status, body))
+}
+
+fn decode_chunked(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|w| w == b"\r\n")?;
+        let size_line = std::str::from_utf8(&body[..line_end]).ok()?;
+        let size_hex = size_line.split(';').next()?.trim();
+        let size = usize::from_str_radix(size_hex, 16).ok()?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Some(out);
+        }
+        out.extend_from_slice(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
+
+fn parse_convert_response(body: &[u8]) -> io::Result<String> {
+    let value: Value = serde_json::from_slice(body)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    if value.get("base64").and_then(Value::as_bool) == Some(true) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "pandoc server returned binary output",
+        ));
+    }
+
+    value
+        .get("output")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "pandoc server response has no output",
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn one_shot_server(response: &'static str) -> (String, thread::JoinHandle<String>) {
+        let listener = StdTcpListener::bind(("127.0.0.1", 0)).expect("bind mock server");
+        let addr = listener.local_addr().expect("local addr").to_string();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().expect("accept");
+            let mut buf = [0u8; 8192];
+            let mut request = Vec::new();
+            loop {
+                let n = stream.read(&mut buf).expect("read request");
+                request.extend_from_slice(&buf[..n]);
+                if n == 0 || request.ends_with(b"}") {
+                    break;
+                }
+            }
+            stream
+                .write_all(response.as_bytes())
+                .expect("write response");
+            String::from_utf8(request).expect("utf8 request")
+        });
+        (addr, handle)
+    }
+
+    #[test]
+    fn convert_posts_json_and_reads_output() {
+        let (addr, handle) = one_shot_server(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n{\"output\":\"<p>hi</p>\",\"base64\":false,\"messages\":[]}",
+        );
+        let server = PandocServer { addr, child: None };
+
+        let html = server
+            .convert(&json!({ "text": "hi", "to": "html5" }))
+            .expect("conversion succeeds");
+        let request = handle.join().expect("mock server thread");
+
+        assert_eq!(html, "<p>hi</p>");
+        assert!(request.starts_with("POST / HTTP/1.1\r\n"));
+        assert!(request.contains("Accept: application/json"));
+        assert!(request.ends_with(r#"{"text":"hi","to":"html5"}"#));
+    }
+
+    #[test]
+    fn convert_reports_server_errors() {
+        let (addr, handle) = one_shot_server(
+            "HTTP/1.1 500 Internal Server Error\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nboom!\r\n0\r\n\r\n",
+        );
+        let server = PandocServer { addr, child: None };
+
+        let err = server
+            .convert(&json!({ "text": "hi" }))
+            .expect_err("500 should be an error");
+        handle.join().expect("mock server thread");
+
+        assert_eq!(err.to_string(), "HTTP 500: boom!");
+    }
+
+    #[test]
+    fn parse_server_addr_accepts_urls_and_bare_hosts() {
+        assert_eq!(
+            parse_server_addr("http://localhost:3031/").unwrap(),
+            "localhost:3031"
+        );
+        assert_eq!(
+            parse_server_addr("127.0.0.1:4000").unwrap(),
+            "127.0.0.1:4000"
+        );
+        assert_eq!(parse_server_addr("localhost").unwrap(), "localhost:3030");
+        assert!(parse_server_addr("https://example.com").is_err());
+    }
+
+    #[test]
+    fn looks_like_url_rejects_file_names() {
+        assert!(looks_like_url("http://localhost:3031"));
+        assert!(looks_like_url("127.0.0.1:4000"));
+        assert!(looks_like_url("localhost:3030"));
+        assert!(!looks_like_url("notes:v2.md"));
+        assert!(!looks_like_url("C:\\docs\\notes.md"));
+        assert!(!looks_like_url("./a:1"));
+        assert!(!looks_like_url("README.md"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn kill_spawned_stops_registered_children() {
+        let child = Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .expect("spawn sleep");
+        let pid = child.id();
+        SPAWNED.lock().unwrap().push(child);
+
+        kill_spawned();
+
+        assert!(!SPAWNED.lock().unwrap().iter().any(|c| c.id() == pid));
+        // Reaped, so the pid no longer names a process of ours.
+        let alive = unsafe { libc::kill(pid as libc::pid_t, 0) } == 0;
+        assert!(!alive, "child {pid} is still running");
+    }
+}
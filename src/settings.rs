@@ -0,0 +1,398 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+pub const PROJECT_FILE: &str = ".mdr.toml";
+
+/// Defaults read from the user config (`$XDG_CONFIG_HOME/mdr/config.toml`) and
+/// the nearest `.mdr.toml` at or above the input file; project values win.
+/// Settings that run commands are only taken from the user config, since a
+/// project file arrives with whatever repository was checked out.
+/// Command-line flags override both.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub serve: ServeSettings,
+    pub document: DocumentSettings,
+    pub check: CheckSettings,
+    pub lint: LintSettings,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServeSettings {
+    /// Open the preview in a browser once the server is up.
+    pub open: Option<bool>,
+    /// Browser command; `%s` is replaced by the URL, otherwise it is appended.
+    pub browser: Option<String>,
+    /// Check links after every build and list broken ones over the preview.
+    #[serde(rename = "check-links")]
+    pub check_links: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CheckSettings {
+    /// Let `mdr check` fetch external URLs (`--offline` still wins). User
+    /// config only, like `url_command`.
+    pub external: Option<bool>,
+    /// Command that fetches one URL and fails when it is broken; `%s` is
+    /// replaced by the URL, otherwise it is appended.
+    pub url_command: Option<String>,
+}
+
+/// How `mdr lint` treats each rule, plus the rules' own knobs.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LintSettings {
+    pub unclosed_math: Option<Severity>,
+    pub long_sidenote: Option<Severity>,
+    pub margin_marker: Option<Severity>,
+    pub heading_skip: Option<Severity>,
+    pub trailing_whitespace: Option<Severity>,
+    /// Longest footnote, in words, that still reads well as a sidenote.
+    pub max_sidenote_words: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Off,
+    /// Reported; fails `mdr lint` only with `--strict`.
+    #[serde(alias = "warn")]
+    Warning,
+    /// Reported and fails `mdr lint`.
+    Error,
+}
+
+impl Severity {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Severity::Off => "off",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DocumentSettings {
+    /// Where the page title comes from when front matter has none.
+    pub title_from: Option<TitleMode>,
+    /// Render a table of contents (on unless turned off).
+    pub toc: Option<bool>,
+    /// Deepest heading level listed in the table of contents.
+    pub toc_depth: Option<u8>,
+    pub toc_placement: Option<TocPlacement>,
+    /// Number headings (and their table of contents entries).
+    pub number_sections: Option<bool>,
+    /// How heading IDs are derived from heading text.
+    pub slugs: Option<SlugStyle>,
+    /// Render diagram blocks with locally installed tools. User config only,
+    /// since it runs programs on whatever a document contains.
+    pub diagrams: Option<bool>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TitleMode {
+    /// Promote the first level-1 heading, removing it from the body.
+    Heading,
+    /// Use the file name (`README` for `README.md`).
+    #[default]
+    Filename,
+    /// No title header at all; the file name only names the browser tab.
+    None,
+}
+
+impl TitleMode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "heading" => Some(TitleMode::Heading),
+            "filename" => Some(TitleMode::Filename),
+            "none" => Some(TitleMode::None),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TocPlacement {
+    /// A sticky sidebar on wide screens, collapsed above the text otherwise.
+    #[default]
+    Sidebar,
+    /// Expanded above the text.
+    Top,
+    /// Collapsed above the text behind a toggle.
+    Collapsible,
+}
+
+impl TocPlacement {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "sidebar" => Some(TocPlacement::Sidebar),
+            "top" => Some(TocPlacement::Top),
+            "collapsible" => Some(TocPlacement::Collapsible),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TocPlacement::Sidebar => "sidebar",
+            TocPlacement::Top => "top",
+            TocPlacement::Collapsible => "collapsible",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SlugStyle {
+    /// Pandoc's own identifiers.
+    #[default]
+    Pandoc,
+    /// The anchors GitHub generates, so links written against a repository's
+    /// rendered README keep working.
+    Github,
+}
+
+impl SlugStyle {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "pandoc" => Some(SlugStyle::Pandoc),
+            "github" => Some(SlugStyle::Github),
+            _ => None,
+        }
+    }
+}
+
+impl Settings {
+    /// Load and merge every config file that applies to `input`.
+    pub fn load(input: &Path) -> Result<Settings, String> {
+        Settings::load_from(
+            user_config_path().as_deref(),
+            project_config_path(input).as_deref(),
+        )
+    }
+
+    fn load_from(user: Option<&Path>, project: Option<&Path>) -> Result<Settings, String> {
+        let mut settings = Settings::default();
+        if let Some(parsed) = user.map(read_file).transpose()?.flatten() {
+            settings.merge(parsed);
+        }
+        if let Some(path) = project {
+            if let Some(mut parsed) = read_file(path)? {
+                for key in parsed.drop_user_only() {
+                    warn!(
+                        "{}: ignoring `{key}`; it is only read from the user config",
+                        path.display()
+                    );
+                }
+                settings.merge(parsed);
+            }
+        }
+        Ok(settings)
+    }
+
+    /// Clear the settings a project file may not set, returning their keys.
+    fn drop_user_only(&mut self) -> Vec<&'static str> {
+        let mut dropped = Vec::new();
+        if self.serve.open.take().is_some() {
+            dropped.push("serve.open");
+        }
+        if self.serve.browser.take().is_some() {
+            dropped.push("serve.browser");
+        }
+        if self.check.external.take().is_some() {
+            dropped.push("check.external");
+        }
+        if self.check.url_command.take().is_some() {
+            dropped.push("check.url-command");
+        }
+        if self.document.diagrams.take().is_some() {
+            dropped.push("document.diagrams");
+        }
+        dropped
+    }
+
+    fn merge(&mut self, other: Settings) {
+        let serve = other.serve;
+        self.serve.open = serve.open.or(self.serve.open);
+        self.serve.browser = serve.browser.or(self.serve.browser.take());
+        self.serve.check_links = serve.check_links.or(self.serve.check_links);
+        let check = other.check;
+        self.check.external = check.external.or(self.check.external);
+        self.check.url_command = check.url_command.or(self.check.url_command.take());
+        let lint = other.lint;
+        self.lint.unclosed_math = lint.unclosed_math.or(self.lint.unclosed_math);
+        self.lint.long_sidenote = lint.long_sidenote.or(self.lint.long_sidenote);
+        self.lint.margin_marker = lint.margin_marker.or(self.lint.margin_marker);
+        self.lint.heading_skip = lint.heading_skip.or(self.lint.heading_skip);
+        self.lint.trailing_whitespace = lint.trailing_whitespace.or(self.lint.trailing_whitespace);
+        self.lint.max_sidenote_words = lint.max_sidenote_words.or(self.lint.max_sidenote_words);
+        let document = other.document;
+        self.document.title_from = document.title_from.or(self.document.title_from);
+        self.document.toc = document.toc.or(self.document.toc);
+        self.document.toc_depth = document.toc_depth.or(self.document.toc_depth);
+        self.document.toc_placement = document.toc_placement.or(self.document.toc_placement);
+        self.document.number_sections = document.number_sections.or(self.document.number_sections);
+        self.document.slugs = document.slugs.or(self.document.slugs);
+        self.document.diagrams = document.diagrams.or(self.document.diagrams);
+    }
+}
+
+fn read_file(path: &Path) -> Result<Option<Settings>, String> {
+    match fs::read_to_string(path) {
+        Ok(text) => toml::from_str(&text)
+            .map(Some)
+            .map_err(|err| format!("{}: {}", path.display(), err.message())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(format!("{}: {err}", path.display())),
+    }
+}
+
+fn user_config_path() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("mdr").join("config.toml"))
+}
+
+fn project_config_path(input: &Path) -> Option<PathBuf> {
+    let start = input
+        .canonicalize()
+        .ok()
+        .and_then(|p| p.parent().map(Path::to_path_buf))?;
+    start
+        .ancestors()
+        .map(|dir| dir.join(PROJECT_FILE))
+        .find(|candidate| candidate.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn project_values_override_user_values() {
+        let mut settings: Settings =
+            toml::from_str("[serve]\nopen = true\nbrowser = \"firefox\"\n").unwrap();
+        settings.merge(toml::from_str("[serve]\nopen = false\n").unwrap());
+
+        assert_eq!(settings.serve.open, Some(false));
+        assert_eq!(settings.serve.browser.as_deref(), Some("firefox"));
+
+        settings.merge(toml::from_str("[document]\ntitle-from = \"heading\"\n").unwrap());
+        assert_eq!(settings.document.title_from, Some(TitleMode::Heading));
+
+        let document: Settings =
+            toml::from_str("[document]\ntoc-depth = 4\ntoc-placement = \"collapsible\"\n").unwrap();
+        settings.merge(document);
+        assert_eq!(settings.document.toc_depth, Some(4));
+        assert_eq!(
+            settings.document.toc_placement,
+            Some(TocPlacement::Collapsible)
+        );
+        assert_eq!(settings.document.title_from, Some(TitleMode::Heading));
+        assert_eq!(settings.serve.open, Some(false));
+
+        settings.merge(toml::from_str("[lint]\nheading-skip = \"error\"\n").unwrap());
+        settings.merge(toml::from_str("[lint]\ntrailing-whitespace = \"warn\"\n").unwrap());
+        assert_eq!(settings.lint.heading_skip, Some(Severity::Error));
+        assert_eq!(settings.lint.trailing_whitespace, Some(Severity::Warning));
+    }
+
+    #[test]
+    fn project_file_cannot_choose_the_browser() {
+        let root = tempfile::tempdir().unwrap();
+        let user = root.path().join("config.toml");
+        let project = root.path().join(PROJECT_FILE);
+        fs::write(
+            &project,
+            "[serve]\nopen = true\nbrowser = \"sh -c 'touch pwned'\"\ncheck-links = true\n",
+        )
+        .unwrap();
+
+        let settings = Settings::load_from(Some(&user), Some(&project)).unwrap();
+        assert_eq!(settings.serve.open, None);
+        assert_eq!(settings.serve.browser, None);
+        assert_eq!(settings.serve.check_links, Some(true));
+
+        fs::write(&user, "[serve]\nopen = true\nbrowser = \"firefox\"\n").unwrap();
+        let settings = Settings::load_from(Some(&user), Some(&project)).unwrap();
+        assert_eq!(settings.serve.open, Some(true));
+        assert_eq!(settings.serve.browser.as_deref(), Some("firefox"));
+    }
+
+    #[test]
+    fn project_file_cannot_enable_url_commands() {
+        let root = tempfile::tempdir().unwrap();
+        let project = root.path().join(PROJECT_FILE);
+        fs::write(
+            &project,
+            "[check]\nexternal = true\nurl-command = \"touch pwned\"\n",
+        )
+        .unwrap();
+
+        let settings = Settings::load_from(None, Some(&project)).unwrap();
+        assert_eq!(settings.check, CheckSettings::default());
+    }
+
+    #[test]
+    fn project_file_cannot_enable_diagrams() {
+        let root = tempfile::tempdir().unwrap();
+        let user = root.path().join("config.toml");
+        let project = root.path().join(PROJECT_FILE);
+        fs::write(
+            &project,
+            "[document]
+diagrams = true
+toc = false
+",
+        )
+        .unwrap();
+
+        let settings = Settings::load_from(Some(&user), Some(&project)).unwrap();
+        assert_eq!(settings.document.diagrams, None);
+        assert_eq!(settings.document.toc, Some(false));
+
+        fs::write(
+            &user,
+            "[document]
+diagrams = true
+",
+        )
+        .unwrap();
+        let settings = Settings::load_from(Some(&user), Some(&project)).unwrap();
+        assert_eq!(settings.document.diagrams, Some(true));
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let err = toml::from_str::<Settings>("[serve]\nopne = true\n").unwrap_err();
+        assert!(err.message().contains("unknown field"), "{err}");
+    }
+
+    #[test]
+    fn nearest_project_file_is_found() {
+        let root = tempfile::tempdir().unwrap();
+        let nested = root.path().join("docs/notes");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(root.path().join(PROJECT_FILE), "[serve]\nopen = true\n").unwrap();
+        let input = nested.join("note.md");
+        fs::write(&input, "# Note").unwrap();
+
+        let found = project_config_path(&input).expect("project config");
+        assert_eq!(
+            found,
+            root.path().canonicalize().unwrap().join(PROJECT_FILE)
+        );
+    }
+}
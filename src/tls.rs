@@ -0,0 +1,193 @@
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, BufReader};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::Router;
+use tokio::net::TcpListener;
+use tokio::select;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+use crate::connection::{self, Connections};
+use crate::lan;
+
+// A client that stalls mid-handshake must not hold its task open forever.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Build a TLS config from PEM files: `cert` may hold a full chain, `key` the
+/// first PKCS#1, PKCS#8, or SEC1 private key found.
+pub fn load(cert: &Path, key: &Path) -> io::Result<Arc<ServerConfig>> {
+    let certs =
+        rustls_pemfile::certs(&mut BufReader::new(open(cert)?)).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(invalid(format!(
+            "no certificates found in {}",
+            cert.display()
+        )));
+    }
+
+    let key = rustls_pemfile::private_key(&mut BufReader::new(open(key)?))?
+        .ok_or_else(|| invalid(format!("no private key found in {}", key.display())))?;
+
+    server_config(certs, key)
+}
+
+/// Generate a throwaway self-signed certificate valid for `localhost`, the
+/// loopback addresses, and `host` — or, when `host` is unspecified, every
+/// address other devices can reach it on.
+pub fn self_signed(host: &str) -> io::Result<Arc<ServerConfig>> {
+    let mut names = vec![
+        "localhost".to_string(),
+        "127.0.0.1".to_string(),
+        "::1".to_string(),
+    ];
+    let extra = match host.parse::<IpAddr>() {
+        Ok(ip) => lan::reachable_addrs(ip)
+            .iter()
+            .map(IpAddr::to_string)
+            .collect(),
+        Err(_) => vec![host.to_string()],
+    };
+    for name in extra {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+
+    let cert = rcgen::generate_simple_self_signed(names).map_err(io::Error::other)?;
+    let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+    server_config(vec![cert.cert.der().clone()], key.into())
+}
+
+fn server_config(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> io::Result<Arc<ServerConfig>> {
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| invalid(err.to_string()))?;
+    // WebSocket upgrades need HTTP/1.1.
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    File::open(path).map_err(|err| io::Error::new(err.kind(), format!("{}: {err}", path.display())))
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Accept TLS connections until `shutdown` resolves, then wait for open
+/// connections to finish their requests. Live reload handlers watch the same
+/// shutdown signal, so they wind down on their own.
+pub async fn serve<F>(
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+    app: Router,
+    shutdown: F,
+) -> io::Result<()>
+where
+    F: Future<Output = ()>,
+{
+    let acceptor = TlsAcceptor::from(config);
+    let mut connections = Connections::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        let (stream, peer) = select! {
+            accepted = listener.accept() => match accepted {
+                Ok(conn) => conn,
+                Err(err) => {
+                    // Usually fd exhaustion; back off instead of spinning.
+                    debug!("accept failed: {err}");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            _ = connections.reap() => continue,
+            _ = &mut shutdown => break,
+        };
+
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        connections.spawn(|mut closing| async move {
+            let handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream));
+            let stream = select! {
+                result = handshake => match result {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(err)) => {
+                        debug!("TLS handshake with {peer} failed: {err}");
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("TLS handshake with {peer} timed out");
+                        return;
+                    }
+                },
+                // Nothing has been served yet, so shutdown need not wait.
+                _ = closing.wait_for(|closing| *closing) => return,
+            };
+            connection::serve(stream, app, peer, closing).await;
+        });
+    }
+
+    connections.drain().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn self_signed_config_speaks_http1() {
+        let config = self_signed("0.0.0.0").expect("generate certificate");
+        assert_eq!(config.alpn_protocols, vec![b"http/1.1".to_vec()]);
+    }
+
+    #[test]
+    fn load_rejects_files_without_certificates() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = dir.path().join("cert.pem");
+        let key = dir.path().join("key.pem");
+        std::fs::write(&cert, "not a certificate\n").unwrap();
+        std::fs::write(&key, "not a key\n").unwrap();
+
+        let err = load(&cert, &key).expect_err("empty PEM should fail");
+
+        assert!(err.to_string().contains("no certificates found"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn shutdown_does_not_wait_for_stalled_handshakes() {
+        let config = self_signed("127.0.0.1").expect("generate certificate");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, config, Router::new(), async {
+            let _ = stop_rx.await;
+        }));
+
+        // Connects but never sends a ClientHello.
+        let _idle = tokio::net::TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let _ = stop_tx.send(());
+
+        tokio::time::timeout(Duration::from_secs(2), server)
+            .await
+            .expect("serve returns promptly")
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,160 @@
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use axum::Router;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::select;
+
+use crate::connection::{self, Connections};
+
+/// A listening Unix domain socket; the socket file is removed on drop.
+pub struct SocketListener {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl SocketListener {
+    /// Bind `path`, replacing a stale socket left by a process that died
+    /// without cleaning up. A live socket or any other file is an error.
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        if let Ok(meta) = fs::symlink_metadata(path) {
+            if !meta.file_type().is_socket() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display()),
+                ));
+            }
+            if StdUnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is in use by another server", path.display()),
+                ));
+            }
+            fs::remove_file(path)?;
+        }
+
+        // Other users on a shared host should not be able to connect. `bind`
+        // creates the file with umask permissions, so it is bound inside a
+        // private directory, tightened, and only then moved into place.
+        let staging = private_dir_beside(path)?;
+        let staged = staging.join("socket");
+        let bound = UnixListener::bind(&staged)
+            .and_then(|listener| {
+                fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+                fs::rename(&staged, path)?;
+                Ok(listener)
+            })
+            .map(|listener| SocketListener {
+                listener,
+                path: path.to_path_buf(),
+            });
+        let _ = fs::remove_file(&staged);
+        let _ = fs::remove_dir(&staging);
+        bound
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Wait for the next connection, retrying after transient accept errors.
+    pub async fn accept(&self) -> UnixStream {
+        loop {
+            match self.listener.accept().await {
+                Ok((stream, _)) => return stream,
+                Err(err) => {
+                    debug!("accept failed: {err}");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    }
+
+    /// Accept connections until `shutdown` resolves, then wait for open
+    /// connections to finish their requests.
+    pub async fn serve<F>(&self, app: Router, shutdown: F) -> io::Result<()>
+    where
+        F: Future<Output = ()>,
+    {
+        let mut connections = Connections::new();
+        tokio::pin!(shutdown);
+        loop {
+            let stream = select! {
+                stream = self.accept() => stream,
+                _ = connections.reap() => continue,
+                _ = &mut shutdown => break,
+            };
+
+            let app = app.clone();
+            let peer = self.path.display().to_string();
+            connections.spawn(|closing| connection::serve(stream, app, peer, closing));
+        }
+
+        connections.drain().await;
+        Ok(())
+    }
+}
+
+impl Drop for SocketListener {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.path) {
+            if err.kind() != io::ErrorKind::NotFound {
+                warn!("unable to remove socket {}: {err}", self.path.display());
+            }
+        }
+    }
+}
+
+// A fresh `0700` directory next to `path`, on the same filesystem so the
+// socket can be renamed out of it.
+fn private_dir_beside(path: &Path) -> io::Result<PathBuf> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let dir = parent.join(format!(".{name}.{}.tmp", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    Ok(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn bind_replaces_stale_socket_and_cleans_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mdr.sock");
+
+        // A socket file nobody listens on any more.
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let listener = SocketListener::bind(&path).expect("stale socket is replaced");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let entries = fs::read_dir(dir.path()).unwrap().count();
+        assert_eq!(entries, 1, "the staging directory is removed");
+        assert!(SocketListener::bind(&path).is_err(), "live socket is kept");
+
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn bind_refuses_regular_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.md");
+        fs::write(&path, "# keep me").unwrap();
+
+        assert!(SocketListener::bind(&path).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "# keep me");
+    }
+}
@@ -4,7 +4,7 @@ use std::fs;
 use std::io::{Read, Write};
 use std::net::TcpStream;
 use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process;
 use std::thread;
 use std::time::{Duration, Instant};
@@ -21,7 +21,7 @@ fn katex_fixture_url() -> String {
     )
 }
 
-fn make_fake_pandoc(dir: &PathBuf) -> PathBuf {
+fn make_fake_pandoc(dir: &Path) -> PathBuf {
     let fake = dir.join("pandoc");
     let mut file = fs::File::create(&fake).expect("create fake pandoc");
     writeln!(
@@ -37,8 +37,11 @@ while [ $# -gt 0 ]; do
   if [ "$1" = "--output" ]; then shift; out="$1"; fi
   shift
 done
-[ -z "$out" ] && {{ echo "no --output given" >&2; exit 1; }}
-printf "<!--ARGS:%s-->\n<html>fake</html>\n" "$all" > "$out"
+if [ -z "$out" ]; then
+  printf "<!--ARGS:%s-->\n<html>fake</html>\n" "$all"
+else
+  printf "<!--ARGS:%s-->\n<html>fake</html>\n" "$all" > "$out"
+fi
 exit 0
 "#
     )
@@ -113,22 +116,24 @@ fn output_flag_without_value_uses_default_path() {
     assert!(fs::metadata(&fake).unwrap().is_file());
 }
 
-fn ensure_real_pandoc_available() {
-    let status = process::Command::new("pandoc")
+fn real_pandoc_available(test: &str) -> bool {
+    let found = process::Command::new("pandoc")
         .arg("--version")
         .stdout(std::process::Stdio::null())
         .stderr(std::process::Stdio::null())
         .status()
-        .expect("failed to spawn pandoc check");
-    assert!(
-        status.success(),
-        "pandoc not found on PATH; install pandoc to run integration embedding test"
-    );
+        .is_ok_and(|status| status.success());
+    if !found {
+        eprintln!("skipping {test}: pandoc not found on PATH");
+    }
+    found
 }
 
 #[test]
 fn real_pandoc_embeds_assets_and_template() {
-    ensure_real_pandoc_available();
+    if !real_pandoc_available("real_pandoc_embeds_assets_and_template") {
+        return;
+    }
 
     let tmp = tempdir().unwrap();
     let dir = tmp.path();
@@ -169,6 +174,112 @@ fn real_pandoc_embeds_assets_and_template() {
     );
 }
 
+// Render `markdown` with the real pandoc and return the HTML.
+fn render_with_real_pandoc(dir: &Path, markdown: &str) -> String {
+    let input = dir.join("doc.md");
+    let output = dir.join("doc.html");
+    fs::write(&input, markdown).unwrap();
+    Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("-o")
+        .arg(&output)
+        .arg(&input)
+        .env("MDR_KATEX", katex_fixture_url())
+        .assert()
+        .success();
+    fs::read_to_string(&output).unwrap()
+}
+
+#[test]
+fn real_pandoc_renders_callouts() {
+    if !real_pandoc_available("real_pandoc_renders_callouts") {
+        return;
+    }
+
+    let tmp = tempdir().unwrap();
+    let html = render_with_real_pandoc(
+        tmp.path(),
+        "> [!WARNING]\n> GitHub-style alert.\n\n\
+         ::: {#faster .tip .wide title=\"Fenced div\" data-x=\"1\"}\nA tip.\n:::\n",
+    );
+
+    assert!(
+        html.contains("class=\"callout callout-warning\" role=\"note\"")
+            && html.contains("class=\"callout callout-tip wide\""),
+        "callout markup missing; lua filter likely not applied"
+    );
+    assert!(
+        html.contains("id=\"faster\"") && html.contains("data-x=\"1\""),
+        "fenced div identifier and attributes dropped"
+    );
+    assert!(html.contains("Fenced div"), "callout title missing");
+}
+
+#[test]
+fn real_pandoc_numbers_crossrefs() {
+    if !real_pandoc_available("real_pandoc_numbers_crossrefs") {
+        return;
+    }
+
+    let tmp = tempdir().unwrap();
+    fs::write(tmp.path().join("plot.png"), []).unwrap();
+    let html = render_with_real_pandoc(
+        tmp.path(),
+        "![A plot](plot.png){#fig:plot .margin}\n\n\
+         | A | B |\n|---|---|\n| 1 | 2 |\n\nTable: Values {#tbl:values}\n\n\
+         See @fig:plot and -@tbl:values.\n",
+    );
+
+    assert!(
+        html.contains("class=\"marginfigure\"")
+            && html.matches("<span class=\"caption-label\">").count() == 2,
+        "figure and table not numbered, or the figure not in the margin"
+    );
+    assert!(
+        html.contains("<a href=\"#fig:plot\" class=\"cross-ref\">Figure")
+            && html.contains("<a href=\"#tbl:values\" class=\"cross-ref\">1</a>"),
+        "references not resolved"
+    );
+}
+
+#[test]
+fn real_pandoc_reports_unknown_crossrefs() {
+    if !real_pandoc_available("real_pandoc_reports_unknown_crossrefs") {
+        return;
+    }
+
+    let tmp = tempdir().unwrap();
+    let input = tmp.path().join("doc.md");
+    fs::write(&input, "# Doc\n\nSee @fig:missing.\n").unwrap();
+
+    Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("-o")
+        .arg(tmp.path().join("doc.html"))
+        .arg(&input)
+        .env("MDR_KATEX", katex_fixture_url())
+        .assert()
+        .success()
+        .stderr(contains("unknown cross-reference @fig:missing"));
+    let html = fs::read_to_string(tmp.path().join("doc.html")).unwrap();
+    assert!(
+        html.contains("class=\"cross-ref cross-ref-missing\""),
+        "unknown reference not marked"
+    );
+
+    let check = Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("check")
+        .arg(&input)
+        .env("MDR_KATEX", katex_fixture_url())
+        .assert()
+        .code(1);
+    assert_eq!(
+        String::from_utf8_lossy(&check.get_output().stdout),
+        format!(
+            "{}:3: unknown cross-reference @fig:missing\n",
+            input.display()
+        )
+    );
+}
+
 #[test]
 fn errors_when_pandoc_missing() {
     let tmp = tempdir().unwrap();
@@ -364,6 +475,215 @@ fn preserves_existing_title_metadata() {
     assert!(html.contains("fake</html>"));
 }
 
+// Like `make_fake_pandoc`, but also echoes the document it got on stdin.
+fn make_echoing_fake_pandoc(dir: &Path) {
+    let fake = dir.join("pandoc");
+    fs::write(
+        &fake,
+        "#!/bin/sh\n\
+         [ \"$1\" = \"--version\" ] && exit 0\n\
+         all=\"$*\"\n\
+         while [ $# -gt 0 ]; do [ \"$1\" = \"--output\" ] && out=\"$2\"; shift; done\n\
+         { printf '<!--ARGS:%s-->\\n' \"$all\"; cat; } > \"$out\"\n",
+    )
+    .unwrap();
+    fs::set_permissions(&fake, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+#[test]
+fn toml_front_matter_is_passed_to_pandoc_as_yaml() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    make_echoing_fake_pandoc(&dir);
+
+    let input = dir.join("page.md");
+    fs::write(
+        &input,
+        "\u{feff}+++\ntitle = \"Hugo Page\"\ntheme = \"dark\"\n[author]\ntitle = \"Dr\"\n+++\n\n# Body\n",
+    )
+    .unwrap();
+    let output = dir.join("page.html");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("-o")
+        .arg(&output)
+        .arg(&input)
+        .env("MDR_KATEX", katex_fixture_url())
+        .env(
+            "PATH",
+            format!(
+                "{}:{}",
+                dir.display(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        )
+        .assert()
+        .success();
+
+    let html = fs::read_to_string(&output).unwrap();
+    assert!(!html.contains("--metadata title="), "{html}");
+    assert!(html.contains("--variable theme=dark"), "{html}");
+    assert!(
+        !html.contains("page.md"),
+        "document goes through stdin: {html}"
+    );
+    assert!(html.contains("\n---\n{"), "{html}");
+    assert!(html.contains(r#""title":"Hugo Page""#), "{html}");
+    assert!(!html.contains("+++"), "{html}");
+    assert!(html.ends_with("---\n\n# Body\n"), "{html}");
+}
+
+#[test]
+fn title_can_come_from_the_first_heading_or_be_left_out() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    make_echoing_fake_pandoc(&dir);
+    let input = dir.join("README.md");
+    fs::write(&input, "# Project Name\n\nIntro.\n").unwrap();
+    let output = dir.join("README.html");
+    let path = format!(
+        "{}:{}",
+        dir.display(),
+        std::env::var("PATH").unwrap_or_default()
+    );
+
+    Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .args(["--title-from", "heading", "-o"])
+        .arg(&output)
+        .arg(&input)
+        .env("PATH", &path)
+        .assert()
+        .success();
+    let html = fs::read_to_string(&output).unwrap();
+    assert!(!html.contains("--metadata title="), "{html}");
+    assert!(html.contains(r#"{"title":"Project Name"}"#), "{html}");
+    assert!(!html.contains("# Project Name"), "{html}");
+    assert!(html.ends_with("---\n\nIntro.\n"), "{html}");
+
+    fs::write(dir.join(".mdr.toml"), "[document]\ntitle-from = \"none\"\n").unwrap();
+    Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("-o")
+        .arg(&output)
+        .arg(&input)
+        .env("PATH", &path)
+        .assert()
+        .success();
+    let html = fs::read_to_string(&output).unwrap();
+    assert!(html.contains("--metadata pagetitle=README"), "{html}");
+    assert!(html.contains("--variable hide-header"), "{html}");
+    assert!(
+        html.ends_with("README.md-->\n"),
+        "pandoc reads the file: {html}"
+    );
+
+    Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .args(["--title-from", "h1", "-o"])
+        .arg(&output)
+        .arg(&input)
+        .env("PATH", &path)
+        .assert()
+        .code(64);
+}
+
+#[test]
+fn toc_can_be_configured_or_turned_off() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let _fake = make_fake_pandoc(&dir);
+    let input = dir.join("spec.md");
+    fs::write(&input, "# Spec\n\n## Part\n").unwrap();
+    let output = dir.join("spec.html");
+    let path = format!(
+        "{}:{}",
+        dir.display(),
+        std::env::var("PATH").unwrap_or_default()
+    );
+
+    Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .args([
+            "--toc-depth",
+            "4",
+            "--toc-placement",
+            "top",
+            "--number-sections",
+            "-o",
+        ])
+        .arg(&output)
+        .arg(&input)
+        .env("PATH", &path)
+        .assert()
+        .success();
+    let html = fs::read_to_string(&output).unwrap();
+    assert!(
+        html.contains("--toc --variable toc-placement=top"),
+        "{html}"
+    );
+    assert!(html.contains("--toc-depth=4"), "{html}");
+    assert!(html.contains("--number-sections"), "{html}");
+
+    fs::write(dir.join(".mdr.toml"), "[document]\ntoc = false\n").unwrap();
+    Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("-o")
+        .arg(&output)
+        .arg(&input)
+        .env("PATH", &path)
+        .assert()
+        .success();
+    let html = fs::read_to_string(&output).unwrap();
+    assert!(!html.contains("--toc"), "{html}");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .args(["--toc-depth", "9", "-o"])
+        .arg(&output)
+        .arg(&input)
+        .env("PATH", &path)
+        .assert()
+        .code(64);
+}
+
+#[test]
+fn github_slugs_and_duplicate_heading_warnings() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    // Renders two sections pandoc could only tell apart by position.
+    let fake = dir.join("pandoc");
+    fs::write(
+        &fake,
+        "#!/bin/sh\n\
+         [ \"$1\" = \"--version\" ] && exit 0\n\
+         all=\"$*\"\n\
+         while [ $# -gt 0 ]; do [ \"$1\" = \"--output\" ] && out=\"$2\"; shift; done\n\
+         printf '<!--ARGS:%s-->\\n<h2 id=\"setup\">Setup</h2>\\n<h2 id=\"setup-1\">Setup</h2>\\n' \"$all\" > \"$out\"\n",
+    )
+    .unwrap();
+    fs::set_permissions(&fake, fs::Permissions::from_mode(0o755)).unwrap();
+    let input = dir.join("guide.md");
+    fs::write(&input, "## Setup\n\n## Setup\n").unwrap();
+    let output = dir.join("guide.html");
+
+    Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .args(["--slugs", "github", "-o"])
+        .arg(&output)
+        .arg(&input)
+        .env(
+            "PATH",
+            format!(
+                "{}:{}",
+                dir.display(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        )
+        .assert()
+        .success()
+        .stderr(contains("duplicate heading \"Setup\": #setup-1"));
+
+    let html = fs::read_to_string(&output).unwrap();
+    assert!(
+        html.contains("--from markdown+tex_math_single_backslash+gfm_auto_identifiers"),
+        "{html}"
+    );
+}
+
 #[test]
 fn watch_rebuilds_on_change() {
     let tmp = tempdir().unwrap();
@@ -493,3 +813,1905 @@ fn serves_html_over_http() {
     assert!(resp.contains("200 OK"));
     assert!(fs::metadata(&fake).unwrap().is_file());
 }
+
+fn send_signal(child: &process::Child, signal: &str) {
+    let status = process::Command::new("kill")
+        .arg(format!("-{signal}"))
+        .arg(child.id().to_string())
+        .status()
+        .expect("run kill");
+    assert!(status.success(), "kill -{signal} failed");
+}
+
+#[test]
+fn sigterm_in_watch_mode_removes_temp_dir() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let _fake = make_fake_pandoc(&dir);
+    let scratch = tempdir().unwrap();
+
+    let input = dir.join("note.md");
+    fs::write(&input, "# Title\n\nBody").unwrap();
+    let output = dir.join("note.html");
+
+    let mut child = process::Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("--watch")
+        .arg("-o")
+        .arg(&output)
+        .arg(&input)
+        .env("MDR_KATEX", katex_fixture_url())
+        .env("TMPDIR", scratch.path())
+        .env(
+            "PATH",
+            format!(
+                "{}:{}",
+                dir.display(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        )
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .spawn()
+        .expect("spawn watcher");
+
+    let temp_root = scratch.path().join(format!("mdr-{}", child.id()));
+    wait_until(Duration::from_secs(5), || output.exists().then_some(()))
+        .expect("initial build did not finish in time");
+    assert!(temp_root.is_dir(), "temp dir should exist while running");
+
+    send_signal(&child, "TERM");
+    let status = wait_until(Duration::from_secs(5), || child.try_wait().ok().flatten());
+    if status.is_none() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+
+    let status = status.expect("mdr did not exit after SIGTERM");
+    assert!(status.success(), "expected clean exit, got {status}");
+    assert!(!temp_root.exists(), "temp dir should be removed on SIGTERM");
+}
+
+#[test]
+fn sighup_in_serve_mode_removes_temp_dir() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let _fake = make_fake_pandoc(&dir);
+    let scratch = tempdir().unwrap();
+
+    let input = dir.join("note.md");
+    fs::write(&input, "# Title\n\nBody").unwrap();
+
+    let Some(port) = pick_free_port() else {
+        eprintln!("skipping sighup_in_serve_mode_removes_temp_dir: unable to bind loopback port");
+        return;
+    };
+
+    let mut child = process::Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("--port")
+        .arg(port.to_string())
+        .arg(&input)
+        .env("MDR_KATEX", katex_fixture_url())
+        .env("TMPDIR", scratch.path())
+        .env(
+            "PATH",
+            format!(
+                "{}:{}",
+                dir.display(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        )
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .spawn()
+        .expect("spawn serve mode");
+
+    let temp_root = scratch.path().join(format!("mdr-{}", child.id()));
+    wait_until(Duration::from_secs(5), || {
+        TcpStream::connect(("127.0.0.1", port)).ok()
+    })
+    .expect("server did not start in time");
+    assert!(temp_root.is_dir(), "temp dir should exist while serving");
+
+    send_signal(&child, "HUP");
+    let status = wait_until(Duration::from_secs(5), || child.try_wait().ok().flatten());
+    if status.is_none() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+
+    let status = status.expect("mdr did not exit after SIGHUP");
+    assert!(status.success(), "expected clean exit, got {status}");
+    assert!(!temp_root.exists(), "temp dir should be removed on SIGHUP");
+}
+
+#[test]
+fn json_log_format_emits_build_events() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let _fake = make_fake_pandoc(&dir);
+
+    let input = dir.join("note.md");
+    fs::write(&input, "# Title\n\nBody").unwrap();
+    let output = dir.join("note.html");
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("mdr"));
+    cmd.arg("--log-format")
+        .arg("json")
+        .arg("-o")
+        .arg(&output)
+        .arg(&input)
+        .env("MDR_KATEX", katex_fixture_url())
+        .env(
+            "PATH",
+            format!(
+                "{}:{}",
+                dir.display(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        );
+
+    let assert = cmd.assert().success();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+    let finished = stderr
+        .lines()
+        .find(|l| l.contains("\"event\":\"build_finished\""))
+        .expect("build_finished event missing");
+
+    assert!(finished.starts_with('{') && finished.ends_with('}'));
+    assert!(finished.contains("\"duration_ms\":"));
+    assert!(finished.contains("\"level\":\"info\""));
+}
+
+#[test]
+fn json_log_format_emits_lifecycle_events_without_verbose() {
+    use std::io::BufRead;
+    use std::sync::mpsc;
+
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let _fake = make_fake_pandoc(&dir);
+
+    let input = dir.join("note.md");
+    fs::write(&input, "# Title\n\nBody").unwrap();
+
+    let Some(port) = pick_free_port() else {
+        eprintln!(
+            "skipping json_log_format_emits_lifecycle_events_without_verbose: unable to bind loopback port"
+        );
+        return;
+    };
+
+    let mut child = process::Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("--port")
+        .arg(port.to_string())
+        .arg("--log-format")
+        .arg("json")
+        .arg(&input)
+        .env("MDR_KATEX", katex_fixture_url())
+        .env(
+            "PATH",
+            format!(
+                "{}:{}",
+                dir.display(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        )
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .expect("spawn serve mode");
+
+    let (tx, rx) = mpsc::channel();
+    let stderr = std::io::BufReader::new(child.stderr.take().unwrap());
+    thread::spawn(move || {
+        for line in stderr.lines().map_while(Result::ok) {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut seen = Vec::new();
+    let mut wait_for = |event: &str| {
+        let needle = format!("\"event\":\"{event}\"");
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !seen.iter().any(|l: &String| l.contains(&needle)) {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match rx.recv_timeout(left) {
+                Ok(line) => seen.push(line),
+                Err(_) => return false,
+            }
+        }
+        true
+    };
+
+    let listening = wait_for("server_listening");
+    let started = wait_for("build_started");
+
+    let mut sse = TcpStream::connect(("127.0.0.1", port)).expect("connect");
+    sse.write_all(b"GET /events HTTP/1.1\r\nHost: localhost\r\nAccept: text/event-stream\r\n\r\n")
+        .unwrap();
+    let connected = wait_for("client_connected");
+
+    thread::sleep(Duration::from_millis(200));
+    fs::write(&input, "# Title\n\nChanged").unwrap();
+    let changed = wait_for("file_changed");
+
+    let _ = child.kill();
+    let _ = child.wait();
+
+    assert!(listening, "server_listening missing: {seen:?}");
+    assert!(started, "build_started missing: {seen:?}");
+    assert!(connected, "client_connected missing: {seen:?}");
+    assert!(changed, "file_changed missing: {seen:?}");
+}
+
+#[test]
+fn quiet_suppresses_informational_output() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let _fake = make_fake_pandoc(&dir);
+
+    let input = dir.join("note.md");
+    fs::write(&input, "# Title\n\nBody").unwrap();
+    let output = dir.join("note.html");
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("mdr"));
+    cmd.arg("-q")
+        .arg("-o")
+        .arg(&output)
+        .arg(&input)
+        .env("MDR_KATEX", katex_fixture_url())
+        .env(
+            "PATH",
+            format!(
+                "{}:{}",
+                dir.display(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        );
+
+    cmd.assert().success().stderr("");
+}
+
+#[test]
+fn export_ignores_basic_auth_from_the_environment() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let _fake = make_fake_pandoc(&dir);
+
+    let input = dir.join("note.md");
+    fs::write(&input, "# Title\n\nBody").unwrap();
+    let output = dir.join("note.html");
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("mdr"));
+    cmd.arg("-o")
+        .arg(&output)
+        .arg(&input)
+        .env("MDR_BASIC_AUTH", "not-a-credential")
+        .env("MDR_KATEX", katex_fixture_url())
+        .env(
+            "PATH",
+            format!(
+                "{}:{}",
+                dir.display(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        );
+
+    cmd.assert().success();
+    assert!(output.exists());
+}
+
+#[test]
+fn stacked_verbose_flags_enable_trace_output() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let _fake = make_fake_pandoc(&dir);
+
+    let input = dir.join("note.md");
+    fs::write(&input, "# Title\n\nBody").unwrap();
+    let output = dir.join("note.html");
+
+    let mut cmd = Command::new(assert_cmd::cargo::cargo_bin!("mdr"));
+    cmd.arg("-vvv")
+        .arg("-o")
+        .arg(&output)
+        .arg(&input)
+        .env("MDR_KATEX", katex_fixture_url())
+        .env(
+            "PATH",
+            format!(
+                "{}:{}",
+                dir.display(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        );
+
+    cmd.assert().success().stderr(contains("debug: running"));
+}
+
+fn http_request(port: u16, method: &str, path: &str) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).expect("connect to server");
+    write!(
+        stream,
+        "{method} {path} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+    )
+    .unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    resp
+}
+
+#[test]
+fn status_api_reports_builds_and_rebuild_forces_one() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let _fake = make_fake_pandoc(&dir);
+
+    let input = dir.join("note.md");
+    fs::write(&input, "# Title\n\nBody").unwrap();
+
+    let Some(port) = pick_free_port() else {
+        eprintln!("skipping status_api_reports_builds_and_rebuild_forces_one: unable to bind loopback port");
+        return;
+    };
+
+    let mut child = process::Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("--port")
+        .arg(port.to_string())
+        .arg(&input)
+        .env("MDR_KATEX", katex_fixture_url())
+        .env(
+            "PATH",
+            format!(
+                "{}:{}",
+                dir.display(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        )
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .spawn()
+        .expect("spawn serve mode");
+
+    wait_until(Duration::from_secs(5), || {
+        TcpStream::connect(("127.0.0.1", port)).ok()
+    })
+    .expect("server did not start in time");
+
+    let status = http_request(port, "GET", "/api/status");
+    let rebuilt = http_request(port, "POST", "/api/rebuild");
+
+    let _ = child.kill();
+    let _ = child.wait();
+
+    assert!(
+        status.contains("200 OK"),
+        "unexpected status response: {status}"
+    );
+    assert!(status.contains("application/json"));
+    assert!(status.contains("\"success\":true"));
+    assert!(status.contains("\"builds\":1"));
+    assert!(status.contains("note.md"));
+
+    assert!(
+        rebuilt.contains("200 OK"),
+        "unexpected rebuild response: {rebuilt}"
+    );
+    assert!(rebuilt.contains("\"builds\":2"));
+}
+
+// Stands in for pandoc by passing the document (a file argument or stdin)
+// through, so raw HTML in the Markdown is what gets checked.
+fn make_passthrough_pandoc(dir: &Path) {
+    let fake = dir.join("pandoc");
+    fs::write(
+        &fake,
+        "#!/bin/sh\n\
+         [ \"$1\" = \"--version\" ] && exit 0\n\
+         for last; do :; done\n\
+         if [ -f \"$last\" ]; then cat \"$last\"; else cat; fi\n",
+    )
+    .unwrap();
+    fs::set_permissions(&fake, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+#[test]
+fn lint_reports_findings_and_fails_on_errors() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let input = dir.join("doc.md");
+    fs::write(&input, "# Doc\n\n### Deep\n\nSee^[A note.] and \\(x + 1.\n").unwrap();
+
+    let failed = Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .args(["lint"])
+        .arg(&input)
+        .assert()
+        .code(1);
+    let stdout = String::from_utf8_lossy(&failed.get_output().stdout).to_string();
+    assert_eq!(
+        stdout,
+        format!(
+            "{0}:3: warning: heading level jumps from h1 to h3 [heading-skip]\n\
+             {0}:5: error: `\\(` starts inline math that is never closed with `\\)` \
+             [unclosed-math]\n",
+            input.display()
+        )
+    );
+
+    fs::write(dir.join(".mdr.toml"), "[lint]\nunclosed-math = \"warn\"\n").unwrap();
+    Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .args(["lint"])
+        .arg(&input)
+        .assert()
+        .success();
+    Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .args(["lint", "--strict"])
+        .arg(&input)
+        .assert()
+        .code(1);
+
+    fs::write(dir.join(".mdr.toml"), "[lint]\nunclosed-maths = \"off\"\n").unwrap();
+    Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .args(["lint"])
+        .arg(&input)
+        .assert()
+        .code(78);
+}
+
+#[test]
+fn check_reports_broken_links_with_line_numbers() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    make_passthrough_pandoc(&dir);
+    fs::write(dir.join("other.md"), "").unwrap();
+    fs::create_dir_all(dir.join("config/mdr")).unwrap();
+    fs::write(
+        dir.join("config/mdr/config.toml"),
+        "[check]\nurl-command = \"false\"\n",
+    )
+    .unwrap();
+    let input = dir.join("doc.md");
+    fs::write(
+        &input,
+        "<h2 id=\"ok\">OK</h2>\n\n\
+         <a href=\"#ok\">fine</a> <a href=\"other.md\">fine</a>\n\n\
+         <a href=\"#nope\">broken</a>\n\n\
+         <img src=\"missing.png\" />\n\n\
+         <a href=\"https://example.invalid/\">external</a>\n",
+    )
+    .unwrap();
+    let path = format!(
+        "{}:{}",
+        dir.display(),
+        std::env::var("PATH").unwrap_or_default()
+    );
+
+    let offline = Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .args(["check"])
+        .arg(&input)
+        .env("PATH", &path)
+        .env("XDG_CONFIG_HOME", dir.join("config"))
+        .assert()
+        .code(1);
+    let stdout = String::from_utf8_lossy(&offline.get_output().stdout).to_string();
+    assert_eq!(
+        stdout,
+        format!(
+            "{0}:5: no heading or anchor #nope\n{0}:7: image target missing.png does not exist\n",
+            input.display()
+        )
+    );
+
+    Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .args(["check", "--external"])
+        .arg(&input)
+        .env("PATH", &path)
+        .env("XDG_CONFIG_HOME", dir.join("config"))
+        .assert()
+        .code(1)
+        .stdout(contains(":9: link failed"));
+
+    fs::write(
+        &input,
+        "<a href=\"https://example.invalid/\">external</a>\n",
+    )
+    .unwrap();
+    Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .args(["check", "--offline"])
+        .arg(&input)
+        .env("PATH", &path)
+        .env("XDG_CONFIG_HOME", dir.join("config"))
+        .assert()
+        .success()
+        .stdout("");
+}
+
+#[test]
+fn check_ignores_url_command_from_project_config() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    make_passthrough_pandoc(&dir);
+    let marker = dir.join("pwned");
+    fs::write(
+        dir.join(".mdr.toml"),
+        format!(
+            "[check]\nexternal = true\nurl-command = \"touch {}\"\n",
+            marker.display()
+        ),
+    )
+    .unwrap();
+    let input = dir.join("doc.md");
+    fs::write(
+        &input,
+        "<a href=\"https://example.invalid/\">external</a>\n",
+    )
+    .unwrap();
+
+    Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .args(["check"])
+        .arg(&input)
+        .env(
+            "PATH",
+            format!(
+                "{}:{}",
+                dir.display(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        )
+        .env("XDG_CONFIG_HOME", dir.join("config"))
+        .assert()
+        .success()
+        .stdout("")
+        .stderr(contains("ignoring `check.external`"))
+        .stderr(contains("ignoring `check.url-command`"));
+    assert!(!marker.exists(), "project url-command must not run");
+}
+
+#[test]
+fn check_links_lists_broken_links_in_status() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    make_passthrough_pandoc(&dir);
+    let input = dir.join("note.md");
+    fs::write(&input, "<a href=\"#gone\">x</a>\n").unwrap();
+
+    let Some(port) = pick_free_port() else {
+        eprintln!(
+            "skipping check_links_lists_broken_links_in_status: unable to bind loopback port"
+        );
+        return;
+    };
+
+    let mut child = process::Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .args(["--check-links", "--port"])
+        .arg(port.to_string())
+        .arg(&input)
+        .env(
+            "PATH",
+            format!(
+                "{}:{}",
+                dir.display(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        )
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .spawn()
+        .expect("spawn serve mode");
+
+    wait_until(Duration::from_secs(5), || {
+        TcpStream::connect(("127.0.0.1", port)).ok()
+    })
+    .expect("server did not start in time");
+    let status = http_request(port, "GET", "/api/status");
+
+    let _ = child.kill();
+    let _ = child.wait();
+
+    assert!(
+        status.contains(r#""link_problems":["no heading or anchor #gone"]"#),
+        "{status}"
+    );
+}
+
+// Runs what the Lua filter runs for one block: `mdr render-diagram <class>`.
+fn render_diagram(dir: &Path, path: &str, class: &str, source: &str) -> String {
+    let output = Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .args(["render-diagram", class])
+        .env("PATH", path)
+        .env("XDG_CACHE_HOME", dir.join("cache"))
+        .write_stdin(source)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{output:?}");
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn mermaid_blocks_become_inline_svg() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    fs::write(
+        dir.join("mmdc"),
+        "#!/bin/sh\n\
+         input=$(cat)\n\
+         case \"$input\" in *oops*) echo 'Parse error on line 1' >&2; exit 1;; esac\n\
+         printf '<?xml version=\"1.0\"?>\\n<svg id=\"%s\">%s</svg>\\n' \"$*\" \"$input\"\n",
+    )
+    .unwrap();
+    fs::set_permissions(dir.join("mmdc"), fs::Permissions::from_mode(0o755)).unwrap();
+    let path = format!(
+        "{}:{}",
+        dir.display(),
+        std::env::var("PATH").unwrap_or_default()
+    );
+
+    let reply = render_diagram(&dir, &path, "mermaid", "graph TD; A-->B");
+    assert!(
+        reply.starts_with("ok\n<div class=\"diagram mermaid\"><svg id="),
+        "{reply}"
+    );
+    assert!(reply.contains("--svgId mermaid-"), "{reply}");
+    assert!(reply.ends_with(">graph TD; A-->B</svg></div>"), "{reply}");
+    assert!(!reply.contains("<?xml"), "{reply}");
+
+    let reply = render_diagram(&dir, &path, "mermaid", "oops");
+    assert_eq!(reply, "error\nmmdc: Parse error on line 1");
+}
+
+#[test]
+fn graphviz_output_is_cached_and_missing_tools_are_reported() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let runs = dir.join("dot-runs");
+    let version = dir.join("dot-version");
+    fs::write(&version, "dot - graphviz version 2.43.0\n").unwrap();
+    fs::write(
+        dir.join("dot"),
+        format!(
+            "#!/bin/sh\n\
+             [ \"$1\" = -V ] && {{ cat {} >&2; exit 0; }}\n\
+             echo run >> {}\n\
+             printf '<svg class=\"dot\">%s</svg>' \"$(cat)\"\n",
+            version.display(),
+            runs.display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(dir.join("dot"), fs::Permissions::from_mode(0o755)).unwrap();
+    // Only the fakes and `cat`, so a real plantuml cannot be found.
+    let cat = String::from_utf8(
+        process::Command::new("sh")
+            .args(["-c", "command -v cat"])
+            .output()
+            .unwrap()
+            .stdout,
+    )
+    .unwrap();
+    std::os::unix::fs::symlink(cat.trim(), dir.join("cat")).unwrap();
+    let path = dir.display().to_string();
+
+    for _ in 0..2 {
+        assert_eq!(
+            render_diagram(&dir, &path, "dot", "digraph { a -> b }"),
+            "ok\n<div class=\"diagram graphviz\"><svg class=\"dot\">digraph { a -> b }</svg></div>"
+        );
+    }
+    assert_eq!(fs::read_to_string(&runs).unwrap(), "run\n");
+
+    // Upgrading the tool invalidates what the old version rendered.
+    fs::write(&version, "dot - graphviz version 9.0.0\n").unwrap();
+    render_diagram(&dir, &path, "graphviz", "digraph { a -> b }");
+    assert_eq!(fs::read_to_string(&runs).unwrap(), "run\nrun\n");
+
+    let reply = render_diagram(&dir, &path, "plantuml", "@startuml\nA -> B\n@enduml");
+    assert!(
+        reply.starts_with("error\nplantuml diagrams need `plantuml` on PATH"),
+        "{reply}"
+    );
+}
+
+#[test]
+fn diagrams_flag_lets_the_filter_render_and_failures_are_logged() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    // Stands in for pandoc running the filter: shows the command it was
+    // given and a block the filter kept as code.
+    fs::write(
+        dir.join("pandoc"),
+        "#!/bin/sh\n\
+         [ \"$1\" = \"--version\" ] && exit 0\n\
+         for last; do [ \"$prev\" = \"--output\" ] && out=\"$last\"; prev=\"$last\"; done\n\
+         printf '<p>%s</p>\\n<div class=\"diagram-error\">\\n<p>dot: bad &amp; broken</p>\\n</div>\\n' \
+         \"$MDR_DIAGRAM_COMMAND\" > \"$out\"\n",
+    )
+    .unwrap();
+    fs::set_permissions(dir.join("pandoc"), fs::Permissions::from_mode(0o755)).unwrap();
+    let input = dir.join("doc.md");
+    let output = dir.join("doc.html");
+    fs::write(&input, "```dot\nbad\n```\n").unwrap();
+
+    Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg(&input)
+        .arg("-o")
+        .arg(&output)
+        .arg("--diagrams")
+        .env("PATH", &dir)
+        .assert()
+        .success()
+        .stderr(contains("doc.md: dot: bad & broken"));
+
+    let html = fs::read_to_string(&output).unwrap();
+    let mdr = assert_cmd::cargo::cargo_bin!("mdr");
+    assert!(
+        html.starts_with(&format!("<p>{}</p>", mdr.display())),
+        "{html}"
+    );
+
+    // Off by default, even when the variable leaks in from outside.
+    Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg(&input)
+        .arg("-o")
+        .arg(&output)
+        .env("PATH", &dir)
+        .env("MDR_DIAGRAM_COMMAND", "/bin/false")
+        .assert()
+        .success();
+    let html = fs::read_to_string(&output).unwrap();
+    assert!(html.starts_with("<p></p>"), "{html}");
+}
+
+#[test]
+fn real_pandoc_renders_diagrams_through_the_filter() {
+    if !real_pandoc_available("real_pandoc_renders_diagrams_through_the_filter") {
+        return;
+    }
+
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let bin = dir.join("bin");
+    fs::create_dir(&bin).unwrap();
+    fs::write(
+        bin.join("dot"),
+        "#!/bin/sh\n\
+         [ \"$1\" = -V ] && exit 0\n\
+         input=$(cat)\n\
+         case \"$input\" in *broken*) echo 'syntax error in line 1' >&2; exit 1;; esac\n\
+         printf '<svg class=\"dot\">%s</svg>' \"$input\"\n",
+    )
+    .unwrap();
+    fs::set_permissions(bin.join("dot"), fs::Permissions::from_mode(0o755)).unwrap();
+    let input = dir.join("doc.md");
+    let output = dir.join("doc.html");
+    fs::write(
+        &input,
+        "```dot\ndigraph { a }\n```\n\n``` {.graphviz}\nbroken\n```\n\n\
+         ```rust\nfn main() {}\n```\n",
+    )
+    .unwrap();
+
+    Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("-o")
+        .arg(&output)
+        .arg(&input)
+        .arg("--diagrams")
+        .env("MDR_KATEX", katex_fixture_url())
+        .env(
+            "PATH",
+            format!(
+                "{}:{}",
+                bin.display(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        )
+        .env("XDG_CACHE_HOME", dir.join("cache"))
+        .assert()
+        .success()
+        .stderr(contains("dot: syntax error in line 1"));
+
+    let html = fs::read_to_string(&output).unwrap();
+    assert!(
+        html.contains(
+            "<div class=\"diagram graphviz\"><svg class=\"dot\">digraph { a }</svg></div>"
+        ),
+        "{html}"
+    );
+    assert!(html.contains("<div class=\"diagram-error\">"), "{html}");
+    assert!(html.contains("broken"), "{html}");
+    assert!(html.contains("class=\"sourceCode rust\""), "{html}");
+}
+
+fn ws_connect(port: u16, path: &str) -> TcpStream {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).expect("connect websocket");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    write!(
+        stream,
+        "GET {path} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n"
+    )
+    .unwrap();
+
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).expect("read handshake");
+        head.push(byte[0]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    assert!(head.contains("101"), "websocket upgrade failed: {head}");
+    stream
+}
+
+// Reads one unmasked server text frame (no fragmentation, payload < 64 KiB).
+fn ws_read_text(stream: &mut TcpStream) -> String {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).expect("read frame header");
+    let len = match header[1] & 0x7f {
+        126 => {
+            let mut ext = [0u8; 2];
+            stream.read_exact(&mut ext).unwrap();
+            u16::from_be_bytes(ext) as usize
+        }
+        n => n as usize,
+    };
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).expect("read frame payload");
+    assert_eq!(header[0] & 0x0f, 0x1, "expected a text frame");
+    String::from_utf8(payload).expect("utf8 frame")
+}
+
+#[test]
+fn websocket_speaks_json_protocol_and_legacy_reload() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let _fake = make_fake_pandoc(&dir);
+
+    let input = dir.join("note.md");
+    fs::write(&input, "# Title\n\nBody").unwrap();
+
+    let Some(port) = pick_free_port() else {
+        eprintln!("skipping websocket_speaks_json_protocol_and_legacy_reload: unable to bind loopback port");
+        return;
+    };
+
+    let mut child = process::Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("--port")
+        .arg(port.to_string())
+        .arg(&input)
+        .env("MDR_KATEX", katex_fixture_url())
+        .env(
+            "PATH",
+            format!(
+                "{}:{}",
+                dir.display(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        )
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .spawn()
+        .expect("spawn serve mode");
+
+    wait_until(Duration::from_secs(5), || {
+        TcpStream::connect(("127.0.0.1", port)).ok()
+    })
+    .expect("server did not start in time");
+
+    let mut typed = ws_connect(port, "/ws?protocol=1");
+    let hello = ws_read_text(&mut typed);
+    let mut legacy = ws_connect(port, "/ws");
+
+    let rebuilt = http_request(port, "POST", "/api/rebuild");
+    let started = ws_read_text(&mut typed);
+    let reload = ws_read_text(&mut typed);
+    let legacy_reload = ws_read_text(&mut legacy);
+
+    let _ = child.kill();
+    let _ = child.wait();
+
+    assert!(rebuilt.contains("200 OK"));
+    assert!(hello.contains("\"type\":\"hello\""), "got {hello}");
+    assert!(hello.contains("\"v\":1"));
+    assert!(
+        started.contains("\"type\":\"build-started\""),
+        "got {started}"
+    );
+    assert!(reload.contains("\"type\":\"reload\""), "got {reload}");
+    assert_eq!(legacy_reload, "reload");
+}
+
+fn read_until(stream: &mut TcpStream, needle: &str) -> String {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !String::from_utf8_lossy(&buf).contains(needle) {
+        let n = stream.read(&mut chunk).expect("read stream");
+        assert!(n > 0, "stream closed before {needle:?}");
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    String::from_utf8_lossy(&buf).into_owned()
+}
+
+#[test]
+fn sse_and_version_endpoints_report_rebuilds() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let _fake = make_fake_pandoc(&dir);
+
+    let input = dir.join("note.md");
+    fs::write(&input, "# Title\n\nBody").unwrap();
+
+    let Some(port) = pick_free_port() else {
+        eprintln!(
+            "skipping sse_and_version_endpoints_report_rebuilds: unable to bind loopback port"
+        );
+        return;
+    };
+
+    let mut child = process::Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("--port")
+        .arg(port.to_string())
+        .arg(&input)
+        .env("MDR_KATEX", katex_fixture_url())
+        .env(
+            "PATH",
+            format!(
+                "{}:{}",
+                dir.display(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        )
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .spawn()
+        .expect("spawn serve mode");
+
+    wait_until(Duration::from_secs(5), || {
+        TcpStream::connect(("127.0.0.1", port)).ok()
+    })
+    .expect("server did not start in time");
+
+    let version = http_request(port, "GET", "/api/version");
+    let etag = version
+        .lines()
+        .find_map(|l| l.strip_prefix("etag: "))
+        .expect("version response has an ETag")
+        .to_string();
+
+    let mut cached = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    write!(
+        cached,
+        "GET /api/version HTTP/1.1\r\nHost: localhost\r\nIf-None-Match: {etag}\r\nConnection: close\r\n\r\n"
+    )
+    .unwrap();
+    let mut not_modified = String::new();
+    cached.read_to_string(&mut not_modified).unwrap();
+
+    let mut events = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    events
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    write!(
+        events,
+        "GET /events HTTP/1.1\r\nHost: localhost\r\nAccept: text/event-stream\r\n\r\n"
+    )
+    .unwrap();
+    let hello = read_until(&mut events, "\"type\":\"hello\"");
+
+    http_request(port, "POST", "/api/rebuild");
+    let reload = read_until(&mut events, "\"type\":\"reload\"");
+    let bumped = http_request(port, "GET", "/api/version");
+
+    let _ = child.kill();
+    let _ = child.wait();
+
+    assert!(
+        version.contains("200 OK"),
+        "unexpected version response: {version}"
+    );
+    assert!(
+        not_modified.contains("304 Not Modified"),
+        "got {not_modified}"
+    );
+    assert!(hello.contains("text/event-stream"));
+    assert!(reload.contains("\"type\":\"build-started\""));
+    assert!(
+        !bumped.contains(&etag),
+        "ETag should change after a rebuild"
+    );
+}
+
+#[test]
+fn self_signed_tls_serves_https() {
+    if process::Command::new("curl")
+        .arg("--version")
+        .output()
+        .is_err()
+    {
+        eprintln!("skipping self_signed_tls_serves_https: curl not found");
+        return;
+    }
+
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let _fake = make_fake_pandoc(&dir);
+
+    let input = dir.join("note.md");
+    fs::write(&input, "# Title\n\nBody").unwrap();
+
+    let Some(port) = pick_free_port() else {
+        eprintln!("skipping self_signed_tls_serves_https: unable to bind loopback port");
+        return;
+    };
+
+    let mut child = process::Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("--port")
+        .arg(port.to_string())
+        .arg("--tls-self-signed")
+        .arg(&input)
+        .env("MDR_KATEX", katex_fixture_url())
+        .env(
+            "PATH",
+            format!(
+                "{}:{}",
+                dir.display(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        )
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .spawn()
+        .expect("spawn serve mode");
+
+    wait_until(Duration::from_secs(5), || {
+        TcpStream::connect(("127.0.0.1", port)).ok()
+    })
+    .expect("server did not start in time");
+
+    let https = process::Command::new("curl")
+        .args(["-sk", "--max-time", "5"])
+        .arg(format!("https://127.0.0.1:{port}/api/status"))
+        .output()
+        .expect("run curl");
+    let plain = process::Command::new("curl")
+        .args(["-s", "--max-time", "5"])
+        .arg(format!("http://127.0.0.1:{port}/api/status"))
+        .output()
+        .expect("run curl");
+
+    let _ = child.kill();
+    let _ = child.wait();
+
+    let body = String::from_utf8_lossy(&https.stdout);
+    assert!(
+        body.contains("\"success\":true"),
+        "unexpected https body: {body}"
+    );
+    assert!(
+        !String::from_utf8_lossy(&plain.stdout).contains("\"success\""),
+        "plaintext request should not be served"
+    );
+}
+
+#[test]
+fn tls_cert_requires_key() {
+    let tmp = tempdir().unwrap();
+    let input = tmp.path().join("note.md");
+    fs::write(&input, "# Title").unwrap();
+
+    Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("--tls-cert")
+        .arg(tmp.path().join("cert.pem"))
+        .arg(&input)
+        .assert()
+        .code(64)
+        .stderr(contains("--tls-cert and --tls-key"));
+}
+
+fn http_get_with_headers(port: u16, path: &str, headers: &str) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).expect("connect to server");
+    write!(
+        stream,
+        "GET {path} HTTP/1.1\r\nHost: localhost\r\n{headers}Connection: close\r\n\r\n"
+    )
+    .unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+    resp
+}
+
+#[test]
+fn token_and_basic_auth_guard_every_route() {
+    use std::io::BufRead;
+
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let _fake = make_fake_pandoc(&dir);
+
+    let input = dir.join("note.md");
+    fs::write(&input, "# Title\n\nBody").unwrap();
+
+    let Some(port) = pick_free_port() else {
+        eprintln!("skipping token_and_basic_auth_guard_every_route: unable to bind loopback port");
+        return;
+    };
+
+    let mut child = process::Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("--port")
+        .arg(port.to_string())
+        .arg("--token")
+        .arg(&input)
+        .env("MDR_BASIC_AUTH", "ana:s3cret")
+        .env("MDR_KATEX", katex_fixture_url())
+        .env(
+            "PATH",
+            format!(
+                "{}:{}",
+                dir.display(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        )
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .expect("spawn serve mode");
+
+    let stderr = std::io::BufReader::new(child.stderr.take().unwrap());
+    let token = stderr
+        .lines()
+        .map_while(Result::ok)
+        .find_map(|line| {
+            let start = line.find("?token=")? + "?token=".len();
+            Some(line[start..].split_whitespace().next()?.to_string())
+        })
+        .expect("startup output should include the token URL");
+
+    let anonymous = http_get_with_headers(port, "/api/status", "");
+    let anonymous_ws = http_get_with_headers(
+        port,
+        "/ws",
+        "Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n",
+    );
+    let exchanged = http_get_with_headers(port, &format!("/?token={token}"), "");
+    let with_cookie = http_get_with_headers(
+        port,
+        "/api/status",
+        &format!("Cookie: mdr_token={token}\r\n"),
+    );
+    // "ana:s3cret" in base64.
+    let with_basic = http_get_with_headers(
+        port,
+        "/api/status",
+        "Authorization: Basic YW5hOnMzY3JldA==\r\n",
+    );
+
+    let _ = child.kill();
+    let _ = child.wait();
+
+    assert!(anonymous.contains("401 Unauthorized"), "got {anonymous}");
+    assert!(anonymous.contains("www-authenticate: Basic"));
+    assert!(
+        anonymous_ws.contains("401 Unauthorized"),
+        "got {anonymous_ws}"
+    );
+    assert!(exchanged.contains("303 See Other"), "got {exchanged}");
+    assert!(exchanged.contains(&format!("set-cookie: mdr_token={token}")));
+    assert!(exchanged.contains("location: /\r\n"));
+    assert!(with_cookie.contains("200 OK"), "got {with_cookie}");
+    assert!(with_basic.contains("200 OK"), "got {with_basic}");
+}
+
+#[test]
+fn public_server_reports_loopback_and_lan_urls() {
+    use std::io::BufRead;
+
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let _fake = make_fake_pandoc(&dir);
+
+    let input = dir.join("note.md");
+    fs::write(&input, "# Title\n\nBody").unwrap();
+
+    let Some(port) = pick_free_port() else {
+        eprintln!(
+            "skipping public_server_reports_loopback_and_lan_urls: unable to bind loopback port"
+        );
+        return;
+    };
+
+    let mut child = process::Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("--public")
+        .arg("--port")
+        .arg(port.to_string())
+        .arg("--log-format")
+        .arg("json")
+        .arg(&input)
+        .env("MDR_KATEX", katex_fixture_url())
+        .env(
+            "PATH",
+            format!(
+                "{}:{}",
+                dir.display(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        )
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .expect("spawn serve mode");
+
+    let stderr = std::io::BufReader::new(child.stderr.take().unwrap());
+    let listening = stderr
+        .lines()
+        .map_while(Result::ok)
+        .find(|line| line.contains("\"event\":\"server_listening\""));
+
+    let _ = child.kill();
+    let _ = child.wait();
+
+    let listening = listening.expect("server_listening event missing");
+    assert!(
+        listening.contains(&format!("\"url\":\"http://127.0.0.1:{port}/\"")),
+        "got {listening}"
+    );
+    assert!(listening.contains("\"lan_urls\":["));
+    assert!(!listening.contains("0.0.0.0"));
+}
+
+#[test]
+fn config_default_opens_browser_after_startup() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let _fake = make_fake_pandoc(&dir);
+
+    let input = dir.join("note.md");
+    fs::write(&input, "# Title\n\nBody").unwrap();
+    fs::create_dir_all(dir.join("config/mdr")).unwrap();
+    fs::write(dir.join("config/mdr/config.toml"), "[serve]\nopen = true\n").unwrap();
+
+    let opened = dir.join("opened.txt");
+    let browser = dir.join("fake-browser");
+    fs::write(
+        &browser,
+        format!("#!/bin/sh\necho \"$1\" > {}\n", opened.display()),
+    )
+    .unwrap();
+    fs::set_permissions(&browser, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let Some(port) = pick_free_port() else {
+        eprintln!(
+            "skipping config_default_opens_browser_after_startup: unable to bind loopback port"
+        );
+        return;
+    };
+
+    let mut child = process::Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("--port")
+        .arg(port.to_string())
+        .arg(&input)
+        .env("BROWSER", &browser)
+        .env("XDG_CONFIG_HOME", dir.join("config"))
+        .env("XDG_STATE_HOME", dir.join("state"))
+        .env("MDR_KATEX", katex_fixture_url())
+        .env(
+            "PATH",
+            format!(
+                "{}:{}",
+                dir.display(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        )
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .spawn()
+        .expect("spawn serve mode");
+
+    let url = wait_until(Duration::from_secs(8), || {
+        fs::read_to_string(&opened).ok().filter(|s| !s.is_empty())
+    });
+
+    let _ = child.kill();
+    let _ = child.wait();
+
+    assert_eq!(
+        url.expect("browser was not launched").trim(),
+        format!("http://127.0.0.1:{port}/")
+    );
+}
+
+#[test]
+fn project_config_cannot_set_browser() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let _fake = make_fake_pandoc(&dir);
+    let input = dir.join("note.md");
+    fs::write(&input, "# Title").unwrap();
+    fs::write(
+        dir.join(".mdr.toml"),
+        "[serve]\nopen = true\nbrowser = \"touch pwned\"\n",
+    )
+    .unwrap();
+
+    Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("-o")
+        .arg(dir.join("note.html"))
+        .arg(&input)
+        .env("XDG_CONFIG_HOME", dir.join("config"))
+        .env("MDR_KATEX", katex_fixture_url())
+        .env(
+            "PATH",
+            format!(
+                "{}:{}",
+                dir.display(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        )
+        .assert()
+        .success()
+        .stderr(contains("ignoring `serve.browser`"))
+        .stderr(contains("ignoring `serve.open`"));
+}
+
+#[test]
+fn invalid_project_config_is_rejected() {
+    let tmp = tempdir().unwrap();
+    let input = tmp.path().join("note.md");
+    fs::write(&input, "# Title").unwrap();
+    fs::write(tmp.path().join(".mdr.toml"), "[serve]\nopne = true\n").unwrap();
+
+    Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("-o")
+        .arg(tmp.path().join("note.html"))
+        .arg(&input)
+        .env("XDG_CONFIG_HOME", tmp.path().join("config"))
+        .assert()
+        .code(78)
+        .stderr(contains("unknown field"));
+}
+
+#[test]
+fn unix_socket_serves_preview_and_is_removed_on_exit() {
+    use std::os::unix::net::UnixStream;
+
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let _fake = make_fake_pandoc(&dir);
+
+    let input = dir.join("note.md");
+    fs::write(&input, "# Title\n\nBody").unwrap();
+    let socket = dir.join("preview.sock");
+
+    let mut child = process::Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("--socket")
+        .arg(&socket)
+        .arg(&input)
+        .env("MDR_KATEX", katex_fixture_url())
+        .env(
+            "PATH",
+            format!(
+                "{}:{}",
+                dir.display(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        )
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .spawn()
+        .expect("spawn serve mode");
+
+    let mut stream = wait_until(Duration::from_secs(5), || UnixStream::connect(&socket).ok())
+        .expect("socket server did not start in time");
+    write!(
+        stream,
+        "GET /api/status HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"
+    )
+    .unwrap();
+    let mut resp = String::new();
+    stream.read_to_string(&mut resp).unwrap();
+
+    send_signal(&child, "TERM");
+    let exited = wait_until(Duration::from_secs(5), || child.try_wait().ok().flatten());
+    if exited.is_none() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+
+    assert!(resp.contains("200 OK"), "unexpected response: {resp}");
+    assert!(resp.contains("\"success\":true"));
+    assert!(exited.is_some(), "mdr did not exit on SIGTERM");
+    assert!(!socket.exists(), "socket file should be removed on exit");
+}
+
+#[test]
+fn base_path_prefixes_routes_and_injected_urls() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let _fake = make_fake_pandoc(&dir);
+
+    let input = dir.join("note.md");
+    fs::write(&input, "# Title\n\nBody").unwrap();
+
+    let Some(port) = pick_free_port() else {
+        eprintln!(
+            "skipping base_path_prefixes_routes_and_injected_urls: unable to bind loopback port"
+        );
+        return;
+    };
+
+    let mut child = process::Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("--port")
+        .arg(port.to_string())
+        .arg("--base-path")
+        .arg("/docs/")
+        .arg(&input)
+        .env("MDR_KATEX", katex_fixture_url())
+        .env(
+            "PATH",
+            format!(
+                "{}:{}",
+                dir.display(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        )
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .spawn()
+        .expect("spawn serve mode");
+
+    wait_until(Duration::from_secs(5), || {
+        TcpStream::connect(("127.0.0.1", port)).ok()
+    })
+    .expect("server did not start in time");
+
+    let page = http_get_with_headers(port, "/docs/", "");
+    let bare = http_get_with_headers(port, "/docs", "");
+    let proxied = http_get_with_headers(port, "/docs/", "X-Forwarded-Prefix: /proxy\r\n");
+    let status = http_get_with_headers(port, "/docs/api/status", "");
+    let root = http_get_with_headers(port, "/", "");
+    let unprefixed = http_get_with_headers(port, "/api/status", "");
+
+    let _ = child.kill();
+    let _ = child.wait();
+
+    assert!(page.contains("200 OK"), "got {page}");
+    assert!(page.contains("<script src=\"/docs/live.js\"></script>"));
+    assert!(bare.contains("200 OK"), "got {bare}");
+    assert!(proxied.contains("<script src=\"/proxy/docs/live.js\"></script>"));
+    assert!(status.contains("\"success\":true"));
+    assert!(root.contains("307 Temporary Redirect"), "got {root}");
+    assert!(root.contains("location: /docs/\r\n"));
+    assert!(unprefixed.contains("404 Not Found"), "got {unprefixed}");
+}
+
+#[test]
+fn forwarded_headers_need_a_trusted_proxy() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let _fake = make_fake_pandoc(&dir);
+
+    let input = dir.join("note.md");
+    fs::write(&input, "# Title\n\nBody").unwrap();
+
+    let mut pages = Vec::new();
+    for trust in [false, true] {
+        let Some(port) = pick_free_port() else {
+            eprintln!(
+                "skipping forwarded_headers_need_a_trusted_proxy: unable to bind loopback port"
+            );
+            return;
+        };
+
+        let mut cmd = process::Command::new(assert_cmd::cargo::cargo_bin!("mdr"));
+        cmd.arg("--port").arg(port.to_string());
+        if trust {
+            cmd.arg("--trust-proxy");
+        }
+        let mut child = cmd
+            .arg(&input)
+            .env("MDR_KATEX", katex_fixture_url())
+            .env(
+                "PATH",
+                format!(
+                    "{}:{}",
+                    dir.display(),
+                    std::env::var("PATH").unwrap_or_default()
+                ),
+            )
+            .stdout(process::Stdio::null())
+            .stderr(process::Stdio::null())
+            .spawn()
+            .expect("spawn serve mode");
+
+        wait_until(Duration::from_secs(5), || {
+            TcpStream::connect(("127.0.0.1", port)).ok()
+        })
+        .expect("server did not start in time");
+
+        pages.push(http_get_with_headers(
+            port,
+            "/",
+            "X-Forwarded-Prefix: /proxy\r\n",
+        ));
+
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+
+    assert!(
+        pages[0].contains("<script src=\"/live.js\"></script>"),
+        "got {}",
+        pages[0]
+    );
+    assert!(
+        pages[1].contains("<script src=\"/proxy/live.js\"></script>"),
+        "got {}",
+        pages[1]
+    );
+}
+
+#[test]
+fn preview_supports_revalidation_and_compression() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let _fake = make_fake_pandoc(&dir);
+
+    let input = dir.join("note.md");
+    fs::write(&input, "# Title\n\nBody").unwrap();
+
+    let Some(port) = pick_free_port() else {
+        eprintln!(
+            "skipping preview_supports_revalidation_and_compression: unable to bind loopback port"
+        );
+        return;
+    };
+
+    let mut child = process::Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("--port")
+        .arg(port.to_string())
+        .arg(&input)
+        .env("MDR_KATEX", katex_fixture_url())
+        .env(
+            "PATH",
+            format!(
+                "{}:{}",
+                dir.display(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        )
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .spawn()
+        .expect("spawn serve mode");
+
+    wait_until(Duration::from_secs(5), || {
+        TcpStream::connect(("127.0.0.1", port)).ok()
+    })
+    .expect("server did not start in time");
+
+    // Compressed bodies are not UTF-8; only the headers matter here.
+    let head = |path: &str, headers: &str| {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).expect("connect to server");
+        write!(
+            stream,
+            "GET {path} HTTP/1.1\r\nHost: localhost\r\n{headers}Connection: close\r\n\r\n"
+        )
+        .unwrap();
+        let mut resp = Vec::new();
+        stream.read_to_end(&mut resp).unwrap();
+        let text = String::from_utf8_lossy(&resp).into_owned();
+        text.split("\r\n\r\n")
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    };
+    let header_value = |resp: &str, name: &str| {
+        resp.lines()
+            .find_map(|line| line.strip_prefix(&format!("{name}: ")))
+            .map(str::to_string)
+    };
+
+    let first = head("/", "");
+    let etag = header_value(&first, "etag").expect("etag header");
+    let modified = header_value(&first, "last-modified").expect("last-modified header");
+    let revalidated = head("/", &format!("If-None-Match: {etag}\r\n"));
+    let since = head("/", &format!("If-Modified-Since: {modified}\r\n"));
+    let gzip = head("/", "Accept-Encoding: gzip\r\n");
+    let brotli = head("/", "Accept-Encoding: gzip, br\r\n");
+    let script = head("/live.js", "Accept-Encoding: gzip\r\n");
+
+    let _ = child.kill();
+    let _ = child.wait();
+
+    assert!(first.contains("200 OK"), "got {first}");
+    assert!(first.contains("cache-control: no-cache"));
+    assert!(
+        revalidated.contains("304 Not Modified"),
+        "got {revalidated}"
+    );
+    assert!(since.contains("304 Not Modified"), "got {since}");
+    assert!(gzip.contains("content-encoding: gzip"), "got {gzip}");
+    assert_ne!(header_value(&gzip, "etag"), Some(etag));
+    assert!(brotli.contains("content-encoding: br"), "got {brotli}");
+    assert!(script.contains("content-encoding: gzip"), "got {script}");
+}
+
+#[test]
+fn open_adds_documents_to_a_running_server() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let _fake = make_fake_pandoc(&dir);
+    let runtime = dir.join("run");
+    let path_env = format!(
+        "{}:{}",
+        dir.display(),
+        std::env::var("PATH").unwrap_or_default()
+    );
+
+    let input = dir.join("note.md");
+    fs::write(&input, "# Title\n\nBody").unwrap();
+    let other = dir.join("other.md");
+    fs::write(&other, "# Other\n\nBody").unwrap();
+
+    // Nothing to talk to yet.
+    Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("open")
+        .arg(&other)
+        .env("XDG_RUNTIME_DIR", &runtime)
+        .assert()
+        .code(69)
+        .stderr(contains("no running mdr preview"));
+
+    let Some(port) = pick_free_port() else {
+        eprintln!("skipping open_adds_documents_to_a_running_server: unable to bind loopback port");
+        return;
+    };
+
+    let mut child = process::Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("--port")
+        .arg(port.to_string())
+        .arg(&input)
+        .env("MDR_KATEX", katex_fixture_url())
+        .env("PATH", &path_env)
+        .env("XDG_RUNTIME_DIR", &runtime)
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .spawn()
+        .expect("spawn serve mode");
+
+    wait_until(Duration::from_secs(5), || {
+        TcpStream::connect(("127.0.0.1", port)).ok()
+    })
+    .expect("server did not start in time");
+    let single = http_get_with_headers(port, "/", "");
+
+    let opened = Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("open")
+        .arg(&other)
+        .env("PATH", &path_env)
+        .env("XDG_RUNTIME_DIR", &runtime)
+        .output()
+        .expect("run mdr open");
+    let reopened = Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("open")
+        .arg(&other)
+        .env("XDG_RUNTIME_DIR", &runtime)
+        .output()
+        .expect("run mdr open again");
+
+    let index = http_get_with_headers(port, "/", "");
+    let page = http_get_with_headers(port, "/d/other/", "");
+    let first = http_get_with_headers(port, "/d/note/", "");
+
+    fs::write(&other, "# Other\n\nChanged").unwrap();
+    let rebuilt = wait_until(Duration::from_secs(5), || {
+        let status = http_get_with_headers(port, "/d/other/api/status", "");
+        status.contains("\"builds\":2").then_some(status)
+    });
+    let primary = http_get_with_headers(port, "/api/status", "");
+
+    let mut sse = TcpStream::connect(("127.0.0.1", port)).expect("connect");
+    sse.write_all(
+        b"GET /d/other/events HTTP/1.1\r\nHost: localhost\r\nAccept: text/event-stream\r\n\r\n",
+    )
+    .unwrap();
+    let watched = wait_until(Duration::from_secs(5), || {
+        let status = http_get_with_headers(port, "/d/other/api/status", "");
+        status.contains("\"clients\":1").then_some(status)
+    });
+    let unwatched = http_get_with_headers(port, "/api/status", "");
+
+    let _ = child.kill();
+    let _ = child.wait();
+
+    assert!(single.contains("fake</html>"), "got {single}");
+    assert!(opened.status.success(), "{opened:?}");
+    let url = format!("http://127.0.0.1:{port}/d/other/\n");
+    assert_eq!(String::from_utf8_lossy(&opened.stdout), url);
+    assert_eq!(String::from_utf8_lossy(&reopened.stdout), url);
+
+    assert!(index.contains("<a href=\"d/note/\">"), "got {index}");
+    assert!(index.contains("<a href=\"d/other/\">"));
+    assert_eq!(index.matches("<li>").count(), 2, "opened once");
+    assert!(page.contains("<script src=\"/d/other/live.js\"></script>"));
+    assert!(first.contains("<script src=\"/d/note/live.js\"></script>"));
+
+    assert!(rebuilt.is_some(), "other.md was not rebuilt on change");
+    assert!(primary.contains("\"builds\":1"), "got {primary}");
+    assert!(
+        watched.is_some(),
+        "other.md's status did not count its client"
+    );
+    assert!(unwatched.contains("\"clients\":0"), "got {unwatched}");
+}
+
+#[test]
+fn ctl_finds_previews_by_file_and_controls_them() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let _fake = make_fake_pandoc(&dir);
+    let runtime = dir.join("run");
+
+    let input = dir.join("note.md");
+    fs::write(&input, "# Title\n\nBody").unwrap();
+    let unserved = dir.join("unserved.md");
+    fs::write(&unserved, "# Elsewhere").unwrap();
+
+    let Some(port) = pick_free_port() else {
+        eprintln!(
+            "skipping ctl_finds_previews_by_file_and_controls_them: unable to bind loopback port"
+        );
+        return;
+    };
+
+    let mut child = process::Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("--port")
+        .arg(port.to_string())
+        .arg(&input)
+        .env("MDR_KATEX", katex_fixture_url())
+        .env(
+            "PATH",
+            format!(
+                "{}:{}",
+                dir.display(),
+                std::env::var("PATH").unwrap_or_default()
+            ),
+        )
+        .env("XDG_RUNTIME_DIR", &runtime)
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .spawn()
+        .expect("spawn serve mode");
+
+    wait_until(Duration::from_secs(5), || {
+        TcpStream::connect(("127.0.0.1", port)).ok()
+    })
+    .expect("server did not start in time");
+
+    let ctl = |args: &[&std::ffi::OsStr]| {
+        Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+            .arg("ctl")
+            .args(args)
+            .env("XDG_RUNTIME_DIR", &runtime)
+            .output()
+            .expect("run mdr ctl")
+    };
+    let url = format!("http://127.0.0.1:{port}/d/note/");
+    let canonical = input.canonicalize().unwrap();
+
+    let list = ctl(&["list".as_ref()]);
+    let found = ctl(&["url".as_ref(), input.as_os_str()]);
+    let missing = ctl(&["url".as_ref(), unserved.as_os_str()]);
+    let rebuilt = ctl(&["rebuild".as_ref(), input.as_os_str()]);
+    let status = http_get_with_headers(port, "/api/status", "");
+    let usage = ctl(&["frobnicate".as_ref()]);
+    let stopped = ctl(&["stop".as_ref()]);
+
+    let exited = wait_until(Duration::from_secs(5), || child.try_wait().ok().flatten());
+    if exited.is_none() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+    let after = ctl(&["list".as_ref()]);
+
+    assert_eq!(
+        String::from_utf8_lossy(&list.stdout),
+        format!("{}\t{url}\t{}\n", child.id(), canonical.display())
+    );
+    assert_eq!(String::from_utf8_lossy(&found.stdout), format!("{url}\n"));
+    assert_eq!(missing.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&missing.stderr).contains("no running mdr preview serves"));
+    assert!(rebuilt.status.success(), "{rebuilt:?}");
+    assert!(status.contains("\"builds\":2"), "got {status}");
+    assert_eq!(usage.status.code(), Some(64));
+
+    assert!(stopped.status.success(), "{stopped:?}");
+    assert!(
+        exited.is_some_and(|status| status.success()),
+        "server did not stop cleanly"
+    );
+    assert!(after.status.success());
+    assert!(after.stdout.is_empty());
+    assert!(fs::read_dir(runtime.join("mdr")).unwrap().next().is_none());
+}
+
+#[test]
+fn ctl_stop_with_a_file_closes_only_that_document() {
+    let tmp = tempdir().unwrap();
+    let dir = tmp.path().to_path_buf();
+    let _fake = make_fake_pandoc(&dir);
+    let runtime = dir.join("run");
+    let path_env = format!(
+        "{}:{}",
+        dir.display(),
+        std::env::var("PATH").unwrap_or_default()
+    );
+
+    let input = dir.join("note.md");
+    fs::write(&input, "# Title\n\nBody").unwrap();
+    let other = dir.join("other.md");
+    fs::write(&other, "# Other\n\nBody").unwrap();
+
+    let Some(port) = pick_free_port() else {
+        eprintln!(
+            "skipping ctl_stop_with_a_file_closes_only_that_document: unable to bind loopback port"
+        );
+        return;
+    };
+
+    let mut child = process::Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+        .arg("--port")
+        .arg(port.to_string())
+        .arg(&input)
+        .env("MDR_KATEX", katex_fixture_url())
+        .env("PATH", &path_env)
+        .env("XDG_RUNTIME_DIR", &runtime)
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .spawn()
+        .expect("spawn serve mode");
+
+    wait_until(Duration::from_secs(5), || {
+        TcpStream::connect(("127.0.0.1", port)).ok()
+    })
+    .expect("server did not start in time");
+
+    let mdr = |args: &[&std::ffi::OsStr]| {
+        Command::new(assert_cmd::cargo::cargo_bin!("mdr"))
+            .args(args)
+            .env("PATH", &path_env)
+            .env("XDG_RUNTIME_DIR", &runtime)
+            .output()
+            .expect("run mdr")
+    };
+    let opened = mdr(&["open".as_ref(), other.as_os_str()]);
+    // Closing the document named on the command line keeps the opened one.
+    let closed = mdr(&["ctl".as_ref(), "stop".as_ref(), input.as_os_str()]);
+    thread::sleep(Duration::from_millis(200));
+    let running = child.try_wait().unwrap().is_none();
+    let list = mdr(&["ctl".as_ref(), "list".as_ref()]);
+    let root = http_get_with_headers(port, "/", "");
+    let gone = http_get_with_headers(port, "/d/note/", "");
+    let stopped = mdr(&["ctl".as_ref(), "stop".as_ref(), other.as_os_str()]);
+
+    let exited = wait_until(Duration::from_secs(5), || child.try_wait().ok().flatten());
+    if exited.is_none() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+
+    assert!(opened.status.success(), "{opened:?}");
+    assert!(closed.status.success(), "{closed:?}");
+    assert!(running, "closing one document stopped the server");
+    let list = String::from_utf8_lossy(&list.stdout).to_string();
+    assert!(list.contains("/d/other/"), "got {list}");
+    assert!(!list.contains("note.md"), "got {list}");
+    assert!(root.contains("fake</html>"), "got {root}");
+    assert!(gone.contains("404"), "got {gone}");
+    assert!(stopped.status.success(), "{stopped:?}");
+    assert!(
+        exited.is_some_and(|status| status.success()),
+        "closing the last document did not stop the server"
+    );
+}